@@ -0,0 +1,15 @@
+//! Feeds arbitrary bytes through the parsers used for messages from the server.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use minechat_protocol::protocol::MineChatMessage;
+
+#[allow(dead_code)]
+#[path = "../../src/protocol.rs"]
+mod protocol;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = serde_json::from_slice::<MineChatMessage>(data);
+    let _ = serde_json::from_slice::<protocol::Message>(data);
+});
@@ -0,0 +1,52 @@
+//! Checks that every message that parses can be sent and received again unchanged.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use minechat_protocol::{
+    packets::{receive_message, send_message},
+    protocol::MineChatMessage,
+};
+use std::sync::LazyLock;
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    runtime::{Builder, Runtime},
+};
+
+#[allow(dead_code)]
+#[path = "../../src/protocol.rs"]
+mod protocol;
+
+static RUNTIME: LazyLock<Runtime> =
+    LazyLock::new(|| Builder::new_current_thread().build().unwrap());
+
+fuzz_target!(|data: &[u8]| {
+    RUNTIME.block_on(async {
+        if let Ok(msg) = serde_json::from_slice::<MineChatMessage>(data) {
+            let mut sent = Vec::new();
+            send_message(&mut sent, &msg).await.unwrap();
+            let received = receive_message(&mut BufReader::new(sent.as_slice()))
+                .await
+                .unwrap();
+            assert_eq!(
+                serde_json::to_string(&msg).unwrap(),
+                serde_json::to_string(&received).unwrap()
+            );
+        }
+
+        if let Ok(msg) = serde_json::from_slice::<protocol::Message>(data) {
+            let mut sent = Vec::new();
+            protocol::send(&mut sent, &msg).await.unwrap();
+            let mut line = String::new();
+            BufReader::new(sent.as_slice())
+                .read_line(&mut line)
+                .await
+                .unwrap();
+            let received: protocol::Message = serde_json::from_str(&line).unwrap();
+            assert_eq!(
+                serde_json::to_string(&msg).unwrap(),
+                serde_json::to_string(&received).unwrap()
+            );
+        }
+    });
+});
@@ -0,0 +1,105 @@
+//! Away mode, set with `/away`, which answers private messages automatically.
+
+use std::{collections::HashMap, time::Duration};
+use tokio::time::Instant;
+
+/// The automatic reply used unless `--away-reply` is given.
+pub const DEFAULT_REPLY: &str =
+    "Hi {from}, I've been away for {time_away} and will answer when I'm back";
+
+/// Whether the user is away, and whom they sent automatic replies to.
+#[derive(Debug, Default)]
+pub struct Away {
+    since: Option<Instant>,
+    /// When each sender was last replied to, so they aren't replied to more than once per
+    /// cooldown and two away clients can't keep replying to each other.
+    replied: HashMap<String, Instant>,
+}
+
+impl Away {
+    /// Marks the user as away from `now` on.
+    pub fn start(&mut self, now: Instant) {
+        self.since.get_or_insert(now);
+    }
+
+    /// Marks the user as back, returning how long they were away, or `None` if they weren't.
+    pub fn stop(&mut self, now: Instant) -> Option<Duration> {
+        self.replied.clear();
+        self.since.take().map(|since| now - since)
+    }
+
+    /// Returns the reply to send to a private message from `from` at `now`, filled in from
+    /// `template`, unless the user isn't away or `from` was replied to less than `cooldown`
+    /// ago.
+    pub fn reply(
+        &mut self,
+        from: &str,
+        now: Instant,
+        template: &str,
+        cooldown: Duration,
+    ) -> Option<String> {
+        let since = self.since?;
+        if let Some(&last) = self.replied.get(from)
+            && now - last < cooldown
+        {
+            return None;
+        }
+        self.replied.insert(from.to_string(), now);
+        let reply = template
+            .replace("{from}", from)
+            .replace("{time_away}", &format_duration(now - since));
+        Some(reply)
+    }
+}
+
+/// Formats `duration` in whole seconds, minutes or hours and minutes, like `45s`, `12m` or
+/// `2h 5m`.
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m", secs / 60),
+        _ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replies_once_per_cooldown_while_away() {
+        let mut away = Away::default();
+        let start = Instant::now();
+        let cooldown = Duration::from_secs(60);
+        let template = "{from}: away for {time_away}";
+        assert_eq!(away.reply("alice", start, template, cooldown), None);
+
+        away.start(start);
+        let later = start + Duration::from_secs(125);
+        assert_eq!(
+            away.reply("alice", later, template, cooldown).as_deref(),
+            Some("alice: away for 2m")
+        );
+        assert_eq!(away.reply("alice", later, template, cooldown), None);
+        assert!(away.reply("bob", later, template, cooldown).is_some());
+        let after_cooldown = later + cooldown;
+        assert!(
+            away.reply("alice", after_cooldown, template, cooldown)
+                .is_some()
+        );
+
+        assert_eq!(away.stop(after_cooldown), Some(Duration::from_secs(185)));
+        assert_eq!(
+            away.reply("alice", after_cooldown, template, cooldown),
+            None
+        );
+    }
+
+    #[test]
+    fn formats_durations_in_the_largest_units() {
+        assert_eq!(format_duration(Duration::from_secs(45)), "45s");
+        assert_eq!(format_duration(Duration::from_secs(720)), "12m");
+        assert_eq!(format_duration(Duration::from_secs(7500)), "2h 5m");
+    }
+}
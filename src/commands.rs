@@ -0,0 +1,456 @@
+use crate::{dice::Dice, protocol::Secret};
+use std::time::Duration;
+
+/// A slash command typed at the REPL.
+#[derive(Debug)]
+pub enum Command {
+    /// `/exit`: disconnect from the server and quit.
+    Exit,
+    /// `/gpm <user1>,<user2> <text>`: send a private message to several users.
+    GroupPrivateMessage { to: Vec<String>, message: String },
+    /// `/history [--threaded]`: show recently received messages, optionally with replies
+    /// grouped under the message they reply to.
+    History { threaded: bool },
+    /// `/leaderboard [<category>]`: show the server's current leaderboard.
+    Leaderboard { category: Option<String> },
+    /// `/mute`: stop showing received broadcasts.
+    Mute,
+    /// `/unmute`: show received broadcasts again.
+    Unmute,
+    /// `/mute-channel #<channel>`: stop showing broadcasts sent in a channel, without
+    /// leaving it.
+    MuteChannel { channel: String },
+    /// `/unmute-channel #<channel>`: show broadcasts sent in a channel again.
+    UnmuteChannel { channel: String },
+    /// `/muted`: list the muted channels.
+    Muted,
+    /// `/away`: answer private messages automatically until `/back`.
+    Away,
+    /// `/back`: stop answering private messages automatically.
+    Back,
+    /// `/netdiag`: show socket statistics for the connection.
+    NetDiag,
+    /// `/stats`: show statistics about the session.
+    Stats,
+    /// `/stats sender <name>`: show how many broadcasts a user sent since connecting.
+    StatsSender { name: String },
+    /// `/stats senders`: list the users who sent the most broadcasts since connecting.
+    StatsSenders,
+    /// `/stats reset`: forget the broadcasts counted for each user.
+    StatsReset,
+    /// `/servers`: list other MineChat servers known to the server.
+    Servers,
+    /// `/rename <new_alias>`: change the alias of the current server.
+    Rename { alias: String },
+    /// `/join #<channel> [<password>]`: join a channel, giving its password if it has one.
+    Join {
+        channel: String,
+        password: Option<Secret>,
+    },
+    /// `/topic [<new topic>]`: show or set the topic of the current channel.
+    Topic { topic: Option<String> },
+    /// `/reply <message_id> <text>`: send a chat message replying to another message.
+    Reply { message_id: String, message: String },
+    /// `/report <message_id> <reason>`: report an inappropriate message to the server.
+    Report { message_id: String, reason: String },
+    /// `/search <text>`: list received messages containing `text`.
+    Search { text: String },
+    /// `/export <path>`: write piped lines, or the whole history, to a file.
+    Export { path: String },
+    /// `/schedule <delay_seconds> <text>`: send a chat message after a delay.
+    Schedule { delay: Duration, message: String },
+    /// `/pending`: list the scheduled messages that haven't been sent yet.
+    Pending,
+    /// `/cancel <id>`: stop a scheduled message from being sent.
+    Cancel { id: u64 },
+    /// `/dice <count>d<sides>[+<modifier>]`: roll dice and send the result as a chat
+    /// message.
+    Dice { dice: Dice },
+    /// `/dice history`: list the dice rolled this session.
+    DiceHistory,
+    /// `/edit [<text>]`: write a message in an external editor, starting with `text`, and
+    /// send it.
+    Edit { text: String },
+}
+
+/// What a slash command produced.
+#[derive(Debug, PartialEq, Eq)]
+pub enum CommandOutput {
+    /// Nothing, or output the command already showed.
+    Done,
+    /// Lines of text, shown to the user or piped into the next command.
+    Lines(Vec<String>),
+}
+
+impl Command {
+    /// Parses `input` as a slash command.
+    ///
+    /// Returns `None` if `input` isn't a command known to the client, in which case it should
+    /// be sent as a chat message. A malformed command yields its usage string as the error.
+    pub fn parse(input: &str) -> Option<Result<Command, &'static str>> {
+        let rest = input.strip_prefix('/')?;
+        let (name, args) = rest
+            .split_once(char::is_whitespace)
+            .map_or((rest, ""), |(name, args)| (name, args.trim()));
+
+        match name {
+            "exit" => Some(Ok(Command::Exit)),
+            "gpm" => Some(parse_group_private_message(args)),
+            "history" => match args {
+                "" => Some(Ok(Command::History { threaded: false })),
+                "--threaded" => Some(Ok(Command::History { threaded: true })),
+                _ => Some(Err("/history [--threaded]")),
+            },
+            "leaderboard" => Some(Ok(Command::Leaderboard {
+                category: Some(args.to_string()).filter(|category| !category.is_empty()),
+            })),
+            "mute" => Some(Ok(Command::Mute)),
+            "unmute" => Some(Ok(Command::Unmute)),
+            "mute-channel" => Some(
+                channel_name(args)
+                    .map(|channel| Command::MuteChannel { channel })
+                    .ok_or("/mute-channel #<channel>"),
+            ),
+            "unmute-channel" => Some(
+                channel_name(args)
+                    .map(|channel| Command::UnmuteChannel { channel })
+                    .ok_or("/unmute-channel #<channel>"),
+            ),
+            "muted" => Some(Ok(Command::Muted)),
+            "away" => Some(Ok(Command::Away)),
+            "back" => Some(Ok(Command::Back)),
+            "netdiag" => Some(Ok(Command::NetDiag)),
+            "servers" => Some(Ok(Command::Servers)),
+            "stats" => Some(parse_stats(args)),
+            "rename" if args.is_empty() => Some(Err("/rename <new_alias>")),
+            "rename" => Some(Ok(Command::Rename {
+                alias: args.to_string(),
+            })),
+            "reply" => Some(parse_reply(args)),
+            "report" => Some(parse_report(args)),
+            "search" if args.is_empty() => Some(Err("/search <text>")),
+            "search" => Some(Ok(Command::Search {
+                text: args.to_string(),
+            })),
+            "export" if args.is_empty() => Some(Err("/export <path>")),
+            "export" => Some(Ok(Command::Export {
+                path: args.to_string(),
+            })),
+            "schedule" => Some(parse_schedule(args)),
+            "pending" => Some(Ok(Command::Pending)),
+            "cancel" => Some(
+                args.parse()
+                    .map(|id| Command::Cancel { id })
+                    .map_err(|_| "/cancel <id>"),
+            ),
+            "dice" if args == "history" => Some(Ok(Command::DiceHistory)),
+            "dice" => Some(args.parse().map(|dice| Command::Dice { dice }).map_err(
+                |_| "/dice <count>d<sides>[+<modifier>], at most 100d1000, or /dice history",
+            )),
+            "edit" => Some(Ok(Command::Edit {
+                text: args.to_string(),
+            })),
+            "join" => Some(parse_join(args)),
+            "topic" => Some(Ok(Command::Topic {
+                topic: Some(args.to_string()).filter(|topic| !topic.is_empty()),
+            })),
+            _ => None,
+        }
+    }
+
+    /// Whether `input` is a command carrying a secret, such as a channel password, that
+    /// mustn't be kept in the input history or saved as a draft.
+    pub fn has_secret(input: &str) -> bool {
+        matches!(
+            Self::parse(input),
+            Some(Ok(Command::Join {
+                password: Some(_),
+                ..
+            }))
+        )
+    }
+
+    /// Parses `input` as one or more slash commands separated by `|`, where each command's
+    /// output is piped into the next.
+    ///
+    /// Unless every part is a known command, `input` is parsed as a single command, so a `|`
+    /// in the text of a message is left alone.
+    pub fn parse_pipeline(input: &str) -> Option<Result<Vec<Command>, &'static str>> {
+        if input.contains('|') {
+            let parts: Option<Vec<_>> = input
+                .split('|')
+                .map(|part| Command::parse(part.trim()))
+                .collect();
+            if let Some(parts) = parts {
+                return Some(parts.into_iter().collect());
+            }
+        }
+        Command::parse(input).map(|command| command.map(|command| vec![command]))
+    }
+
+    /// Whether the command outputs lines that can be piped into another command.
+    pub fn writes_lines(&self) -> bool {
+        matches!(self, Command::History { .. } | Command::Search { .. })
+    }
+
+    /// Whether the command can take lines piped from the previous command.
+    pub fn reads_input(&self) -> bool {
+        matches!(self, Command::Export { .. })
+    }
+}
+
+fn parse_group_private_message(args: &str) -> Result<Command, &'static str> {
+    const USAGE: &str = "/gpm <user1>,<user2> <text>";
+
+    let (recipients, message) = args.split_once(char::is_whitespace).ok_or(USAGE)?;
+    let to: Vec<String> = recipients
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .collect();
+    let message = message.trim();
+    if to.is_empty() || message.is_empty() {
+        return Err(USAGE);
+    }
+
+    Ok(Command::GroupPrivateMessage {
+        to,
+        message: message.to_string(),
+    })
+}
+
+/// Returns the channel name in `arg`, without its leading `#`, or `None` if it's empty.
+pub fn channel_name(arg: &str) -> Option<String> {
+    let channel = arg.strip_prefix('#').unwrap_or(arg);
+    Some(channel.to_string()).filter(|channel| !channel.is_empty())
+}
+
+fn parse_join(args: &str) -> Result<Command, &'static str> {
+    let (channel, password) = args
+        .split_once(char::is_whitespace)
+        .map_or((args, ""), |(channel, password)| (channel, password.trim()));
+    let channel = channel_name(channel).ok_or("/join #<channel> [<password>]")?;
+
+    Ok(Command::Join {
+        channel,
+        password: Some(password)
+            .filter(|password| !password.is_empty())
+            .map(|password| Secret(password.to_string())),
+    })
+}
+
+fn parse_reply(args: &str) -> Result<Command, &'static str> {
+    const USAGE: &str = "/reply <message_id> <text>";
+
+    let (message_id, message) = args.split_once(char::is_whitespace).ok_or(USAGE)?;
+    // `/history` shows IDs as #<id>, so allow them copied from there.
+    let message_id = message_id.strip_prefix('#').unwrap_or(message_id);
+    let message = message.trim();
+    if message_id.is_empty() || message.is_empty() {
+        return Err(USAGE);
+    }
+
+    Ok(Command::Reply {
+        message_id: message_id.to_string(),
+        message: message.to_string(),
+    })
+}
+
+fn parse_report(args: &str) -> Result<Command, &'static str> {
+    const USAGE: &str = "/report <message_id> <reason>";
+
+    let (message_id, reason) = args.split_once(char::is_whitespace).ok_or(USAGE)?;
+    let message_id = message_id.strip_prefix('#').unwrap_or(message_id);
+    let reason = reason.trim();
+    if message_id.is_empty() || reason.is_empty() {
+        return Err(USAGE);
+    }
+
+    Ok(Command::Report {
+        message_id: message_id.to_string(),
+        reason: reason.to_string(),
+    })
+}
+
+fn parse_stats(args: &str) -> Result<Command, &'static str> {
+    const USAGE: &str = "/stats [sender <name> | senders | reset]";
+
+    let (subcommand, rest) = args
+        .split_once(char::is_whitespace)
+        .map_or((args, ""), |(subcommand, rest)| (subcommand, rest.trim()));
+    match (subcommand, rest) {
+        ("", _) => Ok(Command::Stats),
+        ("sender", name) if !name.is_empty() => Ok(Command::StatsSender {
+            name: name.to_string(),
+        }),
+        ("senders", "") => Ok(Command::StatsSenders),
+        ("reset", "") => Ok(Command::StatsReset),
+        _ => Err(USAGE),
+    }
+}
+
+fn parse_schedule(args: &str) -> Result<Command, &'static str> {
+    const USAGE: &str = "/schedule <delay_seconds> <text>";
+
+    let (delay, message) = args.split_once(char::is_whitespace).ok_or(USAGE)?;
+    let delay = delay.parse().map_err(|_| USAGE)?;
+    let message = message.trim();
+    if message.is_empty() {
+        return Err(USAGE);
+    }
+
+    Ok(Command::Schedule {
+        delay: Duration::from_secs(delay),
+        message: message.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_pipelines() {
+        let pipeline = Command::parse_pipeline("/search hello | /export out.txt")
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            pipeline.as_slice(),
+            [Command::Search { text }, Command::Export { path }]
+                if text == "hello" && path == "out.txt"
+        ));
+    }
+
+    #[test]
+    fn keeps_pipes_inside_messages() {
+        let pipeline = Command::parse_pipeline("/gpm bob a | b").unwrap().unwrap();
+        assert!(matches!(
+            pipeline.as_slice(),
+            [Command::GroupPrivateMessage { message, .. }] if message == "a | b"
+        ));
+        assert!(Command::parse_pipeline("hello | world").is_none());
+    }
+
+    #[test]
+    fn parses_replies() {
+        assert!(matches!(
+            Command::parse("/reply 42 sounds good"),
+            Some(Ok(Command::Reply { message_id, message }))
+                if message_id == "42" && message == "sounds good"
+        ));
+        assert!(matches!(
+            Command::parse("/reply #42 sounds good"),
+            Some(Ok(Command::Reply { message_id, .. })) if message_id == "42"
+        ));
+        assert!(matches!(Command::parse("/reply # hi"), Some(Err(_))));
+        assert!(matches!(Command::parse("/reply 42"), Some(Err(_))));
+    }
+
+    #[test]
+    fn parses_reports() {
+        assert!(matches!(
+            Command::parse("/report 42 advertising another server"),
+            Some(Ok(Command::Report { message_id, reason }))
+                if message_id == "42" && reason == "advertising another server"
+        ));
+        assert!(matches!(
+            Command::parse("/report #42 spam"),
+            Some(Ok(Command::Report { message_id, .. })) if message_id == "42"
+        ));
+        assert!(matches!(Command::parse("/report # spam"), Some(Err(_))));
+        assert!(matches!(Command::parse("/report 42"), Some(Err(_))));
+    }
+
+    #[test]
+    fn parses_edits_with_or_without_text() {
+        assert!(matches!(
+            Command::parse("/edit"),
+            Some(Ok(Command::Edit { text })) if text.is_empty()
+        ));
+        assert!(matches!(
+            Command::parse("/edit Dear all,"),
+            Some(Ok(Command::Edit { text })) if text == "Dear all,"
+        ));
+    }
+
+    #[test]
+    fn parses_dice_rolls_and_their_history() {
+        assert!(matches!(
+            Command::parse("/dice 2d6+1"),
+            Some(Ok(Command::Dice { dice })) if dice.to_string() == "2d6+1"
+        ));
+        assert!(matches!(
+            Command::parse("/dice history"),
+            Some(Ok(Command::DiceHistory))
+        ));
+        assert!(matches!(Command::parse("/dice 2d0"), Some(Err(_))));
+    }
+
+    #[test]
+    fn parses_schedules_and_cancels() {
+        assert!(matches!(
+            Command::parse("/schedule 90 back online soon"),
+            Some(Ok(Command::Schedule { delay, message }))
+                if delay == Duration::from_secs(90) && message == "back online soon"
+        ));
+        assert!(matches!(Command::parse("/schedule soon hi"), Some(Err(_))));
+        assert!(matches!(Command::parse("/schedule 5"), Some(Err(_))));
+        assert!(matches!(
+            Command::parse("/cancel 2"),
+            Some(Ok(Command::Cancel { id: 2 }))
+        ));
+        assert!(matches!(Command::parse("/cancel two"), Some(Err(_))));
+    }
+
+    #[test]
+    fn parses_stats_subcommands() {
+        assert!(matches!(Command::parse("/stats"), Some(Ok(Command::Stats))));
+        assert!(matches!(
+            Command::parse("/stats sender Steve"),
+            Some(Ok(Command::StatsSender { name })) if name == "Steve"
+        ));
+        assert!(matches!(
+            Command::parse("/stats senders"),
+            Some(Ok(Command::StatsSenders))
+        ));
+        assert!(matches!(
+            Command::parse("/stats reset"),
+            Some(Ok(Command::StatsReset))
+        ));
+        assert!(matches!(Command::parse("/stats sender"), Some(Err(_))));
+        assert!(matches!(Command::parse("/stats everyone"), Some(Err(_))));
+    }
+
+    #[test]
+    fn parses_channel_mutes() {
+        assert!(matches!(
+            Command::parse("/mute-channel #trade"),
+            Some(Ok(Command::MuteChannel { channel })) if channel == "trade"
+        ));
+        assert!(matches!(
+            Command::parse("/unmute-channel trade"),
+            Some(Ok(Command::UnmuteChannel { channel })) if channel == "trade"
+        ));
+        assert!(matches!(Command::parse("/mute-channel #"), Some(Err(_))));
+    }
+
+    #[test]
+    fn parses_joins_without_showing_passwords() {
+        let join = Command::parse("/join #vip hunter2").unwrap().unwrap();
+        assert!(matches!(
+            &join,
+            Command::Join { channel, password: Some(Secret(password)) }
+                if channel == "vip" && password == "hunter2"
+        ));
+        assert!(!format!("{:?}", join).contains("hunter2"));
+        assert!(matches!(
+            Command::parse("/join general"),
+            Some(Ok(Command::Join { password: None, .. }))
+        ));
+        assert!(matches!(Command::parse("/join #"), Some(Err(_))));
+        assert!(Command::has_secret("/join #vip hunter2"));
+        assert!(!Command::has_secret("/join #vip"));
+        assert!(!Command::has_secret("join #vip hunter2"));
+    }
+}
@@ -0,0 +1,575 @@
+use crate::{
+    net,
+    theme::{Color, Theme},
+    timestamp,
+};
+use directories::ProjectDirs;
+use log::warn;
+use minechat_protocol::protocol::MineChatError;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::Once,
+};
+use uuid::{Uuid, Version};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServerConfig {
+    pub servers: Vec<ServerEntry>,
+    #[serde(default, skip_serializing_if = "Theme::is_default")]
+    pub theme: Theme,
+    /// The color of each sender's name with `--color-from fixed`, by username.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub colors: HashMap<String, Color>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ServerEntry {
+    pub address: String,
+    pub uuid: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    /// The time zone to show message times in, by IANA name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    /// Whether to reconnect after losing the connection to this server, overriding
+    /// `--reconnect-attempts`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_on_disconnect: Option<bool>,
+    /// The order to connect to this server in among others, lowest first. Servers without
+    /// one come after those with one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<u8>,
+}
+
+impl ServerConfig {
+    /// Finds the entry whose address or alias matches `server`.
+    pub fn find(&self, server: &str) -> Option<&ServerEntry> {
+        self.servers
+            .iter()
+            .find(|e| e.address == server || e.alias.as_deref() == Some(server))
+    }
+
+    /// Returns the entries in order of priority, lowest number first, keeping the order of
+    /// the config between entries of the same priority.
+    pub fn by_priority(&self) -> Vec<&ServerEntry> {
+        let mut servers: Vec<&ServerEntry> = self.servers.iter().collect();
+        servers.sort_by_key(|e| e.priority.map_or((1, 0), |priority| (0, priority)));
+        servers
+    }
+
+    /// Adds `linked` as a new entry or, if its address is already linked, gives that entry
+    /// the new UUID and the fields `linked` sets, keeping the others, such as the alias and
+    /// time zone chosen before relinking.
+    pub fn set_link(&mut self, linked: ServerEntry) {
+        let Some(entry) = self
+            .servers
+            .iter_mut()
+            .find(|e| e.address == linked.address)
+        else {
+            self.servers.push(linked);
+            return;
+        };
+        entry.uuid = linked.uuid;
+        entry.alias = linked.alias.or(entry.alias.take());
+        entry.timezone = linked.timezone.or(entry.timezone.take());
+        entry.retry_on_disconnect = linked.retry_on_disconnect.or(entry.retry_on_disconnect);
+        entry.priority = linked.priority.or(entry.priority);
+    }
+
+    /// Removes and returns the entry whose address or alias matches `server`.
+    pub fn remove(&mut self, server: &str) -> Option<ServerEntry> {
+        let index = self
+            .servers
+            .iter()
+            .position(|e| e.address == server || e.alias.as_deref() == Some(server))?;
+        Some(self.servers.remove(index))
+    }
+
+    /// Checks every entry for a malformed address or UUID and for addresses listed more than
+    /// once, returning a description of each problem found.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut seen = HashSet::new();
+        for (i, entry) in self.servers.iter().enumerate() {
+            let name = format!("Server {} ({})", i + 1, entry.address);
+            if net::parse_address(&entry.address).is_none() {
+                problems.push(format!("{}: address must be host:port", name));
+            } else if !seen.insert(entry.address.as_str()) {
+                problems.push(format!("{}: address is listed more than once", name));
+            }
+            if !is_uuid_v4(&entry.uuid) {
+                problems.push(format!("{}: {:?} is not a UUID v4", name, entry.uuid));
+            }
+            if let Some(timezone) = &entry.timezone
+                && timestamp::parse_timezone(timezone).is_err()
+            {
+                problems.push(format!("{}: unknown time zone {}", name, timezone));
+            }
+        }
+        problems
+    }
+
+    /// Adds the entries of `other` whose addresses aren't in this config yet. Those that are
+    /// replace the existing entries with `overwrite` and are skipped otherwise. Only the
+    /// servers are imported, not the theme or sender colors.
+    pub fn import(&mut self, other: ServerConfig, overwrite: bool) -> ImportSummary {
+        let mut summary = ImportSummary::default();
+        for entry in other.servers {
+            match self.servers.iter_mut().find(|e| e.address == entry.address) {
+                Some(existing) if overwrite => {
+                    *existing = entry;
+                    summary.replaced += 1;
+                }
+                Some(_) => summary.skipped += 1,
+                None => {
+                    self.servers.push(entry);
+                    summary.added += 1;
+                }
+            }
+        }
+        summary
+    }
+
+    /// Combines a shared `base` config with a user's `overlay`: entries of `overlay` replace
+    /// those of `base` with the same address, and the rest of both are kept, `base` first.
+    /// The theme of `overlay` is used unless it sets no colors, and its sender colors replace
+    /// those of `base` for the same users.
+    pub fn merge(base: ServerConfig, overlay: ServerConfig) -> ServerConfig {
+        let mut servers = base.servers;
+        for entry in overlay.servers {
+            match servers.iter_mut().find(|e| e.address == entry.address) {
+                Some(existing) => *existing = entry,
+                None => servers.push(entry),
+            }
+        }
+        let theme = if overlay.theme.is_default() {
+            base.theme
+        } else {
+            overlay.theme
+        };
+        let mut colors = base.colors;
+        colors.extend(overlay.colors);
+        ServerConfig {
+            servers,
+            theme,
+            colors,
+        }
+    }
+}
+
+/// How many entries [`ServerConfig::import`] added, replaced and skipped.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    pub added: usize,
+    pub replaced: usize,
+    pub skipped: usize,
+}
+
+/// Whether `uuid` is a well-formed UUID v4, as client UUIDs are.
+fn is_uuid_v4(uuid: &str) -> bool {
+    Uuid::parse_str(uuid).ok().and_then(|u| u.get_version()) == Some(Version::Random)
+}
+
+/// An example config with every field filled in, written by `generate-config`. JSON has no
+/// comments, so each field is explained by a `//` key next to it, which is ignored when the
+/// config is loaded.
+pub const EXAMPLE_CONFIG: &str = r##"{
+  "//": "Servers are usually added by linking: minechat-client --server <host:port> --link <code>",
+  "servers": [
+    {
+      "//address": "The server's address, as host:port or [ipv6]:port",
+      "address": "localhost:25575",
+      "//uuid": "The client UUID the server gave when linking",
+      "uuid": "your-client-uuid",
+      "//alias": "Optional. A name to use instead of the address with --server",
+      "alias": "survival",
+      "//timezone": "Optional. The IANA time zone to show message times in",
+      "timezone": "Europe/London"
+    }
+  ],
+  "//theme": "Optional. A color name, 256-color index or #rrggbb for each kind of output, or a dark and a light one for --color-scheme",
+  "theme": {
+    "message_from_color": { "dark": "cyan", "light": "blue" },
+    "timestamp_color": "bright-black",
+    "system_message_color": "yellow",
+    "private_message_color": "magenta",
+    "highlight_color": "#ff8800",
+    "error_color": "red",
+    "success_color": "green",
+    "code_color": "green",
+    "code_background_color": "236"
+  },
+  "//colors": "Optional. The color of each user's name with --color-from fixed",
+  "colors": {
+    "Notch": "bright-green",
+    "jeb_": "#ff55ff"
+  }
+}
+"##;
+
+/// Writes [`EXAMPLE_CONFIG`] to the config path, returning the path. An existing config is
+/// only replaced if `force` is set.
+pub fn generate_config(force: bool) -> Result<PathBuf, MineChatError> {
+    let path = config_path()?;
+    if path.exists() && !force {
+        return Err(MineChatError::ConfigError(format!(
+            "{} already exists, pass --force to overwrite it",
+            path.display()
+        )));
+    }
+    fs::write(&path, EXAMPLE_CONFIG)?;
+    Ok(path)
+}
+
+/// Removes the entry whose address or alias matches `server` and saves the config,
+/// returning the removed entry.
+pub fn unlink(server: &str) -> Result<ServerEntry, MineChatError> {
+    let mut config = load_config()?;
+    let entry = config.remove(server).ok_or_else(|| {
+        MineChatError::ConfigError(format!(
+            "{} is not a linked server address or alias",
+            server
+        ))
+    })?;
+    save_config(&config)?;
+    Ok(entry)
+}
+
+/// Removes every entry and saves the config, returning how many there were.
+pub fn unlink_all() -> Result<usize, MineChatError> {
+    let mut config = load_config()?;
+    let count = config.servers.len();
+    config.servers.clear();
+    save_config(&config)?;
+    Ok(count)
+}
+
+/// Imports the servers of the config at `source` into the user's config and saves it.
+pub fn import(source: &Path, overwrite: bool) -> Result<ImportSummary, MineChatError> {
+    let file = File::open(source).map_err(|e| {
+        MineChatError::ConfigError(format!("Can't open {}: {}", source.display(), e))
+    })?;
+    let imported: ServerConfig = serde_json::from_reader(file)?;
+    let mut config = load_config()?;
+    let summary = config.import(imported, overwrite);
+    save_config(&config)?;
+    Ok(summary)
+}
+
+/// Sets the alias of the server at `address` and saves the config.
+pub fn set_alias(address: &str, alias: &str) -> Result<(), MineChatError> {
+    let mut config = load_config()?;
+    let entry = config
+        .servers
+        .iter_mut()
+        .find(|e| e.address == address)
+        .ok_or(MineChatError::ServerNotLinked)?;
+    entry.alias = Some(alias.to_string());
+    save_config(&config)
+}
+
+/// Sets the time zone of the server at `address` and saves the config.
+pub fn set_timezone(address: &str, timezone: &str) -> Result<(), MineChatError> {
+    let mut config = load_config()?;
+    let entry = config
+        .servers
+        .iter_mut()
+        .find(|e| e.address == address)
+        .ok_or(MineChatError::ServerNotLinked)?;
+    entry.timezone = Some(timezone.to_string());
+    save_config(&config)
+}
+
+fn config_dir() -> Result<PathBuf, MineChatError> {
+    let proj_dirs = ProjectDirs::from("", "", "minechat")
+        .ok_or(MineChatError::ConfigError("Can't get config dir".into()))?;
+    let config_dir = proj_dirs.config_dir();
+    fs::create_dir_all(config_dir)?;
+    Ok(config_dir.to_path_buf())
+}
+
+pub fn config_path() -> Result<PathBuf, MineChatError> {
+    Ok(config_dir()?.join("servers.json"))
+}
+
+/// Returns the path of the log of connection events.
+pub fn events_log_path() -> Result<PathBuf, MineChatError> {
+    Ok(config_dir()?.join("events.log"))
+}
+
+/// Returns the path the unsent message for `server` is saved at when the shell is
+/// interrupted.
+pub fn draft_path(server: &str) -> Result<PathBuf, MineChatError> {
+    // Addresses have colons, which aren't allowed in file names everywhere.
+    let name: String = server
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' => c,
+            _ => '_',
+        })
+        .collect();
+    Ok(config_dir()?.join("drafts").join(format!("{}.txt", name)))
+}
+
+/// Returns the path of the database holding the message history.
+pub fn history_path() -> Result<PathBuf, MineChatError> {
+    Ok(config_dir()?.join("history.db"))
+}
+
+/// Loads the user's config, merged over the system-wide config at `system_config` if one
+/// is given with `--system-config`. Saving only ever writes the user's config.
+pub fn load_merged_config(system_config: Option<&Path>) -> Result<ServerConfig, MineChatError> {
+    let config = load_config()?;
+    match system_config {
+        Some(path) => {
+            let file = File::open(path).map_err(|e| {
+                MineChatError::ConfigError(format!("Can't open {}: {}", path.display(), e))
+            })?;
+            Ok(ServerConfig::merge(serde_json::from_reader(file)?, config))
+        }
+        None => Ok(config),
+    }
+}
+
+pub fn load_config() -> Result<ServerConfig, MineChatError> {
+    let path = config_path()?;
+    if !path.exists() {
+        return Ok(ServerConfig {
+            servers: Vec::new(),
+            theme: Theme::default(),
+            colors: HashMap::new(),
+        });
+    }
+    let file = File::open(path)?;
+    let config: ServerConfig = serde_json::from_reader(file)?;
+    // The config is loaded again on every reconnect, which shouldn't repeat the warnings.
+    static WARN_INVALID_UUIDS: Once = Once::new();
+    WARN_INVALID_UUIDS.call_once(|| {
+        for entry in config.servers.iter().filter(|e| !is_uuid_v4(&e.uuid)) {
+            warn!(
+                "The UUID of {} isn't a UUID v4 ({:?}); link the server again",
+                entry.address, entry.uuid
+            );
+        }
+    });
+    Ok(config)
+}
+
+pub fn save_config(config: &ServerConfig) -> Result<(), MineChatError> {
+    let path = config_path()?;
+    let file = File::create(path)?;
+    Ok(serde_json::to_writer_pretty(file, config)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(address: &str, uuid: &str) -> ServerEntry {
+        ServerEntry {
+            address: address.into(),
+            uuid: uuid.into(),
+            alias: None,
+            timezone: None,
+            retry_on_disconnect: None,
+            priority: None,
+        }
+    }
+
+    #[test]
+    fn imports_only_new_entries_unless_overwriting() {
+        let uuid = "0b6f9c4e-4b1a-4c1e-9d2f-3a5b7c9d1e2f";
+        let config = |servers| ServerConfig {
+            servers,
+            theme: Theme::default(),
+            colors: HashMap::new(),
+        };
+        let existing = || {
+            config(vec![ServerEntry {
+                alias: Some("old".into()),
+                ..entry("a:25575", uuid)
+            }])
+        };
+        let imported = || {
+            config(vec![
+                ServerEntry {
+                    alias: Some("new".into()),
+                    ..entry("a:25575", uuid)
+                },
+                entry("b:25575", uuid),
+            ])
+        };
+
+        let mut merged = existing();
+        let summary = merged.import(imported(), false);
+        assert_eq!(
+            summary,
+            ImportSummary {
+                added: 1,
+                replaced: 0,
+                skipped: 1,
+            }
+        );
+        assert_eq!(merged.servers.len(), 2);
+        assert_eq!(merged.servers[0].alias.as_deref(), Some("old"));
+
+        let mut merged = existing();
+        let summary = merged.import(imported(), true);
+        assert_eq!(
+            summary,
+            ImportSummary {
+                added: 1,
+                replaced: 1,
+                skipped: 0,
+            }
+        );
+        assert_eq!(merged.servers[0].alias.as_deref(), Some("new"));
+    }
+
+    #[test]
+    fn keeps_settings_when_relinking() {
+        let mut config = ServerConfig {
+            servers: vec![ServerEntry {
+                alias: Some("home".into()),
+                timezone: Some("Europe/Rome".into()),
+                priority: Some(1),
+                ..entry("a:25575", "0b6f9c4e-4b1a-4c1e-9d2f-3a5b7c9d1e2f")
+            }],
+            theme: Theme::default(),
+            colors: HashMap::new(),
+        };
+        let uuid = "5f3c1b2a-8d4e-4f6a-9b7c-1d2e3f4a5b6c";
+        config.set_link(ServerEntry {
+            retry_on_disconnect: Some(false),
+            ..entry("a:25575", uuid)
+        });
+        config.set_link(entry("b:25575", uuid));
+
+        assert_eq!(config.servers.len(), 2);
+        let relinked = &config.servers[0];
+        assert_eq!(relinked.uuid, uuid);
+        assert_eq!(relinked.alias.as_deref(), Some("home"));
+        assert_eq!(relinked.timezone.as_deref(), Some("Europe/Rome"));
+        assert_eq!(relinked.retry_on_disconnect, Some(false));
+        assert_eq!(relinked.priority, Some(1));
+    }
+
+    #[test]
+    fn orders_entries_by_priority() {
+        let uuid = "0b6f9c4e-4b1a-4c1e-9d2f-3a5b7c9d1e2f";
+        let config = ServerConfig {
+            servers: vec![
+                entry("a:25575", uuid),
+                ServerEntry {
+                    priority: Some(2),
+                    ..entry("b:25575", uuid)
+                },
+                entry("c:25575", uuid),
+                ServerEntry {
+                    priority: Some(0),
+                    ..entry("d:25575", uuid)
+                },
+            ],
+            theme: Theme::default(),
+            colors: HashMap::new(),
+        };
+        let order: Vec<&str> = config
+            .by_priority()
+            .iter()
+            .map(|e| e.address.as_str())
+            .collect();
+        assert_eq!(order, ["d:25575", "b:25575", "a:25575", "c:25575"]);
+    }
+
+    #[test]
+    fn reports_every_invalid_entry() {
+        let uuid = "0b6f9c4e-4b1a-4c1e-9d2f-3a5b7c9d1e2f";
+        let config = ServerConfig {
+            servers: vec![
+                entry("localhost:25575", uuid),
+                entry("", uuid),
+                entry("localhost:25575", "not-a-uuid"),
+                entry("[::1]:25566", "0b6f9c4e-4b1a-1c1e-9d2f-3a5b7c9d1e2f"),
+            ],
+            theme: Theme::default(),
+            colors: HashMap::new(),
+        };
+
+        assert_eq!(
+            config.validate(),
+            [
+                "Server 2 (): address must be host:port",
+                "Server 3 (localhost:25575): address is listed more than once",
+                "Server 3 (localhost:25575): \"not-a-uuid\" is not a UUID v4",
+                "Server 4 ([::1]:25566): \"0b6f9c4e-4b1a-1c1e-9d2f-3a5b7c9d1e2f\" is not a UUID v4",
+            ]
+        );
+    }
+
+    #[test]
+    fn removes_entries_by_address_or_alias() {
+        let mut survival = entry("survival.example.com:25575", "a");
+        survival.alias = Some("survival".into());
+        let mut config = ServerConfig {
+            servers: vec![entry("localhost:25575", "b"), survival],
+            theme: Theme::default(),
+            colors: HashMap::new(),
+        };
+
+        let removed = config.remove("survival").unwrap();
+        assert_eq!(removed.address, "survival.example.com:25575");
+        assert!(config.remove("survival").is_none());
+        assert!(config.remove("localhost:25575").is_some());
+        assert!(config.servers.is_empty());
+    }
+
+    #[test]
+    fn example_config_loads_with_its_comments_ignored() {
+        let config: ServerConfig = serde_json::from_str(EXAMPLE_CONFIG).unwrap();
+        assert_eq!(config.servers[0].alias.as_deref(), Some("survival"));
+        assert!(config.theme.code_background_color.is_some());
+        // Only the placeholder UUID needs replacing.
+        assert_eq!(config.validate().len(), 1);
+    }
+
+    #[test]
+    fn merges_overlay_entries_over_base_entries() {
+        let base = ServerConfig {
+            servers: vec![entry("lobby:25575", "base"), entry("shared:25575", "base")],
+            theme: Theme {
+                error_color: "red".parse().ok(),
+                ..Theme::default()
+            },
+            colors: HashMap::from([
+                ("alice".into(), "red".parse().unwrap()),
+                ("bob".into(), "green".parse().unwrap()),
+            ]),
+        };
+        let overlay = ServerConfig {
+            servers: vec![entry("shared:25575", "user"), entry("home:25575", "user")],
+            theme: Theme::default(),
+            colors: HashMap::from([("alice".into(), "blue".parse().unwrap())]),
+        };
+
+        let merged = ServerConfig::merge(base, overlay);
+        let servers: Vec<_> = merged
+            .servers
+            .iter()
+            .map(|e| (e.address.as_str(), e.uuid.as_str()))
+            .collect();
+        assert_eq!(
+            servers,
+            [
+                ("lobby:25575", "base"),
+                ("shared:25575", "user"),
+                ("home:25575", "user"),
+            ]
+        );
+        assert!(merged.theme.error_color.is_some());
+        assert_eq!(merged.colors["alice"], "blue".parse().unwrap());
+        assert_eq!(merged.colors["bob"], "green".parse().unwrap());
+    }
+}
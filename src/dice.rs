@@ -0,0 +1,100 @@
+//! Dice rolls in `XdY[+Z]` notation, for `/dice`.
+
+use rand::{Rng, distr::Distribution, distr::Uniform};
+use std::{fmt, str::FromStr};
+
+/// The most dice that can be rolled at once.
+const MAX_DICE: u32 = 100;
+
+/// The most sides a die can have.
+const MAX_SIDES: u32 = 1000;
+
+/// Some number of dice alike, with a number added to their total.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dice {
+    pub count: u32,
+    pub sides: u32,
+    pub modifier: i32,
+}
+
+impl FromStr for Dice {
+    type Err = ();
+
+    /// Parses notation like `2d6`, `d20` or `3d8+2`, within [`MAX_DICE`] and [`MAX_SIDES`].
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_ascii_lowercase();
+        let (count, rest) = s.split_once('d').ok_or(())?;
+        let count = if count.is_empty() {
+            1
+        } else {
+            count.parse().map_err(|_| ())?
+        };
+        let (sides, modifier) = match rest.find(['+', '-']) {
+            Some(i) => (&rest[..i], rest[i..].parse().map_err(|_| ())?),
+            None => (rest, 0),
+        };
+        let sides = sides.parse().map_err(|_| ())?;
+        if !(1..=MAX_DICE).contains(&count) || !(1..=MAX_SIDES).contains(&sides) {
+            return Err(());
+        }
+        Ok(Dice {
+            count,
+            sides,
+            modifier,
+        })
+    }
+}
+
+impl fmt::Display for Dice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}d{}", self.count, self.sides)?;
+        match self.modifier {
+            0 => Ok(()),
+            modifier => write!(f, "{:+}", modifier),
+        }
+    }
+}
+
+impl Dice {
+    /// Rolls the dice, describing the result like `Rolled 2d6+1: 3, 5 = 9`.
+    pub fn roll(&self, rng: &mut impl Rng) -> String {
+        let die = Uniform::new_inclusive(1, self.sides).expect("a die has at least one side");
+        let rolls: Vec<u32> = die.sample_iter(rng).take(self.count as usize).collect();
+        let total =
+            rolls.iter().map(|&roll| i64::from(roll)).sum::<i64>() + i64::from(self.modifier);
+        let rolls: Vec<String> = rolls.iter().map(u32::to_string).collect();
+        format!("Rolled {}: {} = {}", self, rolls.join(", "), total)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_notation_within_limits() {
+        let dice = |count, sides, modifier| {
+            Ok(Dice {
+                count,
+                sides,
+                modifier,
+            })
+        };
+        assert_eq!("2d6".parse(), dice(2, 6, 0));
+        assert_eq!("d20".parse(), dice(1, 20, 0));
+        assert_eq!("3D8+2".parse(), dice(3, 8, 2));
+        assert_eq!("1d4-1".parse(), dice(1, 4, -1));
+        for invalid in [
+            "", "6", "2d", "0d6", "101d6", "1d1001", "2d6+", "2d6*2", "xd6",
+        ] {
+            assert_eq!(invalid.parse::<Dice>(), Err(()), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn rolls_within_the_sides_of_the_dice() {
+        let mut rng = rand::rng();
+        let dice: Dice = "5d1+3".parse().unwrap();
+        assert_eq!(dice.roll(&mut rng), "Rolled 5d1+3: 1, 1, 1, 1, 1 = 8");
+    }
+}
@@ -0,0 +1,92 @@
+//! Finding MineChat servers on the local network by UDP multicast, for the `discover`
+//! subcommand.
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+use tokio::{
+    net::UdpSocket,
+    time::{self, Instant},
+};
+
+/// The multicast group and port servers listen on for discovery broadcasts.
+pub const DISCOVERY_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 251), 25565);
+
+/// How long to wait for servers to answer.
+pub const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The packet sent to ask the servers on the network to answer.
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "DISCOVERY_BROADCAST")]
+struct DiscoveryBroadcast {}
+
+/// A server's answer to a [`DiscoveryBroadcast`].
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct DiscoveryResponse {
+    /// The address to connect to, as host:port.
+    pub address: String,
+    pub name: String,
+    pub player_count: u32,
+}
+
+/// Sends a discovery broadcast to `target` and collects the answers received within
+/// `timeout`, once per address and in the order they arrived. Packets that aren't a
+/// [`DiscoveryResponse`] are ignored.
+pub async fn discover(target: SocketAddr, timeout: Duration) -> io::Result<Vec<DiscoveryResponse>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    // Keep the broadcast on the local network.
+    socket.set_multicast_ttl_v4(1)?;
+    let packet = serde_json::to_vec(&DiscoveryBroadcast {}).map_err(io::Error::other)?;
+    socket.send_to(&packet, target).await?;
+
+    let deadline = Instant::now() + timeout;
+    let mut servers: Vec<DiscoveryResponse> = Vec::new();
+    let mut buf = [0; 2048];
+    loop {
+        let (len, from) = match time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            Ok(received) => received?,
+            Err(_) => break,
+        };
+        match serde_json::from_slice::<DiscoveryResponse>(&buf[..len]) {
+            Ok(server) if servers.iter().any(|s| s.address == server.address) => {}
+            Ok(server) => servers.push(server),
+            Err(e) => debug!("Ignoring a packet from {} that isn't a server: {}", from, e),
+        }
+    }
+    Ok(servers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn collects_each_server_once() {
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let target = server.local_addr().unwrap();
+        let answering = tokio::spawn(async move {
+            let mut buf = [0; 2048];
+            let (len, client) = server.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], b"{\"type\":\"DISCOVERY_BROADCAST\"}");
+            let answer = br#"{"address":"192.168.1.5:25575","name":"Survival","player_count":3}"#;
+            server.send_to(answer, client).await.unwrap();
+            server.send_to(b"not json", client).await.unwrap();
+            server.send_to(answer, client).await.unwrap();
+        });
+
+        let servers = discover(target, Duration::from_millis(500)).await.unwrap();
+        answering.await.unwrap();
+        assert_eq!(
+            servers,
+            [DiscoveryResponse {
+                address: "192.168.1.5:25575".into(),
+                name: "Survival".into(),
+                player_count: 3,
+            }]
+        );
+    }
+}
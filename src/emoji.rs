@@ -0,0 +1,54 @@
+/// Expands `:shortcode:` patterns (e.g. `:thumbsup:`) into the emoji they name.
+///
+/// Anything between two colons that isn't a known shortcode is left untouched.
+pub fn expand_shortcodes(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find(':') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+
+        let emoji = after
+            .find(':')
+            .and_then(|end| emojis::get_by_shortcode(&after[..end]).map(|e| (e, end)));
+        match emoji {
+            Some((emoji, end)) => {
+                out.push_str(emoji.as_str());
+                rest = &after[end + 1..];
+            }
+            None => {
+                out.push(':');
+                rest = after;
+            }
+        }
+    }
+
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_known_shortcodes() {
+        assert_eq!(expand_shortcodes("nice :thumbsup:"), "nice 👍");
+        assert_eq!(expand_shortcodes(":fire::rocket:"), "🔥🚀");
+    }
+
+    #[test]
+    fn leaves_everything_else_alone() {
+        for text in [
+            "what is :foo:",
+            "meet at 12:30:45",
+            "see http://x",
+            "a lone : colon",
+            "ends with:",
+            "::",
+        ] {
+            assert_eq!(expand_shortcodes(text), text);
+        }
+    }
+}
@@ -0,0 +1,48 @@
+//! Decoding input typed in terminals that don't use UTF-8, given with `--input-encoding`.
+
+use encoding_rs::Encoding;
+use unicode_normalization::UnicodeNormalization;
+
+/// Parses an encoding label such as `cp1252` or `latin1`, for clap.
+pub fn parse_encoding(label: &str) -> Result<&'static Encoding, String> {
+    let encoding = Encoding::for_label(label.trim().as_bytes())
+        .ok_or_else(|| format!("unknown encoding {}", label))?;
+    // Input is split into lines at newline bytes, which only works if they mean the same.
+    if !encoding.is_ascii_compatible() {
+        return Err(format!("{} is not ASCII-compatible", encoding.name()));
+    }
+    Ok(encoding)
+}
+
+/// Decodes a line of input from `encoding`, or UTF-8 if none is given, normalized to NFC so
+/// accented letters are sent the same however the terminal composed them. Returns `None` if
+/// the input isn't valid in that encoding.
+pub fn decode_input(input: &[u8], encoding: Option<&'static Encoding>) -> Option<String> {
+    let text = match encoding {
+        Some(encoding) => encoding.decode_without_bom_handling_and_without_replacement(input)?,
+        None => std::str::from_utf8(input).ok()?.into(),
+    };
+    Some(text.nfc().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_legacy_encodings_to_nfc() {
+        let cp1252 = parse_encoding("cp1252").unwrap();
+        assert_eq!(
+            decode_input(b"caf\xe9 \x80", Some(cp1252)).as_deref(),
+            Some("café €")
+        );
+        // An `e` followed by a combining acute accent becomes a single `é`.
+        assert_eq!(
+            decode_input("cafe\u{301}".as_bytes(), None).as_deref(),
+            Some("café")
+        );
+        assert_eq!(decode_input(b"caf\xe9", None), None);
+        assert!(parse_encoding("utf-16le").is_err());
+        assert!(parse_encoding("klingon").is_err());
+    }
+}
@@ -0,0 +1,168 @@
+//! The connection event log, `events.log` next to the config: a line for each time the
+//! client connects, disconnects, fails to authenticate or reconnects, queried with
+//! `minechat-client events`.
+
+use chrono::{DateTime, NaiveDate, Utc};
+use log::warn;
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+};
+
+/// The format of the time at the start of each line.
+const TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%SZ";
+
+/// Something that happened to the connection to a server.
+pub enum ConnectionEvent<'a> {
+    Connected,
+    /// The session ended, for this reason.
+    Disconnected(&'a str),
+    /// The server rejected authentication, for this reason.
+    AuthFailed(&'a str),
+    /// About to reconnect for this attempt, counting from 1.
+    ReconnectAttempt(u32),
+}
+
+impl ConnectionEvent<'_> {
+    /// The name of the event in the log.
+    fn kind(&self) -> &'static str {
+        match self {
+            ConnectionEvent::Connected => "CONNECTED",
+            ConnectionEvent::Disconnected(_) => "DISCONNECTED",
+            ConnectionEvent::AuthFailed(_) => "AUTH_FAILED",
+            ConnectionEvent::ReconnectAttempt(_) => "RECONNECT_ATTEMPT",
+        }
+    }
+
+    /// What is logged after the server, if anything.
+    fn details(&self) -> Option<String> {
+        match self {
+            ConnectionEvent::Connected => None,
+            ConnectionEvent::Disconnected(reason) | ConnectionEvent::AuthFailed(reason) => {
+                Some(reason.to_string())
+            }
+            ConnectionEvent::ReconnectAttempt(attempt) => Some(attempt.to_string()),
+        }
+    }
+}
+
+/// The event log, opened for appending.
+pub struct EventLog {
+    file: File,
+}
+
+impl EventLog {
+    /// Opens the log at `path`, creating it if it doesn't exist yet.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file })
+    }
+
+    /// Adds a line for `event` on `server`. Failing to is only logged, since the session
+    /// doesn't depend on it.
+    pub fn record(&self, server: &str, event: ConnectionEvent) {
+        let time = Utc::now().format(TIME_FORMAT);
+        let mut line = format!("[{}] {} {}", time, event.kind(), server);
+        if let Some(details) = event.details() {
+            // Keep each event on one line, whatever the server sent as the reason.
+            line += " ";
+            line += &details.replace(['\r', '\n'], " ");
+        }
+        line.push('\n');
+        if let Err(e) = (&self.file).write_all(line.as_bytes()) {
+            warn!("Can't write to the event log: {}", e);
+        }
+    }
+}
+
+/// Parses the time given with `--since`, either RFC 3339 like `2026-10-14T12:00:00Z` or a
+/// date like `2026-10-14` for the start of that day in UTC.
+pub fn parse_since(s: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .map(|date| date.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc())
+        .map_err(|_| {
+            format!(
+                "Invalid time {}, expected 2026-10-14 or 2026-10-14T12:00:00Z",
+                s
+            )
+        })
+}
+
+/// Reads the lines of the log at `path` about `server`, or every server if not given, from
+/// `since` on. A log that doesn't exist yet has no lines.
+pub fn read(
+    path: &Path,
+    server: Option<&str>,
+    since: Option<DateTime<Utc>>,
+) -> io::Result<Vec<String>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut lines = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let Some((time, rest)) = line
+            .strip_prefix('[')
+            .and_then(|line| line.split_once("] "))
+        else {
+            continue;
+        };
+        let mut fields = rest.split(' ');
+        let line_server = fields.nth(1);
+        if server.is_some_and(|server| line_server != Some(server)) {
+            continue;
+        }
+        if let Some(since) = since {
+            let time = DateTime::parse_from_rfc3339(time).map(|time| time.with_timezone(&Utc));
+            if time.is_ok_and(|time| time < since) {
+                continue;
+            }
+        }
+        lines.push(line);
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_and_filters_events() {
+        let path = std::env::temp_dir().join(format!("minechat-events-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let log = EventLog::open(&path).unwrap();
+        log.record("a:25575", ConnectionEvent::Connected);
+        log.record("b:25575", ConnectionEvent::AuthFailed("Invalid UUID"));
+        log.record("a:25575", ConnectionEvent::Disconnected("Server closing"));
+        log.record("a:25575", ConnectionEvent::ReconnectAttempt(1));
+
+        let lines = read(&path, Some("a:25575"), None).unwrap();
+        let events: Vec<&str> = lines
+            .iter()
+            .map(|line| line.split_once("] ").unwrap().1)
+            .collect();
+        assert_eq!(
+            events,
+            [
+                "CONNECTED a:25575",
+                "DISCONNECTED a:25575 Server closing",
+                "RECONNECT_ATTEMPT a:25575 1",
+            ]
+        );
+        assert_eq!(read(&path, None, None).unwrap().len(), 4);
+        let tomorrow = Utc::now() + chrono::Duration::days(1);
+        assert!(read(&path, None, Some(tomorrow)).unwrap().is_empty());
+        assert_eq!(
+            parse_since("2026-10-14").unwrap(),
+            parse_since("2026-10-14T00:00:00Z").unwrap()
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
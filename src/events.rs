@@ -0,0 +1,72 @@
+//! Events of a chat session published for whatever wants to follow them, such as logging,
+//! without the REPL having to know about it.
+
+use crate::{protocol::Message, repl::ReplExit};
+use log::{debug, trace};
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// How many events a subscriber can fall behind before it misses some.
+const EVENT_CAPACITY: usize = 256;
+
+/// Something that happened in a chat session.
+#[derive(Debug, Clone)]
+pub enum MineChatEvent {
+    /// Connected and authenticated to the server at this address.
+    Connected { address: String },
+    /// A message received from the server, shared between subscribers.
+    Received(Arc<Message>),
+    /// The session ended, for this reason.
+    Disconnected(ReplExit),
+    /// The session ended because of an error.
+    Error(String),
+}
+
+/// Passes the events of a session from where they happen to every subscriber.
+pub trait EventBus: Send + Sync {
+    /// Sends `event` to the current subscribers. It's fine for there to be none.
+    fn publish(&self, event: MineChatEvent);
+
+    /// Returns a receiver for the events published from now on.
+    fn subscribe(&self) -> broadcast::Receiver<MineChatEvent>;
+}
+
+/// An [`EventBus`] on a Tokio broadcast channel. A subscriber falling more than
+/// [`EVENT_CAPACITY`] events behind misses the oldest ones instead of holding up the
+/// session.
+pub struct BroadcastBus {
+    sender: broadcast::Sender<MineChatEvent>,
+}
+
+impl Default for BroadcastBus {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::Sender::new(EVENT_CAPACITY),
+        }
+    }
+}
+
+impl EventBus for BroadcastBus {
+    fn publish(&self, event: MineChatEvent) {
+        // Sending only fails when nobody is subscribed.
+        let _ = self.sender.send(event);
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<MineChatEvent> {
+        self.sender.subscribe()
+    }
+}
+
+/// Logs the events received from `events` until the bus is dropped, for `--verbose`.
+pub async fn log_events(mut events: broadcast::Receiver<MineChatEvent>) {
+    loop {
+        match events.recv().await {
+            Ok(MineChatEvent::Connected { address }) => debug!("Session started on {}", address),
+            Ok(MineChatEvent::Received(msg)) => trace!("Received {:?}", msg),
+            Ok(MineChatEvent::Disconnected(exit)) => debug!("Session ended: {:?}", exit),
+            Ok(MineChatEvent::Error(e)) => debug!("Session failed: {}", e),
+            Err(RecvError::Lagged(missed)) => debug!("Missed logging {} events", missed),
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
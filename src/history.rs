@@ -0,0 +1,327 @@
+//! Received messages saved in an SQLite database, so `/history` and `/search` also cover
+//! earlier sessions.
+
+use crate::session::HistoryEntry;
+use minechat_protocol::protocol::MineChatError;
+use rusqlite::params;
+use serde::Serialize;
+use std::{
+    borrow::Cow,
+    io::{self, Write},
+    path::Path,
+};
+use tokio_rusqlite::Connection;
+
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS messages (
+    id INTEGER PRIMARY KEY,
+    server TEXT NOT NULL,
+    channel TEXT,
+    "from" TEXT NOT NULL,
+    message TEXT NOT NULL,
+    received_at INTEGER NOT NULL,
+    message_id TEXT,
+    reply_to TEXT,
+    sent_at INTEGER
+);
+CREATE INDEX IF NOT EXISTS messages_server ON messages (server, id);
+"#;
+
+const COLUMNS: &str =
+    r#"id, server, channel, "from", message, received_at, message_id, reply_to, sent_at"#;
+
+/// A message saved in the history database.
+#[derive(Debug, Serialize)]
+pub struct StoredMessage {
+    pub id: i64,
+    /// The address of the server the message was received from.
+    pub server: String,
+    /// The channel the client was in when the message arrived, if known.
+    pub channel: Option<String>,
+    pub from: String,
+    pub message: String,
+    /// When the message was received, in milliseconds since the Unix epoch.
+    pub received_at: u64,
+    pub message_id: Option<String>,
+    pub reply_to: Option<String>,
+    /// When the server sent the message, in milliseconds since the Unix epoch.
+    pub sent_at: Option<u64>,
+}
+
+impl StoredMessage {
+    fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get(0)?,
+            server: row.get(1)?,
+            channel: row.get(2)?,
+            from: row.get(3)?,
+            message: row.get(4)?,
+            received_at: row.get(5)?,
+            message_id: row.get(6)?,
+            reply_to: row.get(7)?,
+            sent_at: row.get(8)?,
+        })
+    }
+}
+
+impl From<StoredMessage> for HistoryEntry {
+    fn from(stored: StoredMessage) -> Self {
+        Self {
+            message_id: stored.message_id,
+            from: stored.from,
+            message: stored.message,
+            reply_to: stored.reply_to,
+            sent_at: stored.sent_at,
+            read: false,
+        }
+    }
+}
+
+/// A handle to the history database, which runs queries on a thread of its own.
+#[derive(Clone)]
+pub struct HistoryDb {
+    conn: Connection,
+    /// The longest message saved in full, in bytes.
+    max_message_len: usize,
+}
+
+impl HistoryDb {
+    /// Opens the database at `path`, creating it if needed. Messages longer than
+    /// `max_message_len` bytes are truncated when saved.
+    pub async fn open(path: &Path, max_message_len: usize) -> Result<Self, MineChatError> {
+        let conn = Connection::open(path).await.map_err(db_error)?;
+        Self::init(conn, max_message_len).await
+    }
+
+    /// Opens a database that only lasts as long as the handle.
+    #[cfg(test)]
+    pub async fn open_in_memory(max_message_len: usize) -> Result<Self, MineChatError> {
+        let conn = Connection::open_in_memory().await.map_err(db_error)?;
+        Self::init(conn, max_message_len).await
+    }
+
+    async fn init(conn: Connection, max_message_len: usize) -> Result<Self, MineChatError> {
+        let db = Self {
+            conn,
+            max_message_len,
+        };
+        db.call(|conn| conn.execute_batch(SCHEMA)).await?;
+        Ok(db)
+    }
+
+    /// Saves a message received from `server` at `received_at`, in milliseconds since the
+    /// Unix epoch.
+    pub async fn insert(
+        &self,
+        server: &str,
+        channel: Option<&str>,
+        entry: &HistoryEntry,
+        received_at: u64,
+    ) -> Result<(), MineChatError> {
+        let row = (
+            server.to_string(),
+            channel.map(str::to_string),
+            entry.from.clone(),
+            truncate(&entry.message, self.max_message_len).into_owned(),
+            received_at,
+            entry.message_id.clone(),
+            entry.reply_to.clone(),
+            entry.sent_at,
+        );
+        self.call(move |conn| {
+            conn.execute(
+                r#"INSERT INTO messages
+                   (server, channel, "from", message, received_at, message_id, reply_to, sent_at)
+                   VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)"#,
+                params![row.0, row.1, row.2, row.3, row.4, row.5, row.6, row.7],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Returns the last `limit` messages from `server`, oldest first, only counting those
+    /// whose sender or text contains `text` if given.
+    ///
+    /// Matching ignores the case of ASCII letters only.
+    pub async fn recent(
+        &self,
+        server: &str,
+        text: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<StoredMessage>, MineChatError> {
+        let server = server.to_string();
+        let text = text.map(str::to_string);
+        let mut messages = self
+            .call(move |conn| {
+                let sql = format!(
+                    r#"SELECT {} FROM messages
+                       WHERE server = ?1 AND (?2 IS NULL
+                           OR instr(lower("from"), lower(?2)) OR instr(lower(message), lower(?2)))
+                       ORDER BY id DESC LIMIT ?3"#,
+                    COLUMNS
+                );
+                let mut stmt = conn.prepare(&sql)?;
+                stmt.query_map(params![server, text, limit], StoredMessage::from_row)?
+                    .collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .await?;
+        messages.reverse();
+        Ok(messages)
+    }
+
+    /// Returns every saved message, oldest first.
+    pub async fn all(&self) -> Result<Vec<StoredMessage>, MineChatError> {
+        self.call(|conn| {
+            let sql = format!("SELECT {} FROM messages ORDER BY id", COLUMNS);
+            let mut stmt = conn.prepare(&sql)?;
+            stmt.query_map([], StoredMessage::from_row)?
+                .collect::<rusqlite::Result<Vec<_>>>()
+        })
+        .await
+    }
+
+    async fn call<F, T>(&self, function: F) -> Result<T, MineChatError>
+    where
+        F: FnOnce(&mut rusqlite::Connection) -> rusqlite::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        match self.conn.call_raw(function).await {
+            Ok(result) => result.map_err(db_error),
+            Err(e) => Err(db_error(e)),
+        }
+    }
+}
+
+/// Cuts `text` down to at most `max_len` bytes without splitting a character, marking it
+/// with `[TRUNCATED]` if anything was cut.
+fn truncate(text: &str, max_len: usize) -> Cow<'_, str> {
+    if text.len() <= max_len {
+        return Cow::Borrowed(text);
+    }
+    let end = (0..=max_len)
+        .rev()
+        .find(|&i| text.is_char_boundary(i))
+        .unwrap_or(0);
+    Cow::Owned(format!("{} [TRUNCATED]", &text[..end]))
+}
+
+fn db_error(e: impl std::error::Error + Send + Sync + 'static) -> MineChatError {
+    MineChatError::Io(io::Error::other(e))
+}
+
+/// Writes `messages` as CSV with a header row, quoting fields where needed.
+pub fn write_csv<W: Write>(out: &mut W, messages: &[StoredMessage]) -> io::Result<()> {
+    writeln!(out, "{}", COLUMNS.replace(['"', ' '], ""))?;
+    for m in messages {
+        let fields = [
+            m.id.to_string(),
+            m.server.clone(),
+            m.channel.clone().unwrap_or_default(),
+            m.from.clone(),
+            m.message.clone(),
+            m.received_at.to_string(),
+            m.message_id.clone().unwrap_or_default(),
+            m.reply_to.clone().unwrap_or_default(),
+            m.sent_at
+                .map_or(String::new(), |sent_at| sent_at.to_string()),
+        ];
+        let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        writeln!(out, "{}", fields.join(","))?;
+    }
+    Ok(())
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(from: &str, message: &str) -> HistoryEntry {
+        HistoryEntry {
+            message_id: None,
+            from: from.into(),
+            message: message.into(),
+            reply_to: None,
+            sent_at: None,
+            read: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_messages_per_server_and_searches_them() {
+        let db = HistoryDb::open_in_memory(4096).await.unwrap();
+        db.insert("a:1", None, &entry("alice", "Hello there"), 1)
+            .await
+            .unwrap();
+        db.insert("b:2", Some("general"), &entry("bob", "hello"), 2)
+            .await
+            .unwrap();
+        db.insert("a:1", None, &entry("carol", "bye"), 3)
+            .await
+            .unwrap();
+
+        let texts = |messages: Vec<StoredMessage>| {
+            messages.into_iter().map(|m| m.message).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            texts(db.recent("a:1", None, 10).await.unwrap()),
+            ["Hello there", "bye"]
+        );
+        assert_eq!(texts(db.recent("a:1", None, 1).await.unwrap()), ["bye"]);
+        assert_eq!(
+            texts(db.recent("a:1", Some("HELLO"), 10).await.unwrap()),
+            ["Hello there"]
+        );
+        let all = db.all().await.unwrap();
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[1].server, "b:2");
+        assert_eq!(all[1].channel.as_deref(), Some("general"));
+        assert_eq!(all[1].received_at, 2);
+    }
+
+    #[tokio::test]
+    async fn truncates_long_messages() {
+        let db = HistoryDb::open_in_memory(6).await.unwrap();
+        db.insert("a:1", None, &entry("alice", "short!"), 1)
+            .await
+            .unwrap();
+        db.insert("a:1", None, &entry("alice", "naïve café"), 2)
+            .await
+            .unwrap();
+
+        let saved = db.all().await.unwrap();
+        assert_eq!(saved[0].message, "short!");
+        // "naïve" is 6 bytes; the space after it doesn't fit.
+        assert_eq!(saved[1].message, "naïve [TRUNCATED]");
+    }
+
+    #[test]
+    fn quotes_csv_fields_that_need_it() {
+        let message = StoredMessage {
+            id: 1,
+            server: "localhost:25575".into(),
+            channel: Some("general".into()),
+            from: "alice".into(),
+            message: "hi, \"bob\"".into(),
+            received_at: 5,
+            message_id: None,
+            reply_to: None,
+            sent_at: Some(4),
+        };
+        let mut out = Vec::new();
+        write_csv(&mut out, &[message]).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "id,server,channel,from,message,received_at,message_id,reply_to,sent_at\n\
+             1,localhost:25575,general,alice,\"hi, \"\"bob\"\"\",5,,,4\n"
+        );
+    }
+}
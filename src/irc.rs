@@ -0,0 +1,519 @@
+//! A minimal IRC server (RFC 1459) on localhost, started with `--irc-gateway`, so IRC clients
+//! can chat on the MineChat server the client is connected to.
+
+use crate::{
+    partial::PartialMessageBuffer,
+    protocol::{self, ChatPayload, Extension, JoinPayload, LeavePayload, Message, Secret},
+    repl::ReplExit,
+};
+use log::{debug, info, warn};
+use minechat_protocol::protocol::{MineChatError, MineChatMessage};
+use std::{collections::BTreeSet, future::pending, io};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{
+        TcpListener,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+    },
+    time::Instant,
+};
+
+/// The name the gateway uses for itself in replies.
+const SERVER_NAME: &str = "minechat";
+
+/// The channel broadcasts that aren't sent in a channel of their own are shown in.
+pub const DEFAULT_CHANNEL: &str = "#minechat";
+
+/// A line sent by an IRC client.
+#[derive(Debug, PartialEq, Eq)]
+pub struct IrcMessage {
+    pub command: String,
+    pub params: Vec<String>,
+}
+
+impl IrcMessage {
+    /// Parses a line, ignoring the prefix. Returns `None` for blank lines.
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut rest = line.trim_end_matches(['\r', '\n']).trim_start();
+        if rest.starts_with(':') {
+            rest = rest.split_once(' ').map_or("", |(_, rest)| rest);
+        }
+        let (command, mut rest) = rest.split_once(' ').unwrap_or((rest, ""));
+        if command.is_empty() {
+            return None;
+        }
+
+        let mut params = Vec::new();
+        loop {
+            rest = rest.trim_start_matches(' ');
+            if rest.is_empty() {
+                break;
+            }
+            if let Some(trailing) = rest.strip_prefix(':') {
+                params.push(trailing.to_string());
+                break;
+            }
+            let (param, next) = rest.split_once(' ').unwrap_or((rest, ""));
+            params.push(param.to_string());
+            rest = next;
+        }
+        Some(Self {
+            command: command.to_ascii_uppercase(),
+            params,
+        })
+    }
+}
+
+/// What to do after an IRC client's command, besides sending the replies it queued.
+#[derive(Debug)]
+pub enum Outcome {
+    /// Send these messages to the MineChat server.
+    Forward(Vec<Extension>),
+    /// The client quit.
+    Quit,
+}
+
+/// The state of one IRC client, translating between its commands and MineChat messages.
+#[derive(Debug, Default)]
+pub struct IrcClient {
+    nick: Option<String>,
+    user: bool,
+    registered: bool,
+    /// Channels the client has joined, with their leading `#`.
+    channels: BTreeSet<String>,
+    /// Lines waiting to be sent to the client, without line endings.
+    replies: Vec<String>,
+}
+
+impl IrcClient {
+    /// Takes the lines queued for the client.
+    pub fn take_replies(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.replies)
+    }
+
+    fn nick(&self) -> &str {
+        self.nick.as_deref().unwrap_or("*")
+    }
+
+    fn mask(&self) -> String {
+        format!("{0}!{0}@{1}", self.nick(), SERVER_NAME)
+    }
+
+    fn numeric(&mut self, code: &str, text: &str) {
+        let reply = format!(":{} {} {} {}", SERVER_NAME, code, self.nick(), text);
+        self.replies.push(reply);
+    }
+
+    /// Handles a command from the client.
+    pub fn handle(&mut self, msg: IrcMessage) -> Outcome {
+        let mut forward = Vec::new();
+        match (msg.command.as_str(), msg.params.first()) {
+            ("QUIT", _) => return Outcome::Quit,
+            ("PING", token) => {
+                let token = token.map_or(SERVER_NAME, String::as_str);
+                self.replies
+                    .push(format!(":{0} PONG {0} :{1}", SERVER_NAME, token));
+            }
+            ("PONG", _) | ("PASS", _) => {}
+            ("NICK", None) => self.numeric("431", ":No nickname given"),
+            ("NICK", Some(nick)) => {
+                if self.registered {
+                    let reply = format!(":{} NICK :{}", self.mask(), nick);
+                    self.replies.push(reply);
+                }
+                self.nick = Some(nick.clone());
+                self.register();
+            }
+            ("USER", _) => {
+                self.user = true;
+                self.register();
+            }
+            (_, _) if !self.registered => self.numeric("451", ":You have not registered"),
+            ("JOIN", None) | ("PART", None) | ("PRIVMSG", None) => {
+                self.numeric("461", &format!("{} :Not enough parameters", msg.command))
+            }
+            ("JOIN", Some(channels)) => {
+                let keys: Vec<&str> = msg
+                    .params
+                    .get(1)
+                    .map_or(Vec::new(), |keys| keys.split(',').collect());
+                for (i, channel) in channels.split(',').enumerate() {
+                    if channel.eq_ignore_ascii_case(DEFAULT_CHANNEL) {
+                        self.joined(DEFAULT_CHANNEL);
+                    } else if let Some(name) = channel.strip_prefix(['#', '&']) {
+                        forward.push(Extension::ChannelJoin {
+                            payload: JoinPayload {
+                                channel: name.to_string(),
+                                password: keys.get(i).map(|key| Secret(key.to_string())),
+                            },
+                        });
+                    } else {
+                        self.numeric("403", &format!("{} :No such channel", channel));
+                    }
+                }
+            }
+            ("PART", Some(channels)) => {
+                for channel in channels.split(',') {
+                    if !self.channels.remove(channel) {
+                        self.numeric("442", &format!("{} :You're not on that channel", channel));
+                        continue;
+                    }
+                    let reply = format!(":{} PART {}", self.mask(), channel);
+                    self.replies.push(reply);
+                    if !channel.eq_ignore_ascii_case(DEFAULT_CHANNEL) {
+                        forward.push(Extension::ChannelLeave {
+                            payload: LeavePayload {
+                                channel: channel[1..].to_string(),
+                            },
+                        });
+                    }
+                }
+            }
+            ("PRIVMSG", Some(target)) => match msg.params.get(1) {
+                None => self.numeric("412", ":No text to send"),
+                Some(_) if !target.starts_with(['#', '&']) => self.numeric(
+                    "401",
+                    &format!("{} :Only channel messages are supported", target),
+                ),
+                Some(text) => forward.push(Extension::Chat {
+                    payload: ChatPayload {
+                        message: text.clone(),
+                        message_id: None,
+                        signature: None,
+                        reply_to: None,
+                    },
+                }),
+            },
+            (command, _) => self.numeric("421", &format!("{} :Unknown command", command)),
+        }
+        Outcome::Forward(forward)
+    }
+
+    /// Welcomes the client once it has given both its nickname and user details.
+    fn register(&mut self) {
+        if self.registered || !self.user || self.nick.is_none() {
+            return;
+        }
+        self.registered = true;
+        let welcome = format!(":Welcome to the MineChat IRC gateway {}", self.mask());
+        self.numeric("001", &welcome);
+        self.numeric("002", &format!(":Your host is {}", SERVER_NAME));
+        self.numeric("376", ":End of MOTD command");
+        self.joined(DEFAULT_CHANNEL);
+    }
+
+    fn joined(&mut self, channel: &str) {
+        if self.channels.insert(channel.to_string()) {
+            let reply = format!(":{} JOIN {}", self.mask(), channel);
+            self.replies.push(reply);
+        }
+    }
+
+    /// Translates a message from the MineChat server into lines for the client.
+    pub fn receive(&mut self, msg: &Message) {
+        if !self.registered {
+            return;
+        }
+        match msg {
+            Message::Extension(
+                Extension::Broadcast { payload } | Extension::TaggedBroadcast { payload },
+            ) => {
+                let channel = payload
+                    .channel
+                    .as_ref()
+                    .map_or(DEFAULT_CHANNEL.to_string(), |channel| {
+                        format!("#{}", channel)
+                    });
+                let from = nick(&payload.from);
+                for line in payload.message.lines() {
+                    let reply = format!(
+                        ":{0}!{0}@{1} PRIVMSG {2} :{3}",
+                        from, SERVER_NAME, channel, line
+                    );
+                    self.replies.push(reply);
+                }
+            }
+            Message::Extension(Extension::NameChange { payload }) => {
+                let old = nick(&payload.old_username);
+                let new = nick(&payload.new_username);
+                self.replies
+                    .push(format!(":{0}!{0}@{1} NICK :{2}", old, SERVER_NAME, new));
+            }
+            Message::Extension(Extension::ChannelJoinAck { payload }) => {
+                let channel = format!("#{}", payload.channel);
+                match payload.status.as_str() {
+                    "success" => self.joined(&channel),
+                    "wrong_password" => self.numeric(
+                        "475",
+                        &format!(
+                            "{} :Cannot join channel (+k) - {}",
+                            channel, payload.message
+                        ),
+                    ),
+                    _ => self.numeric(
+                        "403",
+                        &format!("{} :Cannot join channel - {}", channel, payload.message),
+                    ),
+                }
+            }
+            Message::Extension(Extension::ChannelTopicSet { payload }) => {
+                let setter = if payload.set_by.is_empty() {
+                    SERVER_NAME
+                } else {
+                    payload.set_by.as_str()
+                };
+                let reply = format!(":{} TOPIC #{} :{}", setter, payload.channel, payload.topic);
+                self.replies.push(reply);
+            }
+            Message::Core(MineChatMessage::Disconnect { payload }) => {
+                let reply = format!(
+                    ":{} NOTICE {} :Disconnected: {}",
+                    SERVER_NAME,
+                    self.nick(),
+                    payload.reason
+                );
+                self.replies.push(reply);
+            }
+            msg => debug!("Not forwarding to IRC: {:?}", msg),
+        }
+    }
+}
+
+/// An IRC client connected to the gateway.
+struct Connection {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+    client: IrcClient,
+}
+
+impl Connection {
+    async fn flush_replies(&mut self) -> io::Result<()> {
+        for reply in self.client.take_replies() {
+            self.writer.write_all(reply.as_bytes()).await?;
+            self.writer.write_all(b"\r\n").await?;
+        }
+        self.writer.flush().await
+    }
+}
+
+/// Bridges IRC clients accepted on `listener`, one at a time, with the MineChat server on
+/// `reader` and `writer` until the server closes the connection.
+pub async fn serve<R, W>(
+    listener: &TcpListener,
+    mut reader: R,
+    mut writer: W,
+    safe_mode: bool,
+) -> Result<ReplExit, MineChatError>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut connection: Option<Connection> = None;
+    let mut server_line = Vec::new();
+    let mut client_line = Vec::new();
+    let mut partials = PartialMessageBuffer::default();
+    loop {
+        tokio::select! {
+            accepted = listener.accept(), if connection.is_none() => {
+                let (stream, address) = accepted?;
+                info!("IRC client connected from {}", address);
+                let (reader, writer) = stream.into_split();
+                connection = Some(Connection {
+                    reader: BufReader::new(reader),
+                    writer,
+                    client: IrcClient::default(),
+                });
+            }
+            read = read_client(connection.as_mut(), &mut client_line) => {
+                let Some(conn) = connection.as_mut() else {
+                    continue;
+                };
+                let line = String::from_utf8_lossy(&client_line).into_owned();
+                client_line.clear();
+                let outcome = match read {
+                    Ok(0) | Err(_) => Outcome::Quit,
+                    Ok(_) => match IrcMessage::parse(&line) {
+                        Some(msg) => conn.client.handle(msg),
+                        None => Outcome::Forward(Vec::new()),
+                    },
+                };
+                match outcome {
+                    Outcome::Forward(messages) => {
+                        for msg in &messages {
+                            if safe_mode && !msg.is_safe() {
+                                warn!("Safe mode: not forwarding a message from the IRC client");
+                                continue;
+                            }
+                            protocol::send(&mut writer, msg).await?;
+                        }
+                        writer.flush().await?;
+                        if let Err(e) = conn.flush_replies().await {
+                            warn!("Can't write to the IRC client: {}", e);
+                            connection = None;
+                        }
+                    }
+                    Outcome::Quit => {
+                        info!("IRC client disconnected");
+                        connection = None;
+                    }
+                }
+            }
+            read = reader.read_until(b'\n', &mut server_line) => {
+                if read? == 0 {
+                    return Ok(ReplExit::ConnectionLost);
+                }
+                let msg = serde_json::from_slice::<Message>(&server_line)
+                    .and_then(|msg| partials.reassemble(msg, Instant::now()));
+                server_line.clear();
+                let msg = match msg {
+                    Ok(Some(msg)) => msg,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        debug!("Ignoring malformed message: {}", e);
+                        continue;
+                    }
+                };
+                let disconnected = match &msg {
+                    Message::Core(MineChatMessage::Disconnect { payload }) => {
+                        Some(payload.reason.clone())
+                    }
+                    _ => None,
+                };
+                if let Some(conn) = connection.as_mut() {
+                    conn.client.receive(&msg);
+                    if let Err(e) = conn.flush_replies().await {
+                        warn!("Can't write to the IRC client: {}", e);
+                        connection = None;
+                    }
+                }
+                if let Some(reason) = disconnected {
+                    return Ok(ReplExit::Server { reason });
+                }
+            }
+        }
+    }
+}
+
+/// Turns a MineChat username into a nick, replacing the characters IRC gives a meaning.
+fn nick(username: &str) -> String {
+    username.replace([' ', '!', '@', ':'], "_")
+}
+
+/// Reads a line from the IRC client, if one is connected, or waits forever.
+async fn read_client(connection: Option<&mut Connection>, buf: &mut Vec<u8>) -> io::Result<usize> {
+    match connection {
+        Some(connection) => connection.reader.read_until(b'\n', buf).await,
+        None => pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{BroadcastPayload, JoinAckPayload};
+
+    fn registered() -> IrcClient {
+        let mut client = IrcClient::default();
+        client.handle(IrcMessage::parse("NICK alice").unwrap());
+        client.handle(IrcMessage::parse("USER alice 0 * :Alice").unwrap());
+        client.take_replies();
+        client
+    }
+
+    #[test]
+    fn parses_prefixes_and_trailing_parameters() {
+        assert_eq!(
+            IrcMessage::parse(":alice!a@host privmsg #general :hello there\r\n"),
+            Some(IrcMessage {
+                command: "PRIVMSG".into(),
+                params: vec!["#general".into(), "hello there".into()],
+            })
+        );
+        assert_eq!(IrcMessage::parse("\r\n"), None);
+    }
+
+    #[test]
+    fn welcomes_clients_once_registered() {
+        let mut client = IrcClient::default();
+        client.handle(IrcMessage::parse("NICK alice").unwrap());
+        assert!(client.take_replies().is_empty());
+        client.handle(IrcMessage::parse("USER alice 0 * :Alice").unwrap());
+        let replies = client.take_replies();
+        assert!(replies[0].starts_with(":minechat 001 alice "));
+        assert_eq!(
+            replies.last().unwrap(),
+            ":alice!alice@minechat JOIN #minechat"
+        );
+
+        client.handle(IrcMessage::parse("PING :12345").unwrap());
+        assert_eq!(client.take_replies(), [":minechat PONG minechat :12345"]);
+    }
+
+    #[test]
+    fn maps_commands_to_minechat_messages() {
+        let mut client = registered();
+        let Outcome::Forward(sent) =
+            client.handle(IrcMessage::parse("PRIVMSG #minechat :hi").unwrap())
+        else {
+            panic!("expected messages to forward");
+        };
+        assert!(matches!(&sent[..], [Extension::Chat { payload }] if payload.message == "hi"));
+
+        let Outcome::Forward(sent) =
+            client.handle(IrcMessage::parse("JOIN #staff secret").unwrap())
+        else {
+            panic!("expected messages to forward");
+        };
+        assert!(matches!(
+            &sent[..],
+            [Extension::ChannelJoin { payload }]
+                if payload.channel == "staff" && payload.password.as_ref().is_some_and(|p| p.0 == "secret")
+        ));
+
+        client.receive(&Message::Extension(Extension::ChannelJoinAck {
+            payload: JoinAckPayload {
+                channel: "staff".into(),
+                status: "success".into(),
+                message: String::new(),
+            },
+        }));
+        assert_eq!(client.take_replies(), [":alice!alice@minechat JOIN #staff"]);
+
+        let Outcome::Forward(sent) = client.handle(IrcMessage::parse("PART #staff").unwrap())
+        else {
+            panic!("expected messages to forward");
+        };
+        assert!(matches!(
+            &sent[..],
+            [Extension::ChannelLeave { payload }] if payload.channel == "staff"
+        ));
+        assert!(matches!(
+            client.handle(IrcMessage::parse("QUIT :bye").unwrap()),
+            Outcome::Quit
+        ));
+    }
+
+    #[test]
+    fn shows_broadcasts_as_channel_messages() {
+        let mut client = registered();
+        client.receive(&Message::Extension(Extension::Broadcast {
+            payload: BroadcastPayload {
+                from: "Steve".into(),
+                message: "hello\nworld".into(),
+                message_id: None,
+                signature: None,
+                sent_at: None,
+                reply_to: None,
+                channel: None,
+                tags: Vec::new(),
+            },
+        }));
+        assert_eq!(
+            client.take_replies(),
+            [
+                ":Steve!Steve@minechat PRIVMSG #minechat :hello",
+                ":Steve!Steve@minechat PRIVMSG #minechat :world",
+            ]
+        );
+    }
+}
@@ -0,0 +1,223 @@
+//! Linking the client to a server with a code generated in Minecraft.
+
+use crate::{
+    net::{self, SocketOptions, Transport},
+    protocol::{self, Extension, Message},
+    shutdown, ws,
+};
+use log::debug;
+use minechat_protocol::protocol::{AuthPayload, MineChatError, MineChatMessage};
+use qrcode::{QrCode, render::unicode::Dense1x2};
+use std::{
+    io::{self, Write},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    time::{self, Instant, MissedTickBehavior},
+};
+use uuid::Uuid;
+
+/// Links a new client UUID to the server at `address` with `code`, returning the UUID. The
+/// link goes over the same transport as chat sessions.
+pub async fn link(
+    address: &str,
+    code: &str,
+    socket_options: &SocketOptions,
+) -> Result<String, MineChatError> {
+    let stream = net::connect(address, socket_options).await?;
+    let client_uuid = Uuid::new_v4().to_string();
+    let linking = async {
+        match socket_options.transport {
+            Transport::Tcp => {
+                let (reader, writer) = stream.into_split();
+                exchange(BufReader::new(reader), writer, &client_uuid, code).await
+            }
+            Transport::WebSocket { tls } => {
+                let frames = ws::handshake(stream, address, tls).await?;
+                let (reader, writer) = tokio::io::split(frames);
+                exchange(BufReader::new(reader), writer, &client_uuid, code).await
+            }
+        }
+    };
+    // Waiting for the server to accept the code can take as long as the code is valid.
+    tokio::select! {
+        result = linking => result?,
+        result = shutdown::signal() => {
+            result?;
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "linking was interrupted").into());
+        }
+    }
+    Ok(client_uuid)
+}
+
+/// Authenticates with the link code and waits for the server to accept it, showing how long
+/// the code stays valid if the server says so.
+async fn exchange<R, W>(
+    mut reader: R,
+    mut writer: W,
+    client_uuid: &str,
+    code: &str,
+) -> Result<(), MineChatError>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let msg = MineChatMessage::Auth {
+        payload: AuthPayload {
+            client_uuid: client_uuid.to_string(),
+            link_code: code.to_string(),
+        },
+    };
+    protocol::send(&mut writer, &msg).await?;
+    writer.flush().await?;
+
+    let mut expires_at = None;
+    let mut ticker = time::interval(Duration::from_secs(1));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut line = Vec::new();
+    let result = loop {
+        tokio::select! {
+            read = reader.read_until(b'\n', &mut line) => {
+                if read? == 0 {
+                    break Err(MineChatError::Disconnected);
+                }
+                match serde_json::from_slice::<Message>(&line) {
+                    Ok(Message::Core(MineChatMessage::AuthAck { payload })) => {
+                        break if payload.status == "success" {
+                            Ok(())
+                        } else {
+                            Err(MineChatError::AuthFailed(payload.message))
+                        };
+                    }
+                    Ok(Message::Extension(Extension::LinkExpiry { payload })) => {
+                        if payload.seconds_remaining == 0 {
+                            break Err(expired());
+                        }
+                        let remaining = Duration::from_secs(payload.seconds_remaining);
+                        expires_at = Some(Instant::now() + remaining);
+                    }
+                    Ok(msg) => debug!("Ignoring message while linking: {:?}", msg),
+                    Err(e) => debug!("Ignoring malformed message: {}", e),
+                }
+                line.clear();
+            }
+            _ = ticker.tick(), if expires_at.is_some() => {
+                let remaining = expires_at
+                    .expect("the countdown only runs once started")
+                    .saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    break Err(expired());
+                }
+                eprint!("\rLink code expires in {}s ", remaining.as_secs_f64().round());
+                let _ = std::io::stderr().flush();
+            }
+        }
+    };
+    if expires_at.is_some() {
+        // End the countdown line.
+        eprintln!();
+    }
+    result
+}
+
+/// Renders `code` as a QR code drawn with Unicode block characters, two rows of modules per
+/// line of text.
+pub fn qr_code(code: &str) -> Result<String, MineChatError> {
+    let qr = QrCode::new(code).map_err(|e| {
+        MineChatError::ConfigError(format!("Can't show the link code as a QR code: {}", e))
+    })?;
+    // Inverted, so the code reads as dark on light on a terminal with a dark background.
+    Ok(qr
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .build())
+}
+
+fn expired() -> MineChatError {
+    MineChatError::AuthFailed(
+        "The link code expired; run /link in Minecraft to get a new one".into(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::{
+        io::{duplex, split},
+        net::TcpListener,
+    };
+    use tokio_tungstenite::tungstenite::Message as Frame;
+
+    #[test]
+    fn draws_qr_codes_with_block_characters() {
+        let qr = qr_code("ABC123").unwrap();
+        let lines: Vec<&str> = qr.lines().collect();
+        // A version 1 code is 21 modules wide, plus a 4-module quiet zone on each side.
+        assert_eq!(lines.len(), 15);
+        assert!(lines.iter().all(|line| line.chars().count() == 29));
+        assert!(qr.chars().all(|c| " ▀▄█\n".contains(c)));
+    }
+
+    #[tokio::test]
+    async fn fails_when_the_code_expires() {
+        let (client, mut server) = duplex(4096);
+        let (reader, writer) = split(client);
+        server
+            .write_all(b"{\"type\":\"LINK_EXPIRY\",\"payload\":{\"seconds_remaining\":0}}\n")
+            .await
+            .unwrap();
+
+        let result = exchange(BufReader::new(reader), writer, "uuid", "code").await;
+        assert!(
+            matches!(result, Err(MineChatError::AuthFailed(reason)) if reason.contains("expired"))
+        );
+    }
+
+    #[tokio::test]
+    async fn succeeds_on_auth_ack_after_expiry_notice() {
+        let (client, mut server) = duplex(4096);
+        let (reader, writer) = split(client);
+        server
+            .write_all(
+                b"{\"type\":\"LINK_EXPIRY\",\"payload\":{\"seconds_remaining\":60}}\n\
+                  {\"type\":\"AUTH_ACK\",\"payload\":{\"status\":\"success\",\"message\":\"ok\"}}\n",
+            )
+            .await
+            .unwrap();
+
+        exchange(BufReader::new(reader), writer, "uuid", "code")
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn links_over_websocket() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let auth = ws.next().await.unwrap().unwrap();
+            ws.send(Frame::text(
+                "{\"type\":\"AUTH_ACK\",\"payload\":{\"status\":\"success\",\"message\":\"ok\"}}",
+            ))
+            .await
+            .unwrap();
+            auth
+        });
+        let options = SocketOptions {
+            keepalive_secs: None,
+            nodelay: true,
+            read_buffer_size: 8192,
+            write_buffer_size: 8192,
+            transport: Transport::WebSocket { tls: false },
+        };
+
+        link(&address, "code", &options).await.unwrap();
+        let auth = server.await.unwrap().into_text().unwrap();
+        assert!(auth.starts_with("{\"type\":\"AUTH\""));
+    }
+}
@@ -1,124 +1,1021 @@
-use clap::Parser;
-use directories::ProjectDirs;
+mod away;
+mod commands;
+mod config;
+mod dice;
+mod discover;
+mod emoji;
+mod encoding;
+mod eventlog;
+mod events;
+mod history;
+mod irc;
+mod link;
+mod markup;
+mod net;
+mod netdiag;
+mod pager;
+mod partial;
+mod pidfile;
+mod pipe_in;
+mod protocol;
+mod queue;
+mod repl;
+mod sanitize;
+mod schedule;
+mod scoreboard;
+mod session;
+mod shell;
+mod shutdown;
+mod signing;
+mod sniff;
+mod spam;
+mod stats;
+mod table;
+mod tail;
+mod tee;
+mod theme;
+mod timestamp;
+mod ws;
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use clap::{Parser, Subcommand, ValueEnum};
+use config::{
+    ServerConfig, ServerEntry, config_path, load_config, load_merged_config, save_config,
+};
 use env_logger::{Builder, Target};
-use log::{debug, info};
+use eventlog::{ConnectionEvent, EventLog};
+use futures_util::future::join_all;
+use history::HistoryDb;
+use log::{debug, info, warn};
 use miette::Result;
 use minechat_protocol::{
-    packets::{self, receive_message, send_message},
+    packets::receive_message,
     protocol::{MineChatError, *},
 };
-use serde::{Deserialize, Serialize};
+use net::{SocketOptions, Transport};
+use pidfile::PidFile;
+use pipe_in::PipeIn;
+use protocol::{
+    ChatPayload, ClientInfoPayload, Extension, Message, PingPayload, SessionAuthPayload,
+};
+use queue::MessageQueue;
+use repl::{ReplExit, ReplOptions, repl};
+use scoreboard::ScoreboardMode;
+use session::Session;
+use shell::{MultilineStrategy, PromptContext};
+use signing::{MessageSigner, MessageVerifier};
+use sniff::{SniffingReader, SniffingWriter};
+use socket2::SockRef;
+use spam::SpamGuard;
 use std::{
-    fs::{self, File},
-    path::PathBuf,
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
+use table::Align;
+use tee::TeeSocket;
+use theme::{ColorFrom, ColorScheme, SenderColors};
 use tokio::{
-    io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, BufReader},
-    net::TcpStream,
-    signal,
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter,
+    },
+    net::TcpListener,
+    process,
+    time::{self, Instant, MissedTickBehavior},
 };
+use tracing_subscriber::EnvFilter;
+
+/// How long to wait before reconnecting after losing the connection.
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
+/// How many times to reconnect to a server whose entry asks for it, when
+/// `--reconnect-attempts` isn't given.
+const DEFAULT_RECONNECT_ATTEMPTS: u32 = 3;
+
+/// The longest random delay added after a server restart, so clients don't all reconnect
+/// at once.
+const RESTART_JITTER: Duration = Duration::from_secs(5);
+
+/// How long to wait for the server to answer a ping.
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long `status-all` waits for each server before calling it offline.
+const STATUS_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The reading half of a connection to the server.
+type Reader = Box<dyn AsyncBufRead + Unpin + Send>;
+
+/// The writing half of a connection to the server.
+type Writer = Box<dyn AsyncWrite + Unpin + Send>;
 
 #[derive(Parser)]
 #[clap(
     name = "MineCLI",
     version = "0.1.1",
     author = "walker84837",
-    about = "CLI client for MineChat"
+    about = "CLI client for MineChat",
+    subcommand_negates_reqs = true
 )]
 struct Args {
-    /// The MineChat server address (host:port)
+    /// The MineChat server address (host:port) or alias
     #[clap(short, long, required = true)]
-    server: String,
+    server: Option<String>,
 
     /// Link account using the provided code
     #[clap(long)]
     link: Option<String>,
 
+    /// Show the link code as a QR code while linking
+    #[clap(long, requires = "link")]
+    qr: bool,
+
+    /// Human-readable name to store for the server when linking
+    #[clap(long, requires = "link")]
+    server_alias: Option<String>,
+
+    /// Never reconnect to the server being linked, whatever --reconnect-attempts says
+    #[clap(long, requires = "link")]
+    no_retry: bool,
+
+    /// The order to connect to the server being linked in among others, lowest first
+    #[clap(long, value_name = "N", requires = "link")]
+    priority: Option<u8>,
+
     /// Enable verbose logging
     #[clap(short, long)]
     verbose: bool,
-}
 
-#[derive(Debug, Serialize, Deserialize)]
-struct ServerConfig {
-    servers: Vec<ServerEntry>,
-}
+    /// A shared config file whose servers and theme are used unless the user's own config
+    /// overrides them, e.g. one managed by an administrator
+    #[clap(long, value_name = "PATH")]
+    system_config: Option<PathBuf>,
+
+    /// Create a named pipe at this path and read messages and commands from it as if they
+    /// were typed, removing it on exit
+    #[clap(long, value_name = "PATH")]
+    pipe_in: Option<PathBuf>,
+
+    /// Listen on a Unix domain socket at this path and send every received message to the
+    /// processes connected to it as a line of JSON, removing it on exit
+    #[clap(long, value_name = "PATH")]
+    tee_socket: Option<PathBuf>,
+
+    /// Instead of chatting here, run an IRC server on this port of localhost and relay
+    /// between IRC clients and the MineChat server
+    #[clap(long, value_name = "PORT")]
+    irc_gateway: Option<u16>,
+
+    /// Write the process ID to this file, removing it on exit
+    #[clap(long, value_name = "PATH")]
+    write_pid: Option<PathBuf>,
+
+    /// Per-module log levels, e.g. `minechat_protocol=debug,minechat_client=info`. Takes
+    /// precedence over --verbose
+    #[clap(long, value_name = "FILTER")]
+    log_level_filter: Option<String>,
+
+    /// Format of log output
+    #[clap(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Don't expand `:shortcode:` emoji in sent messages
+    #[clap(long)]
+    no_emoji_expand: bool,
+
+    /// Put this text before every sent chat message, e.g. "[Bot]"
+    #[clap(long, value_name = "TEXT")]
+    chat_prefix: Option<String>,
+
+    /// Collapse runs of spaces, tabs and blank lines in sent chat messages, outside code
+    /// blocks (the default)
+    #[clap(long, overrides_with = "no_normalize_input")]
+    normalize_input: bool,
+
+    /// Send chat messages with their whitespace as typed
+    #[clap(long, overrides_with = "normalize_input")]
+    no_normalize_input: bool,
+
+    /// How to send the line breaks of messages written in an external editor with Escape
+    /// or /edit
+    #[clap(long, value_enum, default_value_t = MultilineStrategy::Collapse)]
+    multiline_strategy: MultilineStrategy,
+
+    /// Show how many words and characters each sent message has
+    #[clap(long)]
+    word_count: bool,
+
+    /// The automatic reply to private messages while /away, where {from} is the sender and
+    /// {time_away} how long you have been away
+    #[clap(long, value_name = "TEMPLATE", default_value = away::DEFAULT_REPLY)]
+    away_reply: String,
+
+    /// Don't send the automatic reply to the same user more than once in this many seconds
+    #[clap(long, value_name = "SECONDS", default_value_t = 60)]
+    away_reply_cooldown: u64,
+
+    /// Remove control characters and ANSI escape sequences from received messages
+    #[clap(long)]
+    strip_formatting: bool,
+
+    /// Whether the terminal background is dark or light, to pick the theme colors for it.
+    /// auto asks the terminal, assuming dark if it doesn't answer
+    #[clap(long, value_enum, default_value_t = ColorScheme::Dark)]
+    color_scheme: ColorScheme,
+
+    /// Color each sender's name: hash picks a color from the name, cycle gives each new
+    /// sender the next color, and fixed uses the colors section of the config. Otherwise
+    /// every name has message_from_color
+    #[clap(long, value_enum, value_name = "MODE")]
+    color_from: Option<ColorFrom>,
+
+    /// Retry connecting when the server rejects authentication
+    #[clap(long)]
+    reconnect_on_auth_failure: bool,
+
+    /// Command to run before retrying a failed authentication, expected to refresh the
+    /// client UUID in the config file
+    #[clap(long, requires = "reconnect_on_auth_failure")]
+    auth_refresh_hook: Option<String>,
+
+    /// Maximum number of retries after an authentication failure
+    #[clap(long, default_value_t = 3, requires = "reconnect_on_auth_failure")]
+    auth_retry_attempts: u32,
+
+    /// Sign sent messages with the Ed25519 private key (PKCS#8 PEM) at this path
+    #[clap(long, value_name = "PRIVATE_KEY_PATH")]
+    sign_messages: Option<PathBuf>,
+
+    /// Verify signatures on received messages with the Ed25519 public key (PEM) at this path
+    #[clap(long, value_name = "PUBLIC_KEY_PATH")]
+    verify_messages: Option<PathBuf>,
+
+    /// Number of times to reconnect after losing the connection to the server
+    #[clap(long, default_value_t = 0)]
+    reconnect_attempts: u32,
+
+    /// Enable TCP keep-alive with the given idle time in seconds (0 disables it)
+    #[clap(long, value_name = "SECONDS")]
+    tcp_keepalive: Option<u64>,
+
+    /// Send small packets immediately instead of buffering them (Nagle's algorithm); pass
+    /// `--tcp-nodelay false` to save bandwidth instead
+    #[clap(
+        long,
+        value_name = "BOOL",
+        default_value_t = true,
+        num_args = 0..=1,
+        default_missing_value = "true",
+        action = clap::ArgAction::Set
+    )]
+    tcp_nodelay: bool,
+
+    /// Show message times in this time zone (e.g. America/New_York) instead of UTC, and
+    /// remember it for the server
+    #[clap(long, value_name = "TZ", value_parser = timestamp::parse_timezone)]
+    timezone: Option<Tz>,
+
+    /// Show message times in this strftime format, or `short` (HH:MM), `long`
+    /// (YYYY-MM-DD HH:MM:SS) or `iso` (ISO 8601 with the offset)
+    #[clap(
+        long,
+        visible_alias = "format-timestamp",
+        value_name = "FORMAT",
+        default_value = timestamp::DEFAULT_TIME_FORMAT,
+        value_parser = timestamp::parse_time_format
+    )]
+    time_format: String,
+
+    /// Connect through a WebSocket (ws://) instead of plain TCP, for servers behind a
+    /// WebSocket proxy
+    #[clap(long)]
+    ws: bool,
+
+    /// Use a secure WebSocket (wss://)
+    #[clap(long, requires = "ws")]
+    tls: bool,
+
+    /// Size in bytes of the buffer for data read from the server
+    #[clap(long, value_name = "BYTES", default_value_t = 65536, value_parser = clap::value_parser!(u32).range(1..))]
+    read_buffer_size: u32,
+
+    /// Size in bytes of the buffer for data written to the server
+    #[clap(long, value_name = "BYTES", default_value_t = 65536, value_parser = clap::value_parser!(u32).range(1..))]
+    write_buffer_size: u32,
+
+    /// Warn when broadcasts take longer than this many milliseconds to arrive
+    #[clap(long, value_name = "MS")]
+    maxlag_warn: Option<u64>,
+
+    /// Reconnect when broadcasts take longer than this many milliseconds to arrive, as long
+    /// as --reconnect-attempts allows reconnecting
+    #[clap(long, value_name = "MS")]
+    maxlag_disconnect: Option<u64>,
+
+    /// Decode typed input from this encoding instead of UTF-8, e.g. cp1252 or latin1. Turns
+    /// off line editing, which only reads UTF-8
+    #[clap(long, value_name = "ENCODING", value_parser = encoding::parse_encoding)]
+    input_encoding: Option<&'static encoding_rs::Encoding>,
+
+    /// Reconnect when nothing has been received from the server for this many seconds, as
+    /// long as --reconnect-attempts allows reconnecting (0 turns this off)
+    #[clap(long, value_name = "SECONDS", default_value_t = 120)]
+    server_timeout: u64,
+
+    /// Don't show messages replayed after reconnecting that were sent more than this many
+    /// seconds ago (0 shows them all)
+    #[clap(long, value_name = "SECONDS", default_value_t = 300)]
+    max_message_age: u64,
+
+    /// Prompt shown before typed input, after the current server and channel
+    #[clap(long, default_value = "> ")]
+    prompt: String,
+
+    /// Ask the server to confirm delivery of sent messages and show how many users received
+    /// them
+    #[clap(long)]
+    show_delivery: bool,
+
+    /// Block sending for this many seconds after repeating a message or sending too many
+    /// at once (0 turns this off)
+    #[clap(long, value_name = "SECONDS", default_value_t = 10)]
+    anti_spam_penalty: u64,
+
+    /// Don't show the scoreboard sent by the server
+    #[clap(long)]
+    no_scoreboard: bool,
+
+    /// Don't show the greeting the server sends to new users
+    #[clap(long)]
+    no_greeting: bool,
+
+    /// Print long /history and /search output directly instead of through $PAGER
+    #[clap(long)]
+    no_pager: bool,
+
+    /// Don't show received broadcasts, for sending only (toggle with /mute and /unmute)
+    #[clap(long)]
+    mute: bool,
+
+    /// Don't show broadcasts sent in this channel, without leaving it (toggle with
+    /// /mute-channel and /unmute-channel). Can be repeated
+    #[clap(long, value_name = "CHANNEL")]
+    mute_channel: Vec<String>,
+
+    /// Only show broadcasts the server tagged with this tag. Can be repeated to show
+    /// broadcasts with any of the tags
+    #[clap(long, value_name = "TAG")]
+    show_tags: Vec<String>,
+
+    /// Don't show broadcasts the server tagged with this tag. Can be repeated
+    #[clap(long, value_name = "TAG")]
+    hide_tags: Vec<String>,
+
+    /// Show the tags of broadcasts after the sender's name
+    #[clap(long)]
+    show_tag_labels: bool,
+
+    /// Send up to this many chat messages that are ready at the same time as one bulk
+    /// message. Needs server support; 1 sends every message on its own
+    #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    max_batch_size: u32,
+
+    /// Stop reading input while this many messages are waiting to be sent
+    #[clap(long, default_value_t = 50, value_parser = clap::value_parser!(u32).range(1..))]
+    max_queue_depth: u32,
+
+    /// Ping the server every SECONDS instead of chatting, warning when it is slow or
+    /// unreachable
+    #[clap(long, value_name = "SECONDS")]
+    watch_server: Option<u64>,
+
+    /// Round-trip time above which --watch-server warns that the server is slow
+    #[clap(long, default_value_t = 500, requires = "watch_server")]
+    alert_threshold_ms: u64,
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct ServerEntry {
-    address: String,
-    uuid: String,
+    /// Print the raw bytes of every line sent and received to stderr
+    #[clap(long)]
+    sniff: bool,
+
+    /// Truncate received messages longer than this many bytes when saving them to the
+    /// history
+    #[clap(long, value_name = "BYTES", default_value_t = 4096, value_parser = clap::value_parser!(u32).range(1..))]
+    max_line_length: u32,
+
+    /// Don't tell the server the client's name, version, OS and locale after connecting
+    #[clap(long)]
+    no_client_info: bool,
+
+    /// Only send authentication, chat, ping and disconnect messages, refusing commands that
+    /// send anything else, such as /report or /join
+    #[clap(long)]
+    safe_mode: bool,
+
+    /// Authenticate with this UUID instead of the one linked to --server, which then doesn't
+    /// need to be in the config, for testing
+    #[clap(long, value_name = "UUID", hide = true)]
+    uuid_override: Option<String>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
 }
 
-fn config_path() -> Result<PathBuf, MineChatError> {
-    let proj_dirs = ProjectDirs::from("", "", "minechat")
-        .ok_or(MineChatError::ConfigError("Can't get config dir".into()))?;
-    let config_dir = proj_dirs.config_dir();
-    fs::create_dir_all(config_dir)?;
-    Ok(config_dir.join("servers.json"))
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormat {
+    /// Human-readable plain text
+    Text,
+    /// One JSON object per line
+    Json,
 }
 
-fn load_config() -> Result<ServerConfig, MineChatError> {
-    let path = config_path()?;
-    if !path.exists() {
-        return Ok(ServerConfig {
-            servers: Vec::new(),
-        });
-    }
-    let file = File::open(path)?;
-    Ok(serde_json::from_reader(file)?)
+#[derive(Clone, Copy, ValueEnum)]
+enum ExportFormat {
+    /// A JSON array of message objects
+    Json,
+    /// Comma-separated values with a header row
+    Csv,
 }
 
-fn save_config(config: &ServerConfig) -> Result<(), MineChatError> {
-    let path = config_path()?;
-    let file = File::create(path)?;
-    Ok(serde_json::to_writer_pretty(file, config)?)
+#[derive(Subcommand)]
+enum Command {
+    /// Chat on --server interactively (the default)
+    Shell,
+    /// List all linked servers
+    ListServers,
+    /// Check the config file for malformed or duplicate server entries
+    ValidateConfig,
+    /// Remove the entry of --server from the config file
+    Unlink {
+        /// Remove every server instead
+        #[clap(long)]
+        all: bool,
+    },
+    /// Add the servers of another config file, such as one from another machine, to the
+    /// config file
+    ImportConfig {
+        /// The config file to import
+        source: PathBuf,
+        /// Replace the servers that are already linked instead of skipping them
+        #[clap(long)]
+        overwrite: bool,
+    },
+    /// Write an example config file explaining every field
+    GenerateConfig {
+        /// Replace the config file if it already exists
+        #[clap(long)]
+        force: bool,
+    },
+    /// Print every message saved in the history database
+    ExportDb {
+        /// Output format
+        #[clap(long, value_enum, default_value_t = ExportFormat::Json)]
+        format: ExportFormat,
+    },
+    /// Measure the round-trip time to a linked server
+    Latency {
+        /// The server address (host:port) or alias
+        server: String,
+    },
+    /// Ping every linked server at once and show which are online
+    StatusAll,
+    /// Find MineChat servers on the local network
+    Discover,
+    /// Show when the client connected, disconnected, failed to authenticate and reconnected,
+    /// to --server if given
+    Events {
+        /// Only show events from this time on, such as 2026-10-14 or 2026-10-14T12:00:00Z
+        #[clap(long, value_name = "TIME", value_parser = eventlog::parse_since)]
+        since: Option<DateTime<Utc>>,
+    },
+    /// Send each line appended to a file, such as a log, to --server as a chat message
+    Watch {
+        /// The file to follow
+        #[clap(long, value_name = "PATH")]
+        file: PathBuf,
+        /// Text to put before each line
+        #[clap(long, value_name = "TEXT")]
+        prefix: Option<String>,
+        /// Send at most this many lines per second, delaying the rest
+        #[clap(long, value_name = "LINES_PER_SECOND", value_parser = clap::value_parser!(u32).range(1..))]
+        rate_limit: Option<u32>,
+    },
 }
 
-async fn set_link(server_addr: &str, code: &str) -> Result<(), MineChatError> {
-    let (client_uuid, _link_code) = packets::link_with_server(server_addr, code).await?;
+async fn set_link(
+    server: &str,
+    code: &str,
+    alias: Option<&str>,
+    qr: bool,
+    no_retry: bool,
+    priority: Option<u8>,
+    socket_options: &SocketOptions,
+) -> Result<(), MineChatError> {
+    let mut config = load_config()?;
+    let server_addr = config
+        .find(server)
+        .map_or(server, |e| e.address.as_str())
+        .to_string();
+    if net::parse_address(&server_addr).is_none() {
+        return Err(MineChatError::ConfigError(format!(
+            "Invalid server address {}, expected host:port or [ipv6]:port",
+            server_addr
+        )));
+    }
+
+    if qr {
+        println!("{}", link::qr_code(code)?);
+    }
+    let client_uuid = link::link(&server_addr, code, socket_options).await?;
 
     info!("Linked successfully");
-    let mut config = load_config()?;
-    config.servers.retain(|e| e.address != server_addr);
-    config.servers.push(ServerEntry {
-        address: server_addr.to_string(),
+    config.set_link(ServerEntry {
+        address: server_addr,
         uuid: client_uuid,
+        alias: alias.map(str::to_string),
+        timezone: None,
+        retry_on_disconnect: no_retry.then_some(false),
+        priority,
     });
     save_config(&config)?;
     Ok(())
 }
 
-async fn handle_connect(server_addr: &str) -> Result<(), MineChatError> {
-    let config = load_config()?;
-    let entry = config
-        .servers
-        .iter()
-        .find(|e| e.address == server_addr)
-        .ok_or(MineChatError::ServerNotLinked)?;
-
-    let mut stream = TcpStream::connect(server_addr).await?;
-    let (reader, mut writer) = stream.split();
-    let mut reader = BufReader::new(reader);
-
-    send_message(
-        &mut writer,
-        &MineChatMessage::Auth {
-            payload: AuthPayload {
-                client_uuid: entry.uuid.clone(),
-                link_code: String::new(),
-            },
+/// Removes `server`, or every server with `all`, from the user's config.
+fn unlink(server: Option<&str>, all: bool) -> Result<(), MineChatError> {
+    match (server, all) {
+        (None, true) => {
+            let count = config::unlink_all()?;
+            println!(
+                "Unlinked {} server{}",
+                count,
+                if count == 1 { "" } else { "s" }
+            );
+        }
+        (Some(server), false) => {
+            let entry = config::unlink(server)?;
+            println!("Unlinked {}", entry.address);
+        }
+        _ => {
+            return Err(MineChatError::ConfigError(
+                "unlink needs either a server, given with --server, or --all".into(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Imports the servers of the config at `source` into the user's config.
+fn import_config(source: &Path, overwrite: bool) -> Result<(), MineChatError> {
+    let summary = config::import(source, overwrite)?;
+    let plural = |count: usize| if count == 1 { "" } else { "s" };
+    if overwrite {
+        println!(
+            "Imported {} new server{}, replaced {} existing",
+            summary.added,
+            plural(summary.added),
+            summary.replaced
+        );
+    } else {
+        println!(
+            "Imported {} new server{}, skipped {} existing",
+            summary.added,
+            plural(summary.added),
+            summary.skipped
+        );
+    }
+    Ok(())
+}
+
+fn validate_config(system_config: Option<&Path>) -> Result<(), MineChatError> {
+    let problems = load_merged_config(system_config)?.validate();
+    if problems.is_empty() {
+        match system_config {
+            Some(path) => println!(
+                "{} and {} are valid",
+                path.display(),
+                config_path()?.display()
+            ),
+            None => println!("{} is valid", config_path()?.display()),
+        }
+        return Ok(());
+    }
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    Err(MineChatError::ConfigError(format!(
+        "Found {} problem(s) in the config file",
+        problems.len()
+    )))
+}
+
+async fn export_db(format: ExportFormat) -> Result<(), MineChatError> {
+    // Nothing is saved while exporting, so no limit is needed.
+    let messages = HistoryDb::open(&config::history_path()?, usize::MAX)
+        .await?
+        .all()
+        .await?;
+    let mut out = io::stdout().lock();
+    match format {
+        ExportFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, &messages)?;
+            writeln!(out)?;
+        }
+        ExportFormat::Csv => history::write_csv(&mut out, &messages)?,
+    }
+    Ok(())
+}
+
+fn list_servers(system_config: Option<&Path>) -> Result<(), MineChatError> {
+    let config = load_merged_config(system_config)?;
+    if config.servers.is_empty() {
+        println!("No linked servers");
+    }
+    for entry in config.by_priority() {
+        let name = match &entry.alias {
+            Some(alias) => format!("{} ({})", alias, entry.address),
+            None => entry.address.clone(),
+        };
+        match entry.priority {
+            Some(priority) => println!("{}, priority {}", name, priority),
+            None => println!("{}", name),
+        }
+    }
+    Ok(())
+}
+
+/// Finds the entry of `server` in `config`, or with `uuid_override` returns one for
+/// `server` with that UUID without looking.
+fn find_entry(
+    config: &ServerConfig,
+    server: &str,
+    uuid_override: Option<&str>,
+) -> Result<ServerEntry, MineChatError> {
+    match uuid_override {
+        Some(uuid) => Ok(ServerEntry {
+            address: server.to_string(),
+            uuid: uuid.to_string(),
+            alias: None,
+            timezone: None,
+            retry_on_disconnect: None,
+            priority: None,
+        }),
+        None => config
+            .find(server)
+            .cloned()
+            .ok_or(MineChatError::ServerNotLinked),
+    }
+}
+
+fn socket_options(args: &Args) -> SocketOptions {
+    SocketOptions {
+        keepalive_secs: args.tcp_keepalive,
+        nodelay: args.tcp_nodelay,
+        read_buffer_size: args.read_buffer_size as usize,
+        write_buffer_size: args.write_buffer_size as usize,
+        transport: if args.ws {
+            Transport::WebSocket { tls: args.tls }
+        } else {
+            Transport::Tcp
+        },
+    }
+}
+
+/// Connects to `entry`, authenticates and measures how long the server takes to answer a
+/// ping.
+async fn ping(
+    entry: &ServerEntry,
+    socket_options: &SocketOptions,
+) -> Result<Duration, MineChatError> {
+    let mut session = Session::new(entry.address.clone());
+    let (mut reader, mut writer) =
+        open_session(entry, socket_options, None, false, false, &mut session).await?;
+
+    let nonce = rand::random();
+    let start = Instant::now();
+    let msg = Extension::Ping {
+        payload: PingPayload { nonce },
+    };
+    protocol::send(&mut writer, &msg).await?;
+    writer.flush().await?;
+
+    let wait_for_pong = async {
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if reader.read_until(b'\n', &mut line).await? == 0 {
+                return Err(MineChatError::Disconnected);
+            }
+            if let Ok(Message::Extension(Extension::Pong { payload })) =
+                serde_json::from_slice(&line)
+                && payload.nonce == nonce
+            {
+                return Ok(start.elapsed());
+            }
+        }
+    };
+    let rtt = time::timeout(PING_TIMEOUT, wait_for_pong)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "No pong received"))??;
+
+    let msg = MineChatMessage::Disconnect {
+        payload: DisconnectPayload {
+            reason: "Client exit".into(),
         },
+    };
+    protocol::send(&mut writer, &msg).await?;
+    writer.flush().await?;
+    Ok(rtt)
+}
+
+async fn latency(server: &str, args: &Args) -> Result<(), MineChatError> {
+    let config = load_merged_config(args.system_config.as_deref())?;
+    let entry = find_entry(&config, server, args.uuid_override.as_deref())?;
+    let rtt = ping(&entry, &socket_options(args)).await?;
+    println!("{}: {} ms", entry.address, rtt.as_millis());
+    Ok(())
+}
+
+/// Pings every linked server concurrently and prints a table of which ones answered and how
+/// quickly, in order of priority.
+async fn status_all(args: &Args) -> Result<(), MineChatError> {
+    let config = load_merged_config(args.system_config.as_deref())?;
+    let socket_options = socket_options(args);
+    // The pings are started in this order, so higher priority servers are connected to first.
+    let servers = config.by_priority();
+    let checks = servers.iter().map(|entry| async {
+        match time::timeout(STATUS_TIMEOUT, ping(entry, &socket_options)).await {
+            Ok(Ok(rtt)) => Some(rtt),
+            Ok(Err(e)) => {
+                debug!("{} is unreachable: {}", entry.address, e);
+                None
+            }
+            Err(_) => {
+                debug!("{} didn't answer in time", entry.address);
+                None
+            }
+        }
+    });
+    let results = join_all(checks).await;
+
+    let rows: Vec<Vec<String>> = servers
+        .iter()
+        .zip(results)
+        .map(|(entry, rtt)| {
+            vec![
+                entry.address.clone(),
+                entry.alias.clone().unwrap_or_default(),
+                entry.uuid.clone(),
+                if rtt.is_some() { "online" } else { "offline" }.to_string(),
+                rtt.map_or(String::new(), |rtt| rtt.as_millis().to_string()),
+            ]
+        })
+        .collect();
+    let aligns = [
+        Align::Left,
+        Align::Left,
+        Align::Left,
+        Align::Left,
+        Align::Right,
+    ];
+    let headers = ["ADDRESS", "ALIAS", "LINKED_UUID", "STATUS", "LATENCY_MS"];
+    print!("{}", table::render_aligned(&headers, &aligns, &rows));
+    Ok(())
+}
+
+/// Asks the servers on the local network to answer and shows those that do.
+async fn discover_servers() -> Result<(), MineChatError> {
+    let servers =
+        discover::discover(discover::DISCOVERY_ADDR.into(), discover::DISCOVERY_TIMEOUT).await?;
+    if servers.is_empty() {
+        println!("No servers found on the local network");
+        return Ok(());
+    }
+    let rows: Vec<Vec<String>> = servers
+        .into_iter()
+        .map(|server| vec![server.address, server.name, server.player_count.to_string()])
+        .collect();
+    let aligns = [Align::Left, Align::Left, Align::Right];
+    print!(
+        "{}",
+        table::render_aligned(&["ADDRESS", "NAME", "PLAYERS"], &aligns, &rows)
+    );
+    Ok(())
+}
+
+/// Pings the server every `interval` seconds until interrupted, warning when the round trip
+/// takes longer than `--alert-threshold-ms` or the server can't be reached.
+async fn watch_server(server: &str, interval: u64, args: &Args) -> Result<(), MineChatError> {
+    let config = load_merged_config(args.system_config.as_deref())?;
+    let entry = find_entry(&config, server, args.uuid_override.as_deref())?;
+    let socket_options = socket_options(args);
+    let threshold = Duration::from_millis(args.alert_threshold_ms);
+
+    let watch = async {
+        let mut ticker = time::interval(Duration::from_secs(interval.max(1)));
+        // A ping can take longer than the interval; don't follow a slow one with a burst.
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            match ping(&entry, &socket_options).await {
+                Ok(rtt) if rtt > threshold => warn!(
+                    "{} is slow: {} ms (threshold {} ms)",
+                    entry.address,
+                    rtt.as_millis(),
+                    threshold.as_millis()
+                ),
+                Ok(rtt) => info!("{} is up: {} ms", entry.address, rtt.as_millis()),
+                Err(e) => warn!("{} is unreachable: {}", entry.address, e),
+            }
+        }
+    };
+
+    tokio::select! {
+        () = watch => Ok(()),
+        result = shutdown::signal() => Ok(result?),
+    }
+}
+
+/// Prints the lines of the event log about `server`, or every server, from `since` on.
+fn show_events(
+    server: Option<&str>,
+    since: Option<DateTime<Utc>>,
+    args: &Args,
+) -> Result<(), MineChatError> {
+    let config = load_merged_config(args.system_config.as_deref())?;
+    // The log has addresses, which an alias stands for.
+    let address = server.map(|server| config.find(server).map_or(server, |e| e.address.as_str()));
+    for line in eventlog::read(&config::events_log_path()?, address, since)? {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+/// Sends the lines appended to `file` to `server` as chat messages until interrupted or
+/// disconnected by the server.
+async fn watch_file(
+    server: &str,
+    file: &Path,
+    prefix: Option<&str>,
+    rate_limit: Option<u32>,
+    args: &Args,
+) -> Result<(), MineChatError> {
+    let config = load_merged_config(args.system_config.as_deref())?;
+    let entry = find_entry(&config, server, args.uuid_override.as_deref())?;
+    let mut tail = tail::LogTail::open(file).await?;
+    let mut session = Session::new(entry.address.clone());
+    let (reader, writer) = open_session(
+        &entry,
+        &socket_options(args),
+        None,
+        args.sniff,
+        !args.no_client_info && !args.safe_mode,
+        &mut session,
     )
     .await?;
+    let mut queue = MessageQueue::spawn(reader, writer, 1, args.max_queue_depth as usize);
+    let mut ticker = rate_limit.map(|lines| {
+        let mut ticker = time::interval(Duration::from_secs(1) / lines);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        ticker
+    });
+    info!("Sending lines written to {}", file.display());
+
+    let chat = |line: String| Extension::Chat {
+        payload: ChatPayload {
+            message: match prefix {
+                Some(prefix) => format!("{} {}", prefix, line),
+                None => line,
+            },
+            message_id: None,
+            signature: None,
+            reply_to: None,
+        },
+    };
+    // A line read while rate limited, waiting for its turn. Waiting for it in a branch of
+    // its own keeps the others, such as Ctrl-C and the server disconnecting, handled.
+    let mut waiting: Option<String> = None;
+
+    let result = loop {
+        tokio::select! {
+            line = tail.next_line(), if waiting.is_none() => {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match ticker {
+                    Some(_) => waiting = Some(line),
+                    None => queue.send(chat(line)).await?,
+                }
+            }
+            _ = async { ticker.as_mut().expect("only waiting when rate limited").tick().await },
+                if waiting.is_some() =>
+            {
+                if let Some(line) = waiting.take() {
+                    queue.send(chat(line)).await?;
+                }
+            }
+            // Received messages are only read so the server never waits on a full buffer.
+            msg = queue.recv() => match msg {
+                Some(Message::Core(MineChatMessage::Disconnect { payload })) => {
+                    warn!("Disconnected: {}", payload.reason);
+                    break Ok(());
+                }
+                Some(msg) => debug!("Ignoring message while watching: {}", msg),
+                None => break Err(MineChatError::Disconnected),
+            },
+            result = shutdown::signal() => {
+                result?;
+                queue.send(repl::disconnect_message()).await?;
+                break Ok(());
+            }
+        }
+    };
+    let closed = queue.close().await;
+    result.and(closed)
+}
+
+/// Connects to `entry` and authenticates, either by resuming a session with `token` or by
+/// the client UUID, asking for the messages missed since the session's last checkpoint.
+async fn open_session(
+    entry: &ServerEntry,
+    socket_options: &SocketOptions,
+    token: Option<&str>,
+    sniff: bool,
+    client_info: bool,
+    session: &mut Session,
+) -> Result<(Reader, Writer), MineChatError> {
+    let stream = net::connect(&entry.address, socket_options).await?;
+    session.socket = Some(SockRef::from(&stream).try_clone()?);
+    let (reader, writer): (
+        Box<dyn AsyncRead + Unpin + Send>,
+        Box<dyn AsyncWrite + Unpin + Send>,
+    ) = match socket_options.transport {
+        Transport::Tcp => {
+            let (reader, writer) = stream.into_split();
+            (Box::new(reader), Box::new(writer))
+        }
+        Transport::WebSocket { tls } => {
+            let frames = ws::handshake(stream, &entry.address, tls).await?;
+            let (reader, writer) = tokio::io::split(frames);
+            (Box::new(reader), Box::new(writer))
+        }
+    };
+    debug!(
+        "Read buffer {} bytes, write buffer {} bytes",
+        socket_options.read_buffer_size, socket_options.write_buffer_size
+    );
+    let writer = BufWriter::with_capacity(socket_options.write_buffer_size, writer);
+    let (mut reader, mut writer): (Reader, Writer) = if sniff {
+        (
+            Box::new(SniffingReader::with_capacity(
+                socket_options.read_buffer_size,
+                reader,
+            )),
+            Box::new(SniffingWriter::new(writer)),
+        )
+    } else {
+        (
+            Box::new(BufReader::with_capacity(
+                socket_options.read_buffer_size,
+                reader,
+            )),
+            Box::new(writer),
+        )
+    };
+
+    match (token, &session.checkpoint) {
+        (None, None) => {
+            let msg = MineChatMessage::Auth {
+                payload: AuthPayload {
+                    client_uuid: entry.uuid.clone(),
+                    link_code: String::new(),
+                },
+            };
+            protocol::send(&mut writer, &msg).await?;
+        }
+        (token, checkpoint) => {
+            let msg = Extension::Auth {
+                payload: SessionAuthPayload {
+                    client_uuid: entry.uuid.clone(),
+                    link_code: String::new(),
+                    session_token: token.map(str::to_string),
+                    resume_from_checkpoint: checkpoint.clone(),
+                },
+            };
+            protocol::send(&mut writer, &msg).await?;
+        }
+    }
+    writer.flush().await?;
 
     match receive_message(&mut reader).await? {
         MineChatMessage::AuthAck { payload } => {
             if payload.status == "success" {
                 info!("Connected: {}", payload.message);
-                // Pass the split reader and writer to repl
-                let (reader, writer) = stream.into_split();
-                repl(BufReader::new(reader), writer).await
+                if client_info {
+                    send_client_info(&mut writer).await?;
+                }
+                Ok((reader, writer))
             } else {
                 Err(MineChatError::AuthFailed(payload.message))
             }
@@ -127,88 +1024,457 @@ async fn handle_connect(server_addr: &str) -> Result<(), MineChatError> {
     }
 }
 
-async fn repl<R, W>(mut reader: R, mut writer: W) -> Result<(), MineChatError>
+/// Tells the server which client and version is connecting.
+async fn send_client_info(writer: &mut Writer) -> Result<(), MineChatError> {
+    let msg = Extension::ClientInfo {
+        payload: ClientInfoPayload {
+            client_name: env!("CARGO_PKG_NAME").to_string(),
+            client_version: env!("CARGO_PKG_VERSION").to_string(),
+            os: std::env::consts::OS.to_string(),
+            locale: sys_locale::get_locale().unwrap_or_else(|| "en-US".to_string()),
+        },
+    };
+    protocol::send(writer, &msg).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+async fn handle_connect<I, O>(
+    socket_options: &SocketOptions,
+    sniff: bool,
+    client_info: bool,
+    options: &ReplOptions,
+    input: &mut I,
+    out: &mut O,
+    session: &mut Session,
+) -> Result<ReplExit, MineChatError>
 where
-    R: AsyncBufRead + Unpin,
-    W: AsyncWrite + Unpin,
+    I: AsyncBufRead + Unpin,
+    O: Write,
 {
-    let mut stdin = BufReader::new(tokio::io::stdin());
-    let mut buffer = String::new();
-    let mut msg_buffer = String::new();
+    let config = load_merged_config(session.system_config.as_deref())?;
+    let entry = &find_entry(&config, &session.address, session.uuid_override.as_deref())?;
 
-    loop {
-        tokio::select! {
-            result = reader.read_line(&mut msg_buffer) => {
-                match result {
-                    Ok(0) => return Ok(()),
-                    Ok(_) => {
-                        if let Ok(msg) = serde_json::from_str::<MineChatMessage>(&msg_buffer) {
-                            match msg {
-                                MineChatMessage::Broadcast { payload } => {
-                                    println!("[{}] {}", payload.from, payload.message);
-                                }
-                                MineChatMessage::Disconnect { payload } => {
-                                    println!("Disconnected: {}", payload.reason);
-                                    return Ok(());
-                                }
-                                _ => debug!("Received message: {:?}", msg),
-                            }
-                        }
-                        msg_buffer.clear();
-                    }
-                    Err(e) => return Err(e.into()),
+    let token = session.valid_token().map(str::to_string);
+    let opened = match token {
+        Some(token) => {
+            match open_session(
+                entry,
+                socket_options,
+                Some(&token),
+                sniff,
+                client_info,
+                session,
+            )
+            .await
+            {
+                Err(MineChatError::AuthFailed(reason)) => {
+                    warn!(
+                        "Session token rejected ({}), authenticating by UUID",
+                        reason
+                    );
+                    session.token = None;
+                    open_session(entry, socket_options, None, sniff, client_info, session).await
                 }
+                result => result,
+            }
+        }
+        None => open_session(entry, socket_options, None, sniff, client_info, session).await,
+    };
+    let (reader, writer) = match opened {
+        Ok(streams) => streams,
+        Err(e) => {
+            if let MineChatError::AuthFailed(reason) = &e {
+                session.log_event(ConnectionEvent::AuthFailed(reason));
             }
-            result = stdin.read_line(&mut buffer) => {
-                let n = result?;
-                if n == 0 {
-                    send_message(&mut writer, &MineChatMessage::Disconnect {
-                        payload: DisconnectPayload { reason: "Client exit".into() }
-                    }).await?;
-                    return Ok(());
+            return Err(e);
+        }
+    };
+    session.log_event(ConnectionEvent::Connected);
+    // Senders are counted per connection, so reconnecting starts the counts over.
+    session.stats.senders.reset();
+    // Heartbeats are timed per connection too, so the outage isn't taken for an interval.
+    session.last_heartbeat = None;
+    session.heartbeat_interval = None;
+
+    let result = match &session.irc_gateway {
+        Some(listener) => irc::serve(listener, reader, writer, options.safe_mode).await,
+        None => repl(reader, writer, input, out, options, session).await,
+    };
+    let reason = match &result {
+        Ok(exit) => exit.to_string(),
+        Err(e) => e.to_string(),
+    };
+    session.log_event(ConnectionEvent::Disconnected(&reason));
+    result
+}
+
+/// Counts down until the server is expected back, then waits a random extra delay of up
+/// to [`RESTART_JITTER`].
+async fn wait_for_restart(restart_in: Duration) -> Result<(), MineChatError> {
+    let mut stdout = std::io::stdout();
+    for remaining in (1..=restart_in.as_secs()).rev() {
+        write!(stdout, "\rReconnecting in {}s ", remaining)?;
+        stdout.flush()?;
+        time::sleep(Duration::from_secs(1)).await;
+    }
+    writeln!(stdout)?;
+
+    let jitter = Duration::from_millis(rand::random_range(0..=RESTART_JITTER.as_millis() as u64));
+    debug!("Waiting an extra {:?} before reconnecting", jitter);
+    time::sleep(jitter).await;
+    Ok(())
+}
+
+/// Runs `hook` through the platform shell, failing if it exits unsuccessfully.
+async fn run_auth_refresh_hook(hook: &str) -> Result<(), MineChatError> {
+    let mut command = if cfg!(windows) {
+        let mut command = process::Command::new("cmd");
+        command.arg("/C");
+        command
+    } else {
+        let mut command = process::Command::new("sh");
+        command.arg("-c");
+        command
+    };
+
+    debug!("Running auth refresh hook: {}", hook);
+    let status = command.arg(hook).status().await?;
+    if !status.success() {
+        return Err(MineChatError::ConfigError(format!(
+            "Auth refresh hook exited with {}",
+            status
+        )));
+    }
+    Ok(())
+}
+
+async fn connect(server: &str, args: &Args) -> Result<(), MineChatError> {
+    let config = load_merged_config(args.system_config.as_deref())?;
+    let entry = find_entry(&config, server, args.uuid_override.as_deref())?;
+    // Resolve aliases once, so the session keeps working if the server is renamed.
+    let address = entry.address.clone();
+    let timezone = match (args.timezone, &entry.timezone) {
+        (Some(timezone), saved) => {
+            if saved.as_deref() != Some(timezone.name()) {
+                // Servers only in the system config or reached with --uuid-override have
+                // no entry of the user's to save it in.
+                match config::set_timezone(&address, timezone.name()) {
+                    Err(MineChatError::ServerNotLinked) => warn!(
+                        "Not saving the time zone of {}, since it isn't in your config",
+                        address
+                    ),
+                    result => result?,
                 }
-                let input = buffer.trim().to_string();
-                if input == "/exit" {
-                    send_message(&mut writer, &MineChatMessage::Disconnect {
-                        payload: DisconnectPayload { reason: "Client exit".into() }
-                    }).await?;
-                    return Ok(());
+            }
+            timezone
+        }
+        (None, Some(saved)) => {
+            timestamp::parse_timezone(saved).map_err(MineChatError::ConfigError)?
+        }
+        (None, None) => Tz::UTC,
+    };
+    let reconnect_attempts = match entry.retry_on_disconnect {
+        Some(false) => 0,
+        Some(true) if args.reconnect_attempts == 0 => DEFAULT_RECONNECT_ATTEMPTS,
+        _ => args.reconnect_attempts,
+    };
+    let socket_options = socket_options(args);
+    // Asking the terminal only works before the line editor takes over stdin.
+    let color_scheme = match args.color_scheme {
+        ColorScheme::Auto if !std::io::stdout().is_terminal() => ColorScheme::Dark,
+        scheme => scheme.detect(),
+    };
+    let mut options = ReplOptions {
+        expand_emoji: !args.no_emoji_expand,
+        chat_prefix: args.chat_prefix.clone(),
+        normalize_input: !args.no_normalize_input,
+        multiline_strategy: args.multiline_strategy,
+        word_count: args.word_count,
+        away_reply: args.away_reply.clone(),
+        away_reply_cooldown: Duration::from_secs(args.away_reply_cooldown),
+        strip_formatting: args.strip_formatting,
+        signer: args
+            .sign_messages
+            .as_deref()
+            .map(MessageSigner::load)
+            .transpose()?,
+        verifier: args
+            .verify_messages
+            .as_deref()
+            .map(MessageVerifier::load)
+            .transpose()?,
+        theme: config.theme.for_scheme(color_scheme),
+        show_delivery: args.show_delivery,
+        max_batch_size: args.max_batch_size as usize,
+        max_queue_depth: args.max_queue_depth as usize,
+        prompt: None,
+        maxlag_warn: args.maxlag_warn.map(Duration::from_millis),
+        maxlag_disconnect: args.maxlag_disconnect.map(Duration::from_millis),
+        input_encoding: args.input_encoding,
+        show_tags: args.show_tags.clone(),
+        hide_tags: args.hide_tags.clone(),
+        show_tag_labels: args.show_tag_labels,
+        // Without reconnecting, a quiet server would only end the session.
+        server_timeout: Some(args.server_timeout)
+            .filter(|&secs| secs > 0 && reconnect_attempts > 0)
+            .map(Duration::from_secs),
+        max_message_age: Some(args.max_message_age)
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs),
+        timezone,
+        time_format: args.time_format.clone(),
+        scoreboard: if args.no_scoreboard {
+            ScoreboardMode::Off
+        } else if std::io::stdout().is_terminal() {
+            ScoreboardMode::Overlay
+        } else {
+            ScoreboardMode::Inline
+        },
+        show_greeting: !args.no_greeting,
+        pager: !args.no_pager && std::io::stdout().is_terminal(),
+        safe_mode: args.safe_mode,
+        sender_colors: args
+            .color_from
+            .map(|from| SenderColors::new(from, color_scheme, config.colors.clone())),
+    };
+
+    // Line editing only makes sense when a person is typing; piped input is read as is.
+    let (mut input, mut out): (Box<dyn AsyncBufRead + Unpin>, Box<dyn Write>) =
+        if std::io::stdin().is_terminal() && args.input_encoding.is_none() {
+            let context = PromptContext::new(server);
+            options.prompt = Some(context.clone());
+            let draft_path = config::draft_path(&address)?;
+            let (input, out) = shell::spawn(args.prompt.clone(), context, draft_path)?;
+            (Box::new(input), Box::new(out))
+        } else {
+            (
+                Box::new(BufReader::new(tokio::io::stdin())),
+                Box::new(std::io::stdout()),
+            )
+        };
+    let mut session = Session::new(address);
+    tokio::spawn(events::log_events(session.events.subscribe()));
+    session.muted = args.mute;
+    session.system_config = args.system_config.clone();
+    session.uuid_override = args.uuid_override.clone();
+    session.pipe_in = args.pipe_in.as_deref().map(PipeIn::create).transpose()?;
+    session.tee_socket = args
+        .tee_socket
+        .as_deref()
+        .map(|path| TeeSocket::bind(path, Arc::clone(&session.events)))
+        .transpose()?;
+    if let Some(port) = args.irc_gateway {
+        let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+        info!("IRC gateway listening on {}", listener.local_addr()?);
+        session.irc_gateway = Some(listener);
+    }
+    session.muted_channels = args
+        .mute_channel
+        .iter()
+        .filter_map(|channel| commands::channel_name(channel))
+        .collect();
+    let history_path = config::history_path()?;
+    let history_db = HistoryDb::open(&history_path, args.max_line_length as usize);
+    session.history_db = match history_db.await {
+        Ok(db) => Some(db),
+        Err(e) => {
+            warn!(
+                "Can't open the message history, keeping it for this session only: {}",
+                e
+            );
+            None
+        }
+    };
+    session.spam_guard = SpamGuard::new(Duration::from_secs(args.anti_spam_penalty));
+    // The log is an audit trail, but not worth refusing to connect over.
+    session.event_log = match EventLog::open(&config::events_log_path()?) {
+        Ok(log) => Some(log),
+        Err(e) => {
+            warn!("Can't open the event log: {}", e);
+            None
+        }
+    };
+
+    let mut auth_attempt = 0;
+    let mut reconnect_attempt = 0;
+    loop {
+        let result = handle_connect(
+            &socket_options,
+            args.sniff,
+            !args.no_client_info && !args.safe_mode,
+            &options,
+            &mut input,
+            &mut out,
+            &mut session,
+        )
+        .await;
+        // The retry budgets are per outage, so a session that got going starts them over.
+        match result {
+            Ok(ReplExit::ConnectionLost) | Ok(ReplExit::Unresponsive) => {
+                reconnect_attempt = 0;
+                auth_attempt = 0;
+            }
+            Ok(ReplExit::Lagging) => {
+                warn!("Server lag above --maxlag-disconnect");
+                reconnect_attempt = 0;
+                auth_attempt = 0;
+            }
+            Ok(ReplExit::Shutdown {
+                restart_in: Some(restart_in),
+            }) if reconnect_attempts > 0 => {
+                tokio::select! {
+                    result = wait_for_restart(restart_in) => result?,
+                    result = shutdown::signal() => return Ok(result?),
                 }
-                send_message(&mut writer, &MineChatMessage::Chat {
-                    payload: ChatPayload { message: input }
-                }).await?;
-                buffer.clear();
+                reconnect_attempt = 0;
+                auth_attempt = 0;
+                continue;
             }
-            _ = signal::ctrl_c() => {
-                send_message(&mut writer, &MineChatMessage::Disconnect {
-                    payload: DisconnectPayload { reason: "Client exit".into() }
-                }).await?;
-                return Ok(());
+            Err(MineChatError::Io(e)) if reconnect_attempt < reconnect_attempts => {
+                warn!("Connection error: {}", e);
             }
+            Err(MineChatError::AuthFailed(reason))
+                if args.reconnect_on_auth_failure && auth_attempt < args.auth_retry_attempts =>
+            {
+                auth_attempt += 1;
+                warn!(
+                    "Authentication failed: {} (retry {}/{})",
+                    reason, auth_attempt, args.auth_retry_attempts
+                );
+                if let Some(hook) = &args.auth_refresh_hook {
+                    run_auth_refresh_hook(hook).await?;
+                }
+                continue;
+            }
+            result => return result.map(|_| ()),
+        }
+
+        if reconnect_attempt >= reconnect_attempts {
+            return Ok(());
+        }
+        reconnect_attempt += 1;
+        session.log_event(ConnectionEvent::ReconnectAttempt(reconnect_attempt));
+        warn!(
+            "Reconnecting in {}s ({}/{})",
+            RECONNECT_DELAY.as_secs(),
+            reconnect_attempt,
+            reconnect_attempts
+        );
+        tokio::select! {
+            () = time::sleep(RECONNECT_DELAY) => {}
+            result = shutdown::signal() => return Ok(result?),
         }
     }
 }
 
-fn init_logger(verbose: bool) {
-    let mut builder = Builder::from_default_env();
-    builder.target(Target::Stdout);
-    builder.filter_level(if verbose {
+fn init_logger(verbose: bool, format: LogFormat, filter: Option<&str>) {
+    let level = if verbose {
         log::LevelFilter::Debug
     } else {
         log::LevelFilter::Info
-    });
-    builder.init();
+    };
+
+    match format {
+        LogFormat::Text => {
+            let mut builder = Builder::from_default_env();
+            builder.target(Target::Stdout);
+            match filter {
+                Some(filter) => builder.parse_filters(filter),
+                None => builder.filter_level(level),
+            };
+            builder.init();
+        }
+        LogFormat::Json => {
+            let filter = match filter {
+                Some(filter) => EnvFilter::new(filter),
+                None => EnvFilter::new(level.as_str()),
+            };
+            tracing_subscriber::fmt()
+                .json()
+                .flatten_event(true)
+                .with_env_filter(filter)
+                .with_writer(std::io::stdout)
+                .init();
+        }
+    }
+
+    if verbose && filter.is_some() {
+        warn!("--log-level-filter takes precedence over --verbose");
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
-    init_logger(args.verbose);
+    init_logger(
+        args.verbose,
+        args.log_format,
+        args.log_level_filter.as_deref(),
+    );
 
-    if let Some(code) = args.link {
-        set_link(&args.server, &code).await
-    } else {
-        handle_connect(&args.server).await
+    if let Some(uuid) = &args.uuid_override {
+        warn!("Authenticating with the overriding UUID {}", uuid);
+    }
+
+    let _pid_file = args
+        .write_pid
+        .as_deref()
+        .map(PidFile::create)
+        .transpose()
+        .map_err(|e| miette::Report::new(MineChatError::Io(e)))?;
+
+    match &args.command {
+        Some(Command::ListServers) => list_servers(args.system_config.as_deref()),
+        Some(Command::ValidateConfig) => validate_config(args.system_config.as_deref()),
+        Some(Command::Unlink { all }) => unlink(args.server.as_deref(), *all),
+        Some(Command::ImportConfig { source, overwrite }) => import_config(source, *overwrite),
+        Some(Command::GenerateConfig { force }) => config::generate_config(*force).map(|path| {
+            println!("Wrote an example config to {}", path.display());
+        }),
+        Some(Command::ExportDb { format }) => export_db(*format).await,
+        Some(Command::Shell) => match args.server.as_deref() {
+            Some(server) => connect(server, &args).await,
+            None => Err(MineChatError::ConfigError(
+                "shell needs a server, given with --server".into(),
+            )),
+        },
+        Some(Command::Latency { server }) => latency(server, &args).await,
+        Some(Command::StatusAll) => status_all(&args).await,
+        Some(Command::Discover) => discover_servers().await,
+        Some(Command::Events { since }) => show_events(args.server.as_deref(), *since, &args),
+        Some(Command::Watch {
+            file,
+            prefix,
+            rate_limit,
+        }) => match args.server.as_deref() {
+            Some(server) => watch_file(server, file, prefix.as_deref(), *rate_limit, &args).await,
+            None => Err(MineChatError::ConfigError(
+                "watch needs a server, given with --server".into(),
+            )),
+        },
+        None => {
+            let server = args
+                .server
+                .as_deref()
+                .expect("--server is required without a subcommand");
+            if let Some(code) = &args.link {
+                set_link(
+                    server,
+                    code,
+                    args.server_alias.as_deref(),
+                    args.qr,
+                    args.no_retry,
+                    args.priority,
+                    &socket_options(&args),
+                )
+                .await
+            } else if let Some(interval) = args.watch_server {
+                watch_server(server, interval, &args).await
+            } else {
+                connect(server, &args).await
+            }
+        }
     }
     .map_err(miette::Report::new)?;
 
@@ -0,0 +1,142 @@
+//! Code in received messages, written as in Markdown: `inline` spans between backticks and
+//! blocks fenced by triple backticks.
+
+use crate::theme::{self, Theme};
+
+/// The fence around a code block.
+const FENCE: &str = "```";
+
+/// Renders the code in `text` in the theme's code colors. Each code block starts on a line
+/// of its own under a `[code]` label and keeps its whitespace, with an optional language
+/// name after the opening fence left out. Unclosed backticks are shown as they are.
+pub fn render_code(text: &str, theme: &Theme) -> String {
+    let mut parts: Vec<&str> = text.split(FENCE).collect();
+    // An even number of parts means the last fence is unclosed.
+    let unclosed = parts.len() % 2 == 0;
+    let last = if unclosed { parts.pop() } else { None };
+
+    let mut out = String::new();
+    let mut after_block = false;
+    for (i, part) in parts.iter().enumerate() {
+        let mut part = *part;
+        if i % 2 == 1 {
+            render_block(&mut out, part, theme);
+            after_block = true;
+            continue;
+        }
+        if after_block {
+            part = part.strip_prefix('\n').unwrap_or(part);
+        }
+        if i + 1 == parts.len()
+            && let Some(last) = last
+        {
+            // The unclosed fence is shown as it was typed.
+            let joined = format!("{}{}{}", part, FENCE, last);
+            push_text(&mut out, &joined, after_block, theme);
+            return out;
+        }
+        push_text(&mut out, part, after_block, theme);
+    }
+    out
+}
+
+/// Appends text outside code blocks, on a new line if it follows a block.
+fn push_text(out: &mut String, text: &str, after_block: bool, theme: &Theme) {
+    if text.is_empty() {
+        return;
+    }
+    if after_block {
+        out.push('\n');
+    }
+    out.push_str(&render_inline(text, theme));
+}
+
+/// Appends a code block, padding its lines to the same width so the background color
+/// forms a rectangle.
+fn render_block(out: &mut String, code: &str, theme: &Theme) {
+    // The text on the line of the opening fence is dropped if it's blank or a language.
+    let code = match code.split_once('\n') {
+        Some((first, rest)) if first.is_empty() || is_language(first) => rest,
+        _ => code,
+    };
+    let code = code.strip_suffix('\n').unwrap_or(code);
+    if !out.is_empty() && !out.ends_with('\n') {
+        out.push('\n');
+    }
+    out.push_str(&theme::paint(theme.code_color, "[code]"));
+    let lines: Vec<&str> = code.lines().collect();
+    let width = lines.iter().map(|l| l.chars().count()).max().unwrap_or(0);
+    for line in lines {
+        let padded = match theme.code_background_color {
+            Some(_) => format!("{:width$}", line, width = width),
+            None => line.to_string(),
+        };
+        out.push('\n');
+        out.push_str(&theme::paint_background(
+            theme.code_background_color,
+            &padded,
+        ));
+    }
+}
+
+/// Whether the first line of a code block names its language, like `rust` in ```` ```rust ````.
+fn is_language(line: &str) -> bool {
+    line.chars()
+        .all(|c| c.is_ascii_alphanumeric() || "+-#_.".contains(c))
+}
+
+/// Colors the spans between single backticks, keeping the backticks.
+fn render_inline(text: &str, theme: &Theme) -> String {
+    if theme.code_color.is_none() {
+        return text.to_string();
+    }
+    let parts: Vec<&str> = text.split('`').collect();
+    let mut out = String::new();
+    for (i, part) in parts.iter().enumerate() {
+        let is_code = i % 2 == 1 && i + 1 < parts.len();
+        if is_code {
+            out.push_str(&theme::paint(theme.code_color, &format!("`{}`", part)));
+        } else {
+            if i % 2 == 1 {
+                // The last backtick has no partner.
+                out.push('`');
+            }
+            out.push_str(part);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_code_blocks_and_keeps_their_whitespace() {
+        let text = "try this:```rust\nfn main() {\n    run();\n}\n```does `run` work? ```oops";
+        assert_eq!(
+            render_code(text, &Theme::default()),
+            "try this:\n[code]\nfn main() {\n    run();\n}\ndoes `run` work? ```oops"
+        );
+    }
+
+    #[test]
+    fn colors_inline_code_and_block_backgrounds() {
+        let theme = Theme {
+            code_color: "green".parse().ok(),
+            code_background_color: "black".parse().ok(),
+            ..Theme::default()
+        };
+        let green = |text| theme::paint(theme.code_color, text).into_owned();
+        let black = |text| theme::paint_background(theme.code_background_color, text).into_owned();
+
+        assert_eq!(
+            render_code("use `ls` or `cd", &theme),
+            format!("use {} or `cd", green("`ls`"))
+        );
+        assert_eq!(
+            render_code("```\nab\nc\n```", &theme),
+            format!("{}\n{}\n{}", green("[code]"), black("ab"), black("c "))
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use log::debug;
+use socket2::{SockRef, TcpKeepalive};
+use std::{io, time::Duration};
+use tokio::net::TcpStream;
+
+/// How messages are carried over the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// Newline-delimited JSON straight over TCP.
+    Tcp,
+    /// One WebSocket text frame per message, over TLS if `tls` is set.
+    WebSocket { tls: bool },
+}
+
+/// Settings applied to the connection to the server.
+pub struct SocketOptions {
+    /// Idle time in seconds before keep-alive probes are sent. `Some(0)` disables keep-alive,
+    /// `None` leaves the system default.
+    pub keepalive_secs: Option<u64>,
+    /// Whether to send small packets immediately instead of waiting to coalesce them
+    /// (Nagle's algorithm).
+    pub nodelay: bool,
+    /// The capacity in bytes of the buffer that data from the server is read into.
+    pub read_buffer_size: usize,
+    /// The capacity in bytes of the buffer that data to the server is collected in.
+    pub write_buffer_size: usize,
+    /// The protocol messages are carried in.
+    pub transport: Transport,
+}
+
+/// Splits a server address into its host and port.
+///
+/// Accepts `host:port` and `[ipv6]:port`; the brackets are removed from the returned host.
+/// Bare IPv6 addresses are rejected because their port can't be told apart.
+pub fn parse_address(addr: &str) -> Option<(&str, u16)> {
+    let (host, port) = match addr.strip_prefix('[') {
+        Some(rest) => {
+            let (host, port) = rest.split_once("]:")?;
+            (host, port)
+        }
+        None => {
+            let (host, port) = addr.rsplit_once(':')?;
+            if host.contains(':') {
+                return None;
+            }
+            (host, port)
+        }
+    };
+    if host.is_empty() {
+        return None;
+    }
+    Some((host, port.parse().ok()?))
+}
+
+/// Connects to `addr` and applies `options` to the resulting socket.
+pub async fn connect(addr: &str, options: &SocketOptions) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect(addr).await?;
+
+    stream.set_nodelay(options.nodelay)?;
+    debug!(
+        "TCP_NODELAY {}",
+        if options.nodelay {
+            "enabled"
+        } else {
+            "disabled"
+        }
+    );
+
+    if let Some(secs) = options.keepalive_secs {
+        let socket = SockRef::from(&stream);
+        if secs == 0 {
+            socket.set_keepalive(false)?;
+            debug!("TCP keep-alive disabled");
+        } else {
+            let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(secs));
+            socket.set_tcp_keepalive(&keepalive)?;
+            debug!("TCP keep-alive enabled after {}s idle", secs);
+        }
+    }
+
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn parses_host_and_port() {
+        assert_eq!(parse_address("localhost:25575"), Some(("localhost", 25575)));
+        assert_eq!(
+            parse_address("192.168.1.100:25566"),
+            Some(("192.168.1.100", 25566))
+        );
+    }
+
+    #[test]
+    fn parses_bracketed_ipv6() {
+        assert_eq!(parse_address("[::1]:25566"), Some(("::1", 25566)));
+        assert_eq!(parse_address("[fe80::1%eth0]:1"), Some(("fe80::1%eth0", 1)));
+    }
+
+    #[test]
+    fn rejects_malformed_addresses() {
+        assert_eq!(parse_address("localhost"), None);
+        assert_eq!(parse_address("localhost:"), None);
+        assert_eq!(parse_address(":25575"), None);
+        assert_eq!(parse_address("host:99999"), None);
+        assert_eq!(parse_address("::1:25566"), None);
+        assert_eq!(parse_address("[::1]"), None);
+    }
+
+    #[tokio::test]
+    async fn connects_to_ipv6_address() {
+        let listener = TcpListener::bind("[::1]:0").await.unwrap();
+        let addr = format!("[::1]:{}", listener.local_addr().unwrap().port());
+        let options = SocketOptions {
+            keepalive_secs: None,
+            nodelay: true,
+            read_buffer_size: 8192,
+            write_buffer_size: 8192,
+            transport: Transport::Tcp,
+        };
+
+        let (stream, accepted) = tokio::join!(connect(&addr, &options), listener.accept());
+        let stream = stream.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), listener.local_addr().unwrap());
+        assert!(stream.nodelay().unwrap());
+        accepted.unwrap();
+    }
+}
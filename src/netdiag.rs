@@ -0,0 +1,87 @@
+use socket2::Socket;
+use std::{fmt::Write, io};
+
+/// Describes the state of the connection's socket for the `/netdiag` command.
+pub fn report(socket: &Socket) -> io::Result<String> {
+    let mut out = String::new();
+    let addr = |addr: socket2::SockAddr| {
+        addr.as_socket()
+            .map_or_else(|| "unknown".to_string(), |a| a.to_string())
+    };
+
+    let _ = writeln!(out, "Local address:   {}", addr(socket.local_addr()?));
+    let _ = writeln!(out, "Remote address:  {}", addr(socket.peer_addr()?));
+    #[cfg(target_os = "linux")]
+    {
+        let state = linux::tcp_state(socket).unwrap_or_else(|| "unknown".to_string());
+        let _ = writeln!(out, "TCP state:       {}", state);
+    }
+    let _ = writeln!(out, "Send buffer:     {} bytes", socket.send_buffer_size()?);
+    let _ = writeln!(out, "Receive buffer:  {} bytes", socket.recv_buffer_size()?);
+    #[cfg(target_os = "linux")]
+    {
+        let (unread, unsent) = linux::queued_bytes(socket)?;
+        let _ = writeln!(out, "Unread bytes:    {}", unread);
+        let _ = writeln!(out, "Unsent bytes:    {}", unsent);
+    }
+
+    Ok(out)
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use nix::libc;
+    use socket2::Socket;
+    use std::{fs, io, os::fd::AsRawFd};
+
+    nix::ioctl_read_bad!(unread_bytes, libc::FIONREAD, libc::c_int);
+    nix::ioctl_read_bad!(unsent_bytes, libc::TIOCOUTQ, libc::c_int);
+
+    /// Returns the number of bytes waiting in the kernel's receive and send queues.
+    pub fn queued_bytes(socket: &Socket) -> io::Result<(i32, i32)> {
+        let fd = socket.as_raw_fd();
+        let (mut unread, mut unsent) = (0, 0);
+        // SAFETY: `fd` is a valid socket for the lifetime of `socket`, and both ioctls write a
+        // single `c_int` through the pointer.
+        unsafe {
+            unread_bytes(fd, &mut unread)?;
+            unsent_bytes(fd, &mut unsent)?;
+        }
+        Ok((unread, unsent))
+    }
+
+    /// Looks up the TCP state of `socket` in `/proc/net/tcp` by its inode.
+    pub fn tcp_state(socket: &Socket) -> Option<String> {
+        let link = fs::read_link(format!("/proc/self/fd/{}", socket.as_raw_fd())).ok()?;
+        let inode = link
+            .to_str()?
+            .strip_prefix("socket:[")?
+            .strip_suffix(']')?
+            .to_string();
+
+        ["/proc/net/tcp", "/proc/net/tcp6"].iter().find_map(|path| {
+            let table = fs::read_to_string(path).ok()?;
+            table.lines().skip(1).find_map(|line| {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                (fields.get(9) == Some(&inode.as_str())).then(|| state_name(fields[3]).to_string())
+            })
+        })
+    }
+
+    fn state_name(code: &str) -> &str {
+        match code {
+            "01" => "ESTABLISHED",
+            "02" => "SYN_SENT",
+            "03" => "SYN_RECV",
+            "04" => "FIN_WAIT1",
+            "05" => "FIN_WAIT2",
+            "06" => "TIME_WAIT",
+            "07" => "CLOSE",
+            "08" => "CLOSE_WAIT",
+            "09" => "LAST_ACK",
+            "0A" => "LISTEN",
+            "0B" => "CLOSING",
+            other => other,
+        }
+    }
+}
@@ -0,0 +1,36 @@
+//! Showing long command output through a pager such as `less`.
+
+use std::{env, io, process::Stdio};
+use tokio::{io::AsyncWriteExt, process::Command};
+
+/// The pager used when `$PAGER` isn't set.
+const DEFAULT_PAGER: &str = "less";
+
+/// Shows `lines` through `$PAGER`, or `less` if it isn't set, and waits for it to exit.
+pub async fn page(lines: &[String]) -> io::Result<()> {
+    let pager = env::var("PAGER")
+        .ok()
+        .filter(|pager| !pager.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_PAGER.to_string());
+    let mut words = pager.split_whitespace();
+    let program = words.next().expect("the pager command isn't blank");
+    let mut command = Command::new(program);
+    command.args(words).stdin(Stdio::piped());
+    if env::var_os("LESS").is_none() {
+        // Let less show colors instead of their escape codes.
+        command.env("LESS", "R");
+    }
+    let mut child = command.spawn()?;
+
+    let mut stdin = child.stdin.take().expect("the pager's stdin is piped");
+    let mut text = lines.join("\n");
+    text.push('\n');
+    match stdin.write_all(text.as_bytes()).await {
+        // The pager was quit before reading everything.
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {}
+        result => result?,
+    }
+    drop(stdin);
+    child.wait().await?;
+    Ok(())
+}
@@ -0,0 +1,134 @@
+//! Reassembly of messages the server split into `PARTIAL_MESSAGE` chunks.
+
+use crate::protocol::{Extension, Message, PartialPayload};
+use log::{debug, warn};
+use std::{collections::HashMap, time::Duration};
+use tokio::time::Instant;
+
+/// How long the chunks of a message are kept waiting for the rest.
+const PARTIAL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The most chunks a message can be split into, so a bogus count can't make the client
+/// allocate without limit.
+const MAX_CHUNKS: u32 = 1024;
+
+/// The chunks received so far of one message.
+struct Partial {
+    chunks: Vec<Option<String>>,
+    received: usize,
+    started: Instant,
+}
+
+/// Chunks of split messages waiting for the rest, by message ID.
+#[derive(Default)]
+pub struct PartialMessageBuffer {
+    pending: HashMap<String, Partial>,
+}
+
+impl PartialMessageBuffer {
+    /// Passes `msg` on unless it's a chunk, returning `None` while its message is
+    /// incomplete and the message parsed from the joined chunks once it's whole.
+    pub fn reassemble(
+        &mut self,
+        msg: Message,
+        now: Instant,
+    ) -> Result<Option<Message>, serde_json::Error> {
+        match msg {
+            Message::Extension(Extension::PartialMessage { payload }) => self
+                .add(payload, now)
+                .map(|json| serde_json::from_str(&json))
+                .transpose(),
+            msg => Ok(Some(msg)),
+        }
+    }
+
+    /// Adds a chunk received at `now`, returning the whole message once its last chunk is
+    /// in. Messages still incomplete after [`PARTIAL_TIMEOUT`] are dropped.
+    fn add(&mut self, chunk: PartialPayload, now: Instant) -> Option<String> {
+        self.pending.retain(|id, partial| {
+            let expired = now - partial.started >= PARTIAL_TIMEOUT;
+            if expired {
+                warn!(
+                    "Dropping message {} after receiving {} of {} chunks",
+                    id,
+                    partial.received,
+                    partial.chunks.len()
+                );
+            }
+            !expired
+        });
+
+        let PartialPayload {
+            chunk_index,
+            total_chunks,
+            chunk_data,
+            message_id,
+        } = chunk;
+        if !(1..=MAX_CHUNKS).contains(&total_chunks) || chunk_index >= total_chunks {
+            debug!(
+                "Ignoring chunk {} of {} of message {}",
+                chunk_index, total_chunks, message_id
+            );
+            return None;
+        }
+        let partial = self
+            .pending
+            .entry(message_id.clone())
+            .or_insert_with(|| Partial {
+                chunks: vec![None; total_chunks as usize],
+                received: 0,
+                started: now,
+            });
+        if partial.chunks.len() != total_chunks as usize {
+            debug!(
+                "Ignoring chunk with a changed count for message {}",
+                message_id
+            );
+            return None;
+        }
+        let slot = &mut partial.chunks[chunk_index as usize];
+        if slot.is_none() {
+            partial.received += 1;
+        }
+        *slot = Some(chunk_data);
+
+        if partial.received < partial.chunks.len() {
+            return None;
+        }
+        let partial = self.pending.remove(&message_id)?;
+        Some(partial.chunks.into_iter().flatten().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(message_id: &str, chunk_index: u32, total_chunks: u32, data: &str) -> PartialPayload {
+        PartialPayload {
+            chunk_index,
+            total_chunks,
+            chunk_data: data.into(),
+            message_id: message_id.into(),
+        }
+    }
+
+    #[test]
+    fn reassembles_chunks_in_any_order_until_they_expire() {
+        let mut buffer = PartialMessageBuffer::default();
+        let start = Instant::now();
+        assert_eq!(buffer.add(chunk("a", 2, 3, "!"), start), None);
+        assert_eq!(buffer.add(chunk("b", 0, 2, "lost"), start), None);
+        assert_eq!(buffer.add(chunk("a", 0, 3, "hel"), start), None);
+        assert_eq!(buffer.add(chunk("a", 3, 3, "?"), start), None);
+        assert_eq!(
+            buffer.add(chunk("a", 1, 3, "lo"), start).as_deref(),
+            Some("hello!")
+        );
+
+        let later = start + PARTIAL_TIMEOUT;
+        // The first chunk of `b` expired, so its second one starts over.
+        assert_eq!(buffer.add(chunk("b", 1, 2, "late"), later), None);
+        assert_eq!(buffer.pending["b"].received, 1);
+    }
+}
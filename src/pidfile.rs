@@ -0,0 +1,28 @@
+use log::warn;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+};
+
+/// A file holding the PID of this process, removed again when dropped.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        fs::write(path, format!("{}\n", process::id()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Can't remove PID file {}: {}", self.path.display(), e);
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use log::warn;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+use tokio::io::AsyncBufRead;
+#[cfg(unix)]
+use tokio::{io::BufReader, net::unix::pipe};
+
+/// A named pipe that other programs can write messages and commands to, read alongside
+/// stdin and removed again when dropped.
+pub struct PipeIn {
+    path: PathBuf,
+    #[cfg(unix)]
+    reader: BufReader<pipe::Receiver>,
+    /// Kept open so reading waits for the next writer instead of ending when one closes the
+    /// pipe.
+    #[cfg(unix)]
+    _writer: pipe::Sender,
+}
+
+impl PipeIn {
+    /// Creates a named pipe at `path` and opens it for reading.
+    #[cfg(unix)]
+    pub fn create(path: &Path) -> io::Result<Self> {
+        use nix::sys::stat::Mode;
+
+        nix::unistd::mkfifo(path, Mode::S_IRUSR | Mode::S_IWUSR)?;
+        let pipe = Self::open(path);
+        if pipe.is_err() {
+            let _ = fs::remove_file(path);
+        }
+        pipe
+    }
+
+    #[cfg(unix)]
+    fn open(path: &Path) -> io::Result<Self> {
+        // The reading end has to be open before the writing end can be.
+        let reader = pipe::OpenOptions::new().open_receiver(path)?;
+        let writer = pipe::OpenOptions::new().open_sender(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            reader: BufReader::new(reader),
+            _writer: writer,
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn create(_path: &Path) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "named pipes are only supported on Unix",
+        ))
+    }
+
+    /// Returns the reader for lines written to the pipe.
+    pub fn reader(&mut self) -> &mut (dyn AsyncBufRead + Unpin) {
+        #[cfg(unix)]
+        return &mut self.reader;
+        #[cfg(not(unix))]
+        unreachable!("named pipes can't be created without Unix");
+    }
+}
+
+impl Drop for PipeIn {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Can't remove named pipe {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn reads_lines_from_successive_writers() {
+        let path = std::env::temp_dir().join(format!("minechat-pipe-{}", std::process::id()));
+        let mut pipe = PipeIn::create(&path).unwrap();
+
+        for text in ["hello\n", "/stats\n"] {
+            let mut writer = pipe::OpenOptions::new().open_sender(&path).unwrap();
+            writer.write_all(text.as_bytes()).await.unwrap();
+            drop(writer);
+
+            let mut line = String::new();
+            pipe.reader().read_line(&mut line).await.unwrap();
+            assert_eq!(line, text);
+        }
+
+        drop(pipe);
+        assert!(!path.exists());
+    }
+}
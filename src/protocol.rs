@@ -0,0 +1,674 @@
+use minechat_protocol::protocol::{MineChatError, MineChatMessage};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// A message exchanged with a MineChat server.
+///
+/// This wraps the messages defined by `minechat-protocol` and adds a catch-all for message
+/// types the client doesn't know about, so servers can send extension messages without
+/// breaking older clients.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Message {
+    /// A message type added or extended by this client on top of the MineChat protocol.
+    Extension(Extension),
+
+    /// A message defined by the MineChat protocol.
+    Core(MineChatMessage),
+
+    /// A message with an unrecognized `type`, with its payload kept as raw JSON.
+    Custom {
+        #[serde(rename = "type")]
+        message_type: String,
+        #[serde(default)]
+        payload: Value,
+    },
+}
+
+/// Message types understood by the client that `minechat-protocol` doesn't define yet,
+/// including core messages with extra fields.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Extension {
+    /// An authentication message that can resume a session with a token instead of
+    /// authenticating by UUID alone, and ask for messages missed since a checkpoint.
+    #[serde(rename = "AUTH")]
+    Auth { payload: SessionAuthPayload },
+
+    /// A chat message, optionally signed.
+    #[serde(rename = "CHAT")]
+    Chat { payload: ChatPayload },
+
+    /// Several chat messages sent together, which the server broadcasts one by one.
+    #[serde(rename = "BULK_MESSAGE")]
+    BulkMessage { payload: BulkMessagePayload },
+
+    /// A broadcast message, optionally signed by the sender.
+    #[serde(rename = "BROADCAST")]
+    Broadcast { payload: BroadcastPayload },
+
+    /// A broadcast with tags, shown like any other broadcast.
+    #[serde(rename = "TAGGED_BROADCAST")]
+    TaggedBroadcast { payload: BroadcastPayload },
+
+    /// A private message addressed to several users.
+    #[serde(rename = "GROUP_PRIVATE_MESSAGE")]
+    GroupPrivateMessage { payload: GroupPrivateMessagePayload },
+
+    /// A request for the list of known MineChat servers.
+    #[serde(rename = "SERVER_LIST_REQUEST")]
+    ServerListRequest,
+
+    /// The list of MineChat servers known to the server.
+    #[serde(rename = "SERVER_LIST_RESPONSE")]
+    ServerListResponse { payload: ServerListResponsePayload },
+
+    /// An acknowledgement that messages have been read, sent by the client for displayed
+    /// messages and relayed by the server for messages read by others.
+    #[serde(rename = "READ_RECEIPT")]
+    ReadReceipt { payload: ReadReceiptPayload },
+
+    /// A session token issued by the server after a successful authentication.
+    #[serde(rename = "SESSION_TOKEN")]
+    SessionToken { payload: SessionTokenPayload },
+
+    /// A notice that the server is shutting down, possibly to restart.
+    #[serde(rename = "SERVER_SHUTDOWN")]
+    ServerShutdown { payload: ServerShutdownPayload },
+
+    /// The topic of a channel, sent by the server when joining a channel or when the topic
+    /// changes, and by the client to change it.
+    #[serde(rename = "CHANNEL_TOPIC_SET")]
+    ChannelTopicSet { payload: TopicPayload },
+
+    /// A request for the topic of a channel, answered with a topic message.
+    #[serde(rename = "CHANNEL_TOPIC_GET")]
+    ChannelTopicGet { payload: TopicRequestPayload },
+
+    /// A request to join a channel, with its password if it has one.
+    #[serde(rename = "CHANNEL_JOIN")]
+    ChannelJoin { payload: JoinPayload },
+
+    /// The server's answer to a request to join a channel.
+    #[serde(rename = "CHANNEL_JOIN_ACK")]
+    ChannelJoinAck { payload: JoinAckPayload },
+
+    /// A request to leave a channel.
+    #[serde(rename = "CHANNEL_LEAVE")]
+    ChannelLeave { payload: LeavePayload },
+
+    /// A welcome message the server sends to new users.
+    #[serde(rename = "GREETING")]
+    Greeting { payload: GreetingPayload },
+
+    /// One chunk of a message the server split because it was too large. The chunks'
+    /// data joined in order is the message.
+    #[serde(rename = "PARTIAL_MESSAGE")]
+    PartialMessage { payload: PartialPayload },
+
+    /// A user's change of username.
+    #[serde(rename = "NAME_CHANGE")]
+    NameChange { payload: NameChangePayload },
+
+    /// A report of an inappropriate message, only seen by the server's moderators.
+    #[serde(rename = "SPAM_REPORT")]
+    SpamReport { payload: SpamReportPayload },
+
+    /// The server's answer to a spam report.
+    #[serde(rename = "SPAM_REPORT_ACK")]
+    SpamReportAck { payload: SpamReportAckPayload },
+
+    /// A request for the current leaderboard, optionally of one category.
+    #[serde(rename = "LEADERBOARD_REQUEST")]
+    LeaderboardRequest { payload: LeaderboardRequestPayload },
+
+    /// Game statistics relayed by the server, ranked by score.
+    #[serde(rename = "LEADERBOARD")]
+    Leaderboard { payload: LeaderboardPayload },
+
+    /// The current scoreboard, which replaces the one sent before.
+    #[serde(rename = "SCOREBOARD_UPDATE")]
+    ScoreboardUpdate { payload: ScoreboardPayload },
+
+    /// Confirmation that a chat message with an ID was delivered.
+    #[serde(rename = "BROADCAST_ACK")]
+    BroadcastAck { payload: BroadcastAckPayload },
+
+    /// A marker sent periodically by the server that a reconnecting client can resume from.
+    #[serde(rename = "CHECKPOINT")]
+    Checkpoint { payload: CheckpointPayload },
+
+    /// How long the link code being used stays valid, sent by the server while linking.
+    #[serde(rename = "LINK_EXPIRY")]
+    LinkExpiry { payload: LinkExpiryPayload },
+
+    /// Which client and version is connecting, sent after authenticating.
+    #[serde(rename = "CLIENT_INFO")]
+    ClientInfo { payload: ClientInfoPayload },
+
+    /// A request for the other side to answer with a pong carrying the same nonce.
+    #[serde(rename = "PING")]
+    Ping { payload: PingPayload },
+
+    /// The answer to a ping.
+    #[serde(rename = "PONG")]
+    Pong { payload: PingPayload },
+
+    /// Statistics the server sends periodically, showing it's still there.
+    #[serde(rename = "SERVER_HEARTBEAT")]
+    ServerHeartbeat { payload: HeartbeatPayload },
+
+    /// The outcome of a slash command, such as `/ban` or `/kick`, the server executed.
+    #[serde(rename = "COMMAND_RESULT")]
+    CommandResult { payload: CommandResultPayload },
+}
+
+/// The payload for an authentication message resuming an earlier session.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionAuthPayload {
+    /// The client's UUID.
+    pub client_uuid: String,
+    /// The link code, empty when already linked.
+    pub link_code: String,
+    /// The session token to resume.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_token: Option<String>,
+    /// The ID of the last checkpoint received, asking the server to replay the messages
+    /// sent since.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resume_from_checkpoint: Option<String>,
+}
+
+/// The payload for a chat message.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChatPayload {
+    /// The text of the chat message.
+    pub message: String,
+    /// A client-assigned ID, used by the server to acknowledge delivery.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    /// The base64-encoded Ed25519 signature of `message`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    /// The ID of the message this one replies to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+}
+
+/// The payload for a bulk message.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BulkMessagePayload {
+    /// The chat messages, in the order they were written.
+    pub messages: Vec<ChatPayload>,
+}
+
+/// The payload for a broadcast message.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BroadcastPayload {
+    /// The name of the sender.
+    pub from: String,
+    /// The text of the broadcast message.
+    pub message: String,
+    /// The server-assigned ID of the message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    /// The base64-encoded Ed25519 signature of `message`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    /// When the server sent the message, in milliseconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sent_at: Option<u64>,
+    /// The ID of the message this one replies to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+    /// The channel the message was sent in, on servers with channels.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+    /// Labels the server attached to the message, like `announcement`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+/// The payload for a broadcast acknowledgement.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BroadcastAckPayload {
+    /// The ID of the acknowledged chat message.
+    pub message_id: String,
+    /// The number of users the message was delivered to.
+    pub delivered_to: u32,
+}
+
+/// The payload for a checkpoint.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CheckpointPayload {
+    /// The ID of the checkpoint.
+    pub checkpoint_id: String,
+    /// When the checkpoint was made, in seconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+/// The payload for a link code expiry notice.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LinkExpiryPayload {
+    /// How many seconds until the code expires; 0 if it already has.
+    pub seconds_remaining: u64,
+}
+
+/// The payload for a client information message.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClientInfoPayload {
+    /// The name of the client program.
+    pub client_name: String,
+    /// The version of the client program.
+    pub client_version: String,
+    /// The operating system the client runs on, like `linux` or `windows`.
+    pub os: String,
+    /// The user's locale as a BCP 47 language tag, like `en-US`.
+    pub locale: String,
+}
+
+/// The payload for a ping or pong.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PingPayload {
+    /// A random value identifying the ping, echoed back in the pong.
+    pub nonce: u32,
+}
+
+/// The payload for a server heartbeat.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeartbeatPayload {
+    /// How long the server has been running, in seconds.
+    pub server_uptime_secs: u64,
+    /// How many clients are connected to the server.
+    pub connected_clients: u32,
+    /// How many messages the server relayed in the last minute, on average.
+    pub messages_per_minute: f64,
+}
+
+/// The payload for the result of a command.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CommandResultPayload {
+    /// The command as it was executed.
+    pub command: String,
+    /// Whether the command succeeded.
+    pub success: bool,
+    /// What the command printed.
+    pub output: String,
+    /// How to use the command, when it failed because it was malformed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub help: Option<String>,
+}
+
+/// The payload for a group private message.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GroupPrivateMessagePayload {
+    /// The name of the sender. Filled in by the server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    /// The usernames of the recipients.
+    pub to: Vec<String>,
+    /// The text of the message.
+    pub message: String,
+}
+
+/// The payload for a session token message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionTokenPayload {
+    /// The opaque session token.
+    pub token: String,
+    /// When the token expires, in seconds since the Unix epoch.
+    pub expires_at: u64,
+}
+
+/// The payload for a server shutdown notice.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServerShutdownPayload {
+    /// Why the server is shutting down.
+    pub message: String,
+    /// How long until the server is back up, if it is restarting.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restart_in_seconds: Option<u64>,
+}
+
+/// The payload for a channel topic message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopicPayload {
+    /// The name of the channel.
+    pub channel: String,
+    /// The topic of the channel.
+    pub topic: String,
+    /// The name of whoever set the topic. Filled in by the server.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub set_by: String,
+}
+
+/// The payload for a channel join request.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JoinPayload {
+    /// The name of the channel.
+    pub channel: String,
+    /// The password of the channel, for password-protected channels.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<Secret>,
+}
+
+/// The payload for the answer to a channel join request.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JoinAckPayload {
+    /// The name of the channel.
+    pub channel: String,
+    /// `success`, `wrong_password` or another reason the channel couldn't be joined.
+    pub status: String,
+    /// A message from the server explaining the status.
+    #[serde(default)]
+    pub message: String,
+}
+
+/// The payload for a channel leave request.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LeavePayload {
+    /// The name of the channel.
+    pub channel: String,
+}
+
+/// The payload for a greeting.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GreetingPayload {
+    /// The welcome text, possibly several lines long.
+    pub welcome_text: String,
+    /// A link to the server's rules.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rules_url: Option<String>,
+}
+
+/// The payload for a chunk of a split message.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PartialPayload {
+    /// The position of this chunk, from 0.
+    pub chunk_index: u32,
+    /// How many chunks the message was split into.
+    pub total_chunks: u32,
+    /// This chunk's part of the message's JSON.
+    pub chunk_data: String,
+    /// The ID shared by the chunks of one message.
+    pub message_id: String,
+}
+
+/// The payload for a change of username.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NameChangePayload {
+    /// The username the user had.
+    pub old_username: String,
+    /// The username the user has now.
+    pub new_username: String,
+}
+
+/// The payload for a spam report.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SpamReportPayload {
+    /// The ID of the reported message.
+    pub message_id: String,
+    /// Why the message is inappropriate.
+    pub reason: String,
+}
+
+/// The payload for the answer to a spam report.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SpamReportAckPayload {
+    /// The ID of the reported message.
+    pub message_id: String,
+    /// `success`, or why the report was rejected.
+    pub status: String,
+    /// A message from the server explaining the status.
+    #[serde(default)]
+    pub message: String,
+}
+
+/// A value that must not end up in logs, such as a password. Its `Debug` output is redacted.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(pub String);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(***)")
+    }
+}
+
+/// The payload for a channel topic request.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TopicRequestPayload {
+    /// The name of the channel.
+    pub channel: String,
+}
+
+/// The payload for a read receipt.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReadReceiptPayload {
+    /// The IDs of the messages that have been read.
+    pub message_ids: Vec<String>,
+}
+
+/// The payload for a leaderboard request.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LeaderboardRequestPayload {
+    /// The category to rank players in, or the server's default if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+}
+
+/// The payload for a leaderboard.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LeaderboardPayload {
+    /// What the leaderboard ranks, such as `Most kills`.
+    pub title: String,
+    /// The ranked players, best first.
+    pub entries: Vec<LeaderboardEntry>,
+}
+
+/// A player's place on a leaderboard.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LeaderboardEntry {
+    /// The player's position, starting at 1.
+    pub rank: u32,
+    /// The name of the player.
+    pub player: String,
+    /// The player's score.
+    pub score: i64,
+}
+
+/// The payload for a scoreboard update.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScoreboardPayload {
+    /// Label and value pairs, top to bottom. Only the first 15 are shown.
+    pub entries: Vec<(String, String)>,
+}
+
+/// The payload for a server list response.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServerListResponsePayload {
+    /// The servers known to the server.
+    pub servers: Vec<ServerListEntry>,
+}
+
+/// A MineChat server in a server list response.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServerListEntry {
+    /// The address of the server (host:port).
+    pub address: String,
+    /// The name of the server.
+    pub name: String,
+    /// The number of players currently online.
+    pub player_count: u32,
+    /// A short description of the server.
+    #[serde(default)]
+    pub description: String,
+}
+
+impl Message {
+    /// The `type` the message is sent with.
+    pub fn message_type(&self) -> String {
+        if let Message::Custom { message_type, .. } = self {
+            return message_type.clone();
+        }
+        serde_json::to_value(self)
+            .ok()
+            .and_then(|json| json["type"].as_str().map(str::to_string))
+            .unwrap_or_else(|| "unknown".into())
+    }
+
+    /// Whether `--safe-mode` allows sending the message: only authentication, chat, pings
+    /// and disconnecting are.
+    pub fn is_safe(&self) -> bool {
+        match self {
+            Message::Core(msg) => matches!(
+                msg,
+                MineChatMessage::Auth { .. }
+                    | MineChatMessage::Chat { .. }
+                    | MineChatMessage::Disconnect { .. }
+            ),
+            Message::Extension(msg) => msg.is_safe(),
+            Message::Custom { .. } => false,
+        }
+    }
+}
+
+impl Extension {
+    /// Whether `--safe-mode` allows sending the message, like [`Message::is_safe`]. Bulk
+    /// messages are the way chat messages are batched.
+    pub fn is_safe(&self) -> bool {
+        matches!(
+            self,
+            Extension::Auth { .. }
+                | Extension::Chat { .. }
+                | Extension::BulkMessage { .. }
+                | Extension::Ping { .. }
+        )
+    }
+}
+
+impl From<MineChatMessage> for Message {
+    fn from(msg: MineChatMessage) -> Self {
+        Message::Core(msg)
+    }
+}
+
+impl From<Extension> for Message {
+    fn from(msg: Extension) -> Self {
+        Message::Extension(msg)
+    }
+}
+
+/// Formats the message the way the REPL shows it, without colors. Messages the REPL has no
+/// single line for are described by their type.
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Message::Core(MineChatMessage::Auth { payload }) => {
+                write!(f, "Authenticating as {}", payload.client_uuid)
+            }
+            Message::Core(MineChatMessage::AuthAck { payload }) => {
+                write!(f, "Authentication {}: {}", payload.status, payload.message)
+            }
+            Message::Core(MineChatMessage::Chat { payload }) => f.write_str(&payload.message),
+            Message::Core(MineChatMessage::Broadcast { payload }) => {
+                write!(f, "[{}] {}", payload.from, payload.message)
+            }
+            Message::Core(MineChatMessage::Disconnect { payload }) => {
+                write!(f, "Disconnected: {}", payload.reason)
+            }
+            Message::Extension(Extension::Auth { payload }) => payload.fmt(f),
+            Message::Extension(Extension::Chat { payload }) => payload.fmt(f),
+            Message::Extension(
+                Extension::Broadcast { payload } | Extension::TaggedBroadcast { payload },
+            ) => payload.fmt(f),
+            msg => write!(f, "{} message", msg.message_type()),
+        }
+    }
+}
+
+/// Describes the authentication without the link code or session token.
+impl fmt::Display for SessionAuthPayload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Authenticating as {}", self.client_uuid)?;
+        if self.session_token.is_some() {
+            f.write_str(" with a session token")?;
+        }
+        if let Some(checkpoint) = &self.resume_from_checkpoint {
+            write!(f, " from checkpoint {}", checkpoint)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ChatPayload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// Formats the broadcast as `[from] message`.
+impl fmt::Display for BroadcastPayload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}", self.from, self.message)
+    }
+}
+
+/// Sends a message to the server as a single line of JSON.
+pub async fn send<W, M>(writer: &mut W, msg: &M) -> Result<(), MineChatError>
+where
+    W: AsyncWrite + Unpin,
+    M: Serialize,
+{
+    let mut json = serde_json::to_vec(msg)?;
+    json.push(b'\n');
+    writer.write_all(&json).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use minechat_protocol::protocol::DisconnectPayload;
+
+    #[test]
+    fn displays_messages_like_the_repl() {
+        let broadcast = Message::Extension(Extension::Broadcast {
+            payload: BroadcastPayload {
+                from: "alice".into(),
+                message: "hi".into(),
+                message_id: None,
+                signature: None,
+                sent_at: None,
+                reply_to: None,
+                channel: None,
+                tags: Vec::new(),
+            },
+        });
+        assert_eq!(broadcast.to_string(), "[alice] hi");
+        let disconnect = Message::Core(MineChatMessage::Disconnect {
+            payload: DisconnectPayload {
+                reason: "Server closing".into(),
+            },
+        });
+        assert_eq!(disconnect.to_string(), "Disconnected: Server closing");
+        let auth = Message::Extension(Extension::Auth {
+            payload: SessionAuthPayload {
+                client_uuid: "0b6f9c4e".into(),
+                link_code: "123456".into(),
+                session_token: Some("token".into()),
+                resume_from_checkpoint: None,
+            },
+        });
+        assert_eq!(
+            auth.to_string(),
+            "Authenticating as 0b6f9c4e with a session token"
+        );
+        let ping = Message::Extension(Extension::Ping {
+            payload: PingPayload { nonce: 1 },
+        });
+        assert_eq!(ping.to_string(), "PING message");
+    }
+}
@@ -0,0 +1,368 @@
+use crate::{
+    partial::PartialMessageBuffer,
+    protocol::{self, BulkMessagePayload, ChatPayload, Extension, Message},
+};
+use log::debug;
+use minechat_protocol::protocol::MineChatError;
+use std::io;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+    task::JoinHandle,
+    time::Instant,
+};
+
+/// How many received messages can wait before the reader has to wait.
+const QUEUE_CAPACITY: usize = 32;
+
+/// The network side of a session.
+///
+/// One task reads messages from the server into a channel and another writes the messages
+/// queued with [`MessageQueue::send`] to the server, so the REPL never touches the connection
+/// directly.
+///
+/// Chat messages queued faster than they can be written are sent as a single bulk message of
+/// up to `max_batch_size` messages. A `max_batch_size` of 1 always sends them one by one.
+///
+/// At most `max_queue_depth` messages wait to be sent; [`MessageQueue::is_full`] tells when
+/// to stop producing more.
+pub struct MessageQueue {
+    incoming: mpsc::Receiver<Message>,
+    outgoing: mpsc::Sender<Message>,
+    reader: JoinHandle<io::Result<()>>,
+    writer: JoinHandle<Result<(), MineChatError>>,
+}
+
+impl MessageQueue {
+    pub fn spawn<R, W>(reader: R, writer: W, max_batch_size: usize, max_queue_depth: usize) -> Self
+    where
+        R: AsyncBufRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (incoming_tx, incoming) = mpsc::channel(QUEUE_CAPACITY);
+        let (outgoing, outgoing_rx) = mpsc::channel(max_queue_depth);
+        Self {
+            incoming,
+            outgoing,
+            reader: tokio::spawn(read_messages(reader, incoming_tx)),
+            writer: tokio::spawn(write_messages(writer, outgoing_rx, max_batch_size)),
+        }
+    }
+
+    /// Receives the next message from the server, or `None` once the connection is closed.
+    pub async fn recv(&mut self) -> Option<Message> {
+        self.incoming.recv().await
+    }
+
+    /// Returns the next message from the server if one has already arrived.
+    pub fn try_recv(&mut self) -> Option<Message> {
+        self.incoming.try_recv().ok()
+    }
+
+    /// Queues a message to be sent to the server.
+    pub async fn send(&self, msg: impl Into<Message>) -> Result<(), MineChatError> {
+        self.outgoing
+            .send(msg.into())
+            .await
+            .map_err(|_| MineChatError::Disconnected)
+    }
+
+    /// Whether as many messages are waiting to be sent as the queue holds, so queueing
+    /// another would wait.
+    pub fn is_full(&self) -> bool {
+        self.outgoing.capacity() == 0
+    }
+
+    /// Returns a future that completes once another message can be queued without waiting.
+    ///
+    /// The future doesn't borrow the queue, so it can wait alongside [`MessageQueue::recv`].
+    pub fn ready(&self) -> impl Future<Output = Result<(), MineChatError>> + use<> {
+        let outgoing = self.outgoing.clone();
+        async move {
+            outgoing
+                .reserve()
+                .await
+                .map(drop)
+                .map_err(|_| MineChatError::Disconnected)
+        }
+    }
+
+    /// Sends any messages still queued and stops both tasks.
+    ///
+    /// Returns the error that stopped either task, if any, preferring the writer's.
+    pub async fn close(self) -> Result<(), MineChatError> {
+        let Self {
+            outgoing,
+            reader,
+            writer,
+            ..
+        } = self;
+        drop(outgoing);
+        reader.abort();
+
+        let written = writer.await.map_err(io::Error::other)?;
+        let read = match reader.await {
+            Ok(result) => result,
+            Err(e) if e.is_cancelled() => Ok(()),
+            Err(e) => Err(io::Error::other(e)),
+        };
+        written?;
+        Ok(read?)
+    }
+}
+
+/// Reads messages from `reader` and forwards them to `tx` until the connection is closed.
+///
+/// Every complete line that arrived with a read is parsed before waiting for more data, so
+/// a burst of messages costs one wakeup instead of one per line. Messages split into chunks
+/// are forwarded once whole.
+async fn read_messages<R>(mut reader: R, tx: mpsc::Sender<Message>) -> io::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = Vec::new();
+    let mut ready = Vec::new();
+    let mut partials = PartialMessageBuffer::default();
+    loop {
+        let available = reader.fill_buf().await?;
+        let eof = available.is_empty();
+        let len = available.len();
+        for chunk in available.split_inclusive(|&b| b == b'\n') {
+            line.extend_from_slice(chunk);
+            if line.ends_with(b"\n") {
+                parse_line(&line, &mut partials, &mut ready);
+                line.clear();
+            }
+        }
+        reader.consume(len);
+        if eof && !line.is_empty() {
+            // The connection closed in the middle of a line; it may still be a whole message.
+            parse_line(&line, &mut partials, &mut ready);
+        }
+
+        for msg in ready.drain(..) {
+            if tx.send(msg).await.is_err() {
+                return Ok(());
+            }
+        }
+        if eof {
+            return Ok(());
+        }
+    }
+}
+
+/// Parses `line` as a message and adds it to `ready`, skipping it if it's malformed. A
+/// chunk is added to `partials` instead, and the message it completes to `ready`.
+fn parse_line(line: &[u8], partials: &mut PartialMessageBuffer, ready: &mut Vec<Message>) {
+    let msg = serde_json::from_slice::<Message>(line)
+        .and_then(|msg| partials.reassemble(msg, Instant::now()));
+    match msg {
+        Ok(Some(msg)) => ready.push(msg),
+        Ok(None) => {}
+        Err(e) => debug!("Ignoring malformed message: {}", e),
+    }
+}
+
+/// Writes the messages received on `rx` to `writer` until every sender is gone, batching
+/// consecutive chat messages that are waiting at the same time.
+async fn write_messages<W>(
+    mut writer: W,
+    mut rx: mpsc::Receiver<Message>,
+    max_batch_size: usize,
+) -> Result<(), MineChatError>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut ready = Vec::new();
+    let mut chats = Vec::new();
+    while rx.recv_many(&mut ready, QUEUE_CAPACITY).await > 0 {
+        for msg in ready.drain(..) {
+            match msg {
+                Message::Extension(Extension::Chat { payload }) if max_batch_size > 1 => {
+                    chats.push(payload);
+                    if chats.len() == max_batch_size {
+                        send_chats(&mut writer, &mut chats).await?;
+                    }
+                }
+                msg => {
+                    send_chats(&mut writer, &mut chats).await?;
+                    protocol::send(&mut writer, &msg).await?;
+                }
+            }
+        }
+        send_chats(&mut writer, &mut chats).await?;
+        writer.flush().await?;
+    }
+    Ok(())
+}
+
+/// Sends and clears the pending chat messages, as a bulk message if there are several.
+async fn send_chats<W>(writer: &mut W, chats: &mut Vec<ChatPayload>) -> Result<(), MineChatError>
+where
+    W: AsyncWrite + Unpin,
+{
+    let msg = match chats.len() {
+        0 => return Ok(()),
+        1 => Extension::Chat {
+            payload: chats.pop().expect("one message is pending"),
+        },
+        _ => Extension::BulkMessage {
+            payload: BulkMessagePayload {
+                messages: std::mem::take(chats),
+            },
+        },
+    };
+    protocol::send(writer, &msg).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use minechat_protocol::protocol::{DisconnectPayload, MineChatMessage};
+    use tokio::io::{AsyncReadExt, BufReader, duplex, split};
+
+    #[tokio::test]
+    async fn skips_malformed_lines() {
+        let (client, mut server) = duplex(4096);
+        let (reader, writer) = split(client);
+        server
+            .write_all(b"not json\n{\"type\":\"DISCONNECT\",\"payload\":{\"reason\":\"bye\"}}\n")
+            .await
+            .unwrap();
+        drop(server);
+
+        let mut queue = MessageQueue::spawn(BufReader::new(reader), writer, 1, QUEUE_CAPACITY);
+        assert!(matches!(
+            queue.recv().await,
+            Some(Message::Core(MineChatMessage::Disconnect { .. }))
+        ));
+        assert!(queue.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn forwards_split_messages_once_whole() {
+        let (client, mut server) = duplex(4096);
+        let (reader, writer) = split(client);
+        let json = "{\"type\":\"DISCONNECT\",\"payload\":{\"reason\":\"bye\"}}";
+        let (first, second) = json.split_at(20);
+        for (i, data) in [(1, second), (0, first)] {
+            let chunk = Extension::PartialMessage {
+                payload: protocol::PartialPayload {
+                    chunk_index: i,
+                    total_chunks: 2,
+                    chunk_data: data.into(),
+                    message_id: "big".into(),
+                },
+            };
+            protocol::send(&mut server, &chunk).await.unwrap();
+        }
+        drop(server);
+
+        let mut queue = MessageQueue::spawn(BufReader::new(reader), writer, 1, QUEUE_CAPACITY);
+        assert!(matches!(
+            queue.recv().await,
+            Some(Message::Core(MineChatMessage::Disconnect { .. }))
+        ));
+        assert!(queue.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn parses_lines_split_across_reads() {
+        let (client, mut server) = duplex(4096);
+        let (reader, writer) = split(client);
+        // A buffer smaller than one message, so every read ends mid-line.
+        let mut queue = MessageQueue::spawn(
+            BufReader::with_capacity(16, reader),
+            writer,
+            1,
+            QUEUE_CAPACITY,
+        );
+        let line = "{\"type\":\"DISCONNECT\",\"payload\":{\"reason\":\"bye\"}}\n";
+        server.write_all(line.repeat(2).as_bytes()).await.unwrap();
+        server.write_all(line.trim_end().as_bytes()).await.unwrap();
+        drop(server);
+
+        for _ in 0..3 {
+            assert!(matches!(
+                queue.recv().await,
+                Some(Message::Core(MineChatMessage::Disconnect { .. }))
+            ));
+        }
+        assert!(queue.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn fills_up_while_the_server_stops_reading() {
+        // Too small for even one message, so the writer blocks on the first one.
+        let (client, mut server) = duplex(8);
+        let (reader, writer) = split(client);
+        let msg = || MineChatMessage::Disconnect {
+            payload: DisconnectPayload {
+                reason: "Client exit".into(),
+            },
+        };
+
+        let queue = MessageQueue::spawn(BufReader::new(reader), writer, 1, 1);
+        queue.send(msg()).await.unwrap();
+        // Once the writer has taken the first message, the second one waits in the queue.
+        queue.ready().await.unwrap();
+        queue.send(msg()).await.unwrap();
+        assert!(queue.is_full());
+
+        let mut buf = [0; 64];
+        while queue.is_full() {
+            assert!(server.read(&mut buf).await.unwrap() > 0);
+        }
+        queue.ready().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn close_sends_queued_messages() {
+        let (client, mut server) = duplex(4096);
+        let (reader, writer) = split(client);
+
+        let queue = MessageQueue::spawn(BufReader::new(reader), writer, 1, QUEUE_CAPACITY);
+        let msg = MineChatMessage::Disconnect {
+            payload: DisconnectPayload {
+                reason: "Client exit".into(),
+            },
+        };
+        queue.send(msg).await.unwrap();
+        queue.close().await.unwrap();
+
+        let mut sent = String::new();
+        server.read_to_string(&mut sent).await.unwrap();
+        assert_eq!(
+            sent,
+            "{\"type\":\"DISCONNECT\",\"payload\":{\"reason\":\"Client exit\"}}\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn batches_chat_messages_ready_together() {
+        let (client, mut server) = duplex(4096);
+        let (reader, writer) = split(client);
+
+        let chat = |message: &str| Extension::Chat {
+            payload: ChatPayload {
+                message: message.into(),
+                message_id: None,
+                signature: None,
+                reply_to: None,
+            },
+        };
+        let queue = MessageQueue::spawn(BufReader::new(reader), writer, 2, QUEUE_CAPACITY);
+        for message in ["a", "b", "c"] {
+            queue.send(chat(message)).await.unwrap();
+        }
+        queue.close().await.unwrap();
+
+        let mut sent = String::new();
+        server.read_to_string(&mut sent).await.unwrap();
+        assert_eq!(
+            sent,
+            "{\"type\":\"BULK_MESSAGE\",\"payload\":{\"messages\":[{\"message\":\"a\"},{\"message\":\"b\"}]}}\n\
+             {\"type\":\"CHAT\",\"payload\":{\"message\":\"c\"}}\n"
+        );
+    }
+}
@@ -0,0 +1,2663 @@
+use crate::{
+    away,
+    commands::{Command, CommandOutput},
+    config, emoji, encoding,
+    events::MineChatEvent,
+    markup, netdiag, pager,
+    pipe_in::PipeIn,
+    protocol::{
+        BroadcastPayload, ChatPayload, Extension, GroupPrivateMessagePayload, JoinPayload,
+        LeaderboardRequestPayload, Message, ReadReceiptPayload, SpamReportPayload, TopicPayload,
+        TopicRequestPayload,
+    },
+    queue::MessageQueue,
+    sanitize,
+    scoreboard::{self, ScoreboardMode},
+    session::{self, HistoryEntry, SentMessage, Session},
+    shell::{self, MultilineStrategy, PromptContext},
+    shutdown,
+    signing::{MessageSigner, MessageVerifier},
+    stats,
+    table::{self, Align},
+    theme::{self, Color, SenderColors, Theme},
+    timestamp,
+};
+use chrono_tz::Tz;
+use encoding_rs::Encoding;
+use log::{debug, trace, warn};
+use minechat_protocol::protocol::{DisconnectPayload, MineChatError, MineChatMessage};
+use std::{
+    borrow::Cow,
+    fmt,
+    fs::File,
+    io::{self, Write},
+    ops::ControlFlow,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite},
+    time::{self, Instant},
+};
+
+/// How long to wait for a delivery acknowledgement before warning that a message may not
+/// have been delivered.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many characters of the original message are quoted above a reply.
+const REPLY_EXCERPT_LEN: usize = 60;
+
+/// How long to wait for the server to close the connection after disconnecting.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How many senders `/stats senders` lists.
+const TOP_SENDERS: usize = 10;
+
+/// How long to collect displayed message IDs before sending a read receipt for all of them.
+const RECEIPT_DELAY: Duration = Duration::from_millis(500);
+
+/// Settings controlling how the REPL handles input and output.
+pub struct ReplOptions {
+    /// Expand `:shortcode:` emoji in outgoing chat messages.
+    pub expand_emoji: bool,
+    /// Text put before every outgoing chat message, followed by a space.
+    pub chat_prefix: Option<String>,
+    /// Collapse runs of whitespace and blank lines in outgoing chat messages.
+    pub normalize_input: bool,
+    /// Show how many words and characters each sent chat message has.
+    pub word_count: bool,
+    /// The automatic reply to private messages while away, with `{from}` and `{time_away}`
+    /// filled in.
+    pub away_reply: String,
+    /// How long to wait before sending the automatic reply to the same user again.
+    pub away_reply_cooldown: Duration,
+    /// How the line breaks of messages written with `/edit` are sent.
+    pub multiline_strategy: MultilineStrategy,
+    /// Remove control characters and ANSI escape sequences from received text.
+    pub strip_formatting: bool,
+    /// Key used to sign outgoing chat messages.
+    pub signer: Option<MessageSigner>,
+    /// Key used to verify signatures on received broadcasts.
+    pub verifier: Option<MessageVerifier>,
+    /// Colors used for displayed text.
+    pub theme: Theme,
+    /// Ask the server to acknowledge sent messages and show how many users received them.
+    pub show_delivery: bool,
+    /// The most chat messages to send together in one bulk message.
+    pub max_batch_size: usize,
+    /// The most messages waiting to be sent before input stops being read.
+    pub max_queue_depth: usize,
+    /// The prompt to keep up to date with the current channel, when editing lines.
+    pub prompt: Option<PromptContext>,
+    /// Lag above which to warn the user.
+    pub maxlag_warn: Option<Duration>,
+    /// Lag above which to disconnect, so the session can reconnect.
+    pub maxlag_disconnect: Option<Duration>,
+    /// The encoding of input other than UTF-8, if any.
+    pub input_encoding: Option<&'static Encoding>,
+    /// Tags of which broadcasts need at least one to be shown, unless empty.
+    pub show_tags: Vec<String>,
+    /// Tags of broadcasts that aren't shown.
+    pub hide_tags: Vec<String>,
+    /// Show the tags of broadcasts after the sender's name.
+    pub show_tag_labels: bool,
+    /// How long the server can send nothing before the session ends to reconnect.
+    pub server_timeout: Option<Duration>,
+    /// Age above which replayed broadcasts aren't shown.
+    pub max_message_age: Option<Duration>,
+    /// The time zone message times are shown in.
+    pub timezone: Tz,
+    /// The strftime format message times are shown in.
+    pub time_format: String,
+    /// How scoreboard updates are shown.
+    pub scoreboard: ScoreboardMode,
+    /// Show the greeting the server sends to new users.
+    pub show_greeting: bool,
+    /// Show long `/history` and `/search` output through a pager.
+    pub pager: bool,
+    /// Only send authentication, chat, ping and disconnect messages.
+    pub safe_mode: bool,
+    /// How sender names are colored with `--color-from`, instead of all in
+    /// `message_from_color`.
+    pub sender_colors: Option<SenderColors>,
+}
+
+impl ReplOptions {
+    /// The color of the name of `sender`, who was the `order`th sender seen if known.
+    fn sender_color(&self, sender: &str, order: Option<usize>) -> Option<Color> {
+        self.sender_colors
+            .as_ref()
+            .and_then(|colors| colors.color(sender, order))
+            .or(self.theme.message_from_color)
+    }
+
+    /// Whether a broadcast with `tags` is filtered out by `show_tags` or `hide_tags`.
+    fn hides_tags(&self, tags: &[String]) -> bool {
+        let tagged = |wanted: &String| tags.iter().any(|tag| tag.eq_ignore_ascii_case(wanted));
+        let shown = self.show_tags.is_empty() || self.show_tags.iter().any(tagged);
+        !shown || self.hide_tags.iter().any(tagged)
+    }
+
+    /// Prepares text received from the server for display.
+    fn clean<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        if self.strip_formatting {
+            Cow::Owned(sanitize::strip_formatting(text))
+        } else {
+            Cow::Borrowed(text)
+        }
+    }
+}
+
+/// How a REPL session ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplExit {
+    /// The user quit.
+    User,
+    /// The server sent a disconnect message.
+    Server { reason: String },
+    /// The connection was closed without a disconnect message.
+    ConnectionLost,
+    /// Broadcasts arrived later than `maxlag_disconnect` allows.
+    Lagging,
+    /// Nothing was received from the server for `server_timeout`.
+    Unresponsive,
+    /// The server announced that it is shutting down, and when it will be back if it is
+    /// restarting.
+    Shutdown { restart_in: Option<Duration> },
+}
+
+impl fmt::Display for ReplExit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplExit::User => write!(f, "quit by the user"),
+            ReplExit::Server { reason } => write!(f, "{}", reason),
+            ReplExit::ConnectionLost => write!(f, "connection lost"),
+            ReplExit::Lagging => write!(f, "server lag above --maxlag-disconnect"),
+            ReplExit::Unresponsive => write!(f, "server unresponsive"),
+            ReplExit::Shutdown { .. } => write!(f, "server shutting down"),
+        }
+    }
+}
+
+/// Runs an interactive session, reading server messages from `reader` and user input from
+/// `input`, sending to the server through `writer` and displaying everything on `out`.
+pub async fn repl<R, W, I, O>(
+    reader: R,
+    writer: W,
+    input: &mut I,
+    out: &mut O,
+    options: &ReplOptions,
+    session: &mut Session,
+) -> Result<ReplExit, MineChatError>
+where
+    R: AsyncBufRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+    I: AsyncBufRead + Unpin,
+    O: Write,
+{
+    session.connected_at = session::unix_now_millis();
+    let mut queue = MessageQueue::spawn(
+        reader,
+        writer,
+        options.max_batch_size,
+        options.max_queue_depth,
+    );
+    session.events.publish(MineChatEvent::Connected {
+        address: session.address.clone(),
+    });
+    let result = run(&mut queue, input, out, options, session).await;
+
+    // An I/O error in the network tasks is the root cause of whatever ended the session.
+    let closed = queue.close().await;
+    let result = match result {
+        Ok(exit) => closed.map(|()| exit),
+        Err(e) => Err(closed.err().unwrap_or(e)),
+    };
+    let event = match &result {
+        Ok(exit) => MineChatEvent::Disconnected(exit.clone()),
+        Err(e) => MineChatEvent::Error(e.to_string()),
+    };
+    session.events.publish(event);
+    result
+}
+
+async fn run<I, O>(
+    queue: &mut MessageQueue,
+    input: &mut I,
+    out: &mut O,
+    options: &ReplOptions,
+    session: &mut Session,
+) -> Result<ReplExit, MineChatError>
+where
+    I: AsyncBufRead + Unpin,
+    O: Write,
+{
+    let mut buffer = Vec::new();
+    let mut pipe_buffer = Vec::new();
+    let mut receipt_deadline = None;
+    let mut last_received = Instant::now();
+
+    loop {
+        let delivery_deadline = session
+            .oldest_undelivered()
+            .filter(|_| options.show_delivery)
+            .map(|sent_at| sent_at + DELIVERY_TIMEOUT);
+        let heartbeat_deadline = session.heartbeat_deadline();
+        let queue_full = queue.is_full();
+        let queue_ready = queue.ready();
+
+        tokio::select! {
+            msg = queue.recv() => {
+                let Some(mut msg) = msg else {
+                    return Ok(ReplExit::ConnectionLost);
+                };
+                last_received = Instant::now();
+                loop {
+                    if let ControlFlow::Break(exit) = receive(out, msg, options, session).await? {
+                        if exit == ReplExit::Lagging {
+                            queue.send(disconnect_message()).await?;
+                        }
+                        return Ok(exit);
+                    }
+                    // Messages often arrive several at a time; show them all before going back
+                    // to waiting on every branch.
+                    match queue.try_recv() {
+                        Some(next) => msg = next,
+                        None => break,
+                    }
+                }
+                for payload in std::mem::take(&mut session.auto_replies) {
+                    if options.safe_mode {
+                        debug!("Safe mode: not sending the away reply to {}", payload.to.join(", "));
+                        continue;
+                    }
+                    queue.send(Extension::GroupPrivateMessage { payload }).await?;
+                }
+                if !session.pending_receipts.is_empty() && receipt_deadline.is_none() {
+                    receipt_deadline = Some(Instant::now() + RECEIPT_DELAY);
+                }
+            }
+            // Input isn't read while the queue is full, so a huge paste waits in the terminal
+            // or pipe instead of in memory.
+            result = input.read_until(b'\n', &mut buffer), if !queue_full => {
+                let n = result?;
+                if n == 0 {
+                    queue.send(disconnect_message()).await?;
+                    break;
+                }
+                let flow = handle_raw_input(queue, out, &buffer, options, session).await;
+                if let Some(prompt) = &options.prompt {
+                    prompt.line_handled();
+                }
+                if flow?.is_break() {
+                    break;
+                }
+                buffer.clear();
+            }
+            result = read_pipe(session.pipe_in.as_mut(), &mut pipe_buffer),
+                if session.pipe_in.is_some() && !queue_full =>
+            {
+                // The pipe stays open for writing by the client itself, so it never ends.
+                result?;
+                if handle_raw_input(queue, out, &pipe_buffer, options, session).await?.is_break() {
+                    break;
+                }
+                pipe_buffer.clear();
+            }
+            message = session.scheduled.next_due() => {
+                // Rather than lose a scheduled message to the spam guard, try it again once
+                // the penalty runs out.
+                if let Some(blocked_for) = send_chat(queue, out, &message, None, options, session).await? {
+                    let id = session.scheduled.schedule(blocked_for, message);
+                    let text = format!(
+                        "Scheduled message #{} will be sent in {}s instead",
+                        id,
+                        blocked_for.as_secs_f64().ceil()
+                    );
+                    writeln!(
+                        out,
+                        "{}",
+                        theme::paint(options.theme.system_message_color, &text)
+                    )?;
+                }
+            }
+            _ = time::sleep_until(receipt_deadline.unwrap_or_else(Instant::now)), if receipt_deadline.is_some() => {
+                receipt_deadline = None;
+                let msg = Extension::ReadReceipt {
+                    payload: ReadReceiptPayload {
+                        message_ids: std::mem::take(&mut session.pending_receipts),
+                    },
+                };
+                if options.safe_mode {
+                    debug!("Safe mode: not sending a read receipt");
+                } else {
+                    queue.send(msg).await?;
+                }
+            }
+            _ = time::sleep_until(delivery_deadline.unwrap_or_else(Instant::now)), if delivery_deadline.is_some() => {
+                flag_undelivered(out, options, session)?;
+            }
+            _ = time::sleep_until(heartbeat_deadline.unwrap_or_else(Instant::now)), if heartbeat_deadline.is_some() => {
+                warn!(
+                    "No heartbeat from the server for {}s",
+                    session.last_heartbeat.map_or(0, |last| last.elapsed().as_secs())
+                );
+                // Warn once, until heartbeats come again.
+                session.last_heartbeat = None;
+            }
+            _ = time::sleep_until(last_received + options.server_timeout.unwrap_or_default()),
+                if options.server_timeout.is_some() =>
+            {
+                let text = format!(
+                    "Server unresponsive for {}s, reconnecting...",
+                    last_received.elapsed().as_secs()
+                );
+                writeln!(out, "{}", theme::paint(options.theme.highlight_color, &text))?;
+                queue.send(disconnect_message()).await?;
+                return Ok(ReplExit::Unresponsive);
+            }
+            result = queue_ready, if queue_full => result?,
+            _ = shutdown::signal() => {
+                queue.send(disconnect_message()).await?;
+                break;
+            }
+        }
+    }
+
+    drain(queue, out, options, session).await?;
+    Ok(ReplExit::User)
+}
+
+/// Shows the messages still arriving after disconnecting, until the server confirms the
+/// disconnect, closes the connection or [`DRAIN_TIMEOUT`] passes.
+async fn drain<O: Write>(
+    queue: &mut MessageQueue,
+    out: &mut O,
+    options: &ReplOptions,
+    session: &mut Session,
+) -> Result<(), MineChatError> {
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    while let Ok(Some(msg)) = time::timeout_at(deadline, queue.recv()).await {
+        if receive(out, msg, options, session).await?.is_break() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Publishes a message received from the server to the session's subscribers, then
+/// displays it.
+async fn receive<O: Write>(
+    out: &mut O,
+    msg: Message,
+    options: &ReplOptions,
+    session: &mut Session,
+) -> Result<ControlFlow<ReplExit>, MineChatError> {
+    let msg = Arc::new(msg);
+    session
+        .events
+        .publish(MineChatEvent::Received(Arc::clone(&msg)));
+    handle_message(out, &msg, options, session).await
+}
+
+/// Displays a message received from the server.
+async fn handle_message<O: Write>(
+    out: &mut O,
+    msg: &Message,
+    options: &ReplOptions,
+    session: &mut Session,
+) -> Result<ControlFlow<ReplExit>, MineChatError> {
+    let stale = match msg {
+        Message::Extension(
+            Extension::Broadcast { payload } | Extension::TaggedBroadcast { payload },
+        ) => is_stale(payload, options, session),
+        _ => false,
+    };
+    if !stale {
+        report_suppressed(out, session)?;
+    }
+    match msg {
+        Message::Extension(
+            Extension::Broadcast { payload } | Extension::TaggedBroadcast { payload },
+        ) if payload
+            .message_id
+            .as_deref()
+            .is_some_and(|id| session.has_seen(id)) =>
+        {
+            debug!("Skipping replayed message {:?}", payload.message_id);
+        }
+        Message::Extension(
+            Extension::Broadcast { payload } | Extension::TaggedBroadcast { payload },
+        ) => {
+            // Muted, filtered and stale messages aren't acknowledged as read, since nobody
+            // saw them.
+            if stale {
+                session.suppressed_old += 1;
+            } else {
+                if !session.hides(payload.channel.as_deref()) && !options.hides_tags(&payload.tags)
+                {
+                    if options
+                        .sender_colors
+                        .as_ref()
+                        .is_some_and(SenderColors::is_cycle)
+                    {
+                        let next = session.sender_order.len();
+                        session
+                            .sender_order
+                            .entry(payload.from.clone())
+                            .or_insert(next);
+                    }
+                    show_broadcast(out, payload, options, session)?;
+                    if let Some(id) = &payload.message_id {
+                        session.pending_receipts.push(id.clone());
+                    }
+                }
+                session.stats.messages_received += 1;
+                session.stats.senders.record(&payload.from);
+            }
+            let lagging = match payload.sent_at {
+                Some(sent_at) => check_lag(out, sent_at, options, session)?,
+                None => false,
+            };
+            let entry = HistoryEntry {
+                message_id: payload.message_id.clone(),
+                from: payload.from.clone(),
+                message: payload.message.clone(),
+                reply_to: payload.reply_to.clone(),
+                sent_at: payload.sent_at,
+                read: false,
+            };
+            if let Some(db) = &session.history_db {
+                let channel = payload.channel.as_deref().or(session.channel.as_deref());
+                let received_at = session::unix_now_millis();
+                // Losing a message from the saved history isn't worth ending the session over.
+                if let Err(e) = db
+                    .insert(&session.address, channel, &entry, received_at)
+                    .await
+                {
+                    warn!("Can't save the message to the history: {}", e);
+                }
+            }
+            session.record(entry);
+            if lagging {
+                return Ok(ControlFlow::Break(ReplExit::Lagging));
+            }
+        }
+        Message::Core(MineChatMessage::Disconnect { payload }) => {
+            let text = format!("Disconnected: {}", options.clean(&payload.reason));
+            writeln!(
+                out,
+                "{}",
+                theme::paint(options.theme.system_message_color, &text)
+            )?;
+            return Ok(ControlFlow::Break(ReplExit::Server {
+                reason: payload.reason.clone(),
+            }));
+        }
+        Message::Extension(Extension::ServerShutdown { payload }) => {
+            let color = options.theme.highlight_color;
+            let text = format!(
+                "*** Server shutting down: {} ***",
+                options.clean(&payload.message)
+            );
+            writeln!(out, "{}", theme::paint(color, &text))?;
+            if let Some(secs) = payload.restart_in_seconds {
+                let text = format!("*** The server will restart in {}s ***", secs);
+                writeln!(out, "{}", theme::paint(color, &text))?;
+            }
+            return Ok(ControlFlow::Break(ReplExit::Shutdown {
+                restart_in: payload.restart_in_seconds.map(Duration::from_secs),
+            }));
+        }
+        Message::Extension(Extension::Greeting { payload }) => {
+            if options.show_greeting && !session.greeted {
+                session.greeted = true;
+                let mut lines: Vec<String> = options
+                    .clean(&payload.welcome_text)
+                    .lines()
+                    .map(str::to_string)
+                    .collect();
+                if let Some(url) = &payload.rules_url {
+                    lines.push(format!("Rules: {}", options.clean(url)));
+                }
+                let text = table::boxed(&lines);
+                write!(
+                    out,
+                    "{}",
+                    theme::paint(options.theme.highlight_color, &text)
+                )?;
+            }
+        }
+        Message::Extension(Extension::NameChange { payload }) => {
+            let text = format!(
+                "* {} is now known as {}",
+                options.clean(&payload.old_username),
+                options.clean(&payload.new_username)
+            );
+            writeln!(
+                out,
+                "{}",
+                theme::paint(options.theme.system_message_color, &text)
+            )?;
+        }
+        Message::Extension(Extension::GroupPrivateMessage { payload }) => {
+            let text = format!(
+                "[GPM from {} to {}] {}",
+                options.clean(payload.from.as_deref().unwrap_or("unknown")),
+                options.clean(&payload.to.join(", ")),
+                options.clean(&payload.message)
+            );
+            writeln!(
+                out,
+                "{}",
+                theme::paint(options.theme.private_message_color, &text)
+            )?;
+            let reply = payload.from.as_deref().and_then(|from| {
+                let template = &options.away_reply;
+                let cooldown = options.away_reply_cooldown;
+                session.away.reply(from, Instant::now(), template, cooldown)
+            });
+            if let (Some(from), Some(message)) = (&payload.from, reply) {
+                let text = format!("Sent an automatic reply to {}", options.clean(from));
+                writeln!(
+                    out,
+                    "{}",
+                    theme::paint(options.theme.system_message_color, &text)
+                )?;
+                session.auto_replies.push(GroupPrivateMessagePayload {
+                    from: None,
+                    to: vec![from.clone()],
+                    message,
+                });
+            }
+        }
+        Message::Extension(Extension::ServerListResponse { payload }) => {
+            if payload.servers.is_empty() {
+                let text = theme::paint(options.theme.system_message_color, "No servers known");
+                writeln!(out, "{}", text)?;
+            } else {
+                let rows: Vec<Vec<String>> = payload
+                    .servers
+                    .iter()
+                    .map(|server| {
+                        vec![
+                            options.clean(&server.name).into_owned(),
+                            options.clean(&server.address).into_owned(),
+                            server.player_count.to_string(),
+                            options.clean(&server.description).into_owned(),
+                        ]
+                    })
+                    .collect();
+                let headers = ["NAME", "ADDRESS", "PLAYERS", "DESCRIPTION"];
+                write!(out, "{}", table::render(&headers, &rows))?;
+            }
+        }
+        Message::Extension(Extension::Leaderboard { payload }) => {
+            let title = options.clean(&payload.title);
+            writeln!(
+                out,
+                "{}",
+                theme::paint(options.theme.system_message_color, &title)
+            )?;
+            let rows: Vec<Vec<String>> = payload
+                .entries
+                .iter()
+                .map(|entry| {
+                    vec![
+                        entry.rank.to_string(),
+                        options.clean(&entry.player).into_owned(),
+                        entry.score.to_string(),
+                    ]
+                })
+                .collect();
+            let aligns = [Align::Right, Align::Left, Align::Right];
+            let table = table::render_aligned(&["RANK", "PLAYER", "SCORE"], &aligns, &rows);
+            write!(out, "{}", table)?;
+        }
+        Message::Extension(Extension::ScoreboardUpdate { payload }) => {
+            let entries: Vec<(String, String)> = payload
+                .entries
+                .iter()
+                .take(scoreboard::MAX_ENTRIES)
+                .map(|(label, value)| {
+                    (
+                        options.clean(label).into_owned(),
+                        options.clean(value).into_owned(),
+                    )
+                })
+                .collect();
+            match options.scoreboard {
+                ScoreboardMode::Off => debug!("Ignoring scoreboard update"),
+                ScoreboardMode::Overlay => {
+                    let width = scoreboard::terminal_width().unwrap_or(80);
+                    let drawn = scoreboard::overlay(&entries, width, session.scoreboard_rows);
+                    write!(out, "{}", drawn)?;
+                    out.flush()?;
+                    session.scoreboard_rows = entries.len();
+                }
+                ScoreboardMode::Inline => {
+                    let title = theme::paint(options.theme.system_message_color, "Scoreboard");
+                    writeln!(out, "{}", title)?;
+                    for (label, value) in &entries {
+                        writeln!(out, "  {}: {}", label, value)?;
+                    }
+                }
+            }
+        }
+        Message::Extension(Extension::ChannelTopicSet { payload }) => {
+            let mut text = format!(
+                "Topic for #{}: {}",
+                options.clean(&payload.channel),
+                options.clean(&payload.topic)
+            );
+            if !payload.set_by.is_empty() {
+                text += &format!(" (set by {})", options.clean(&payload.set_by));
+            }
+            writeln!(
+                out,
+                "{}",
+                theme::paint(options.theme.system_message_color, &text)
+            )?;
+            if let Some(prompt) = &options.prompt {
+                prompt.set_channel(&payload.channel);
+            }
+            session.channel = Some(payload.channel.clone());
+            session.topic = Some(payload.clone());
+        }
+        Message::Extension(Extension::ChannelJoinAck { payload }) => {
+            let channel = options.clean(&payload.channel);
+            let error = options.theme.error_color;
+            let (text, color) = match payload.status.as_str() {
+                "success" => {
+                    if let Some(prompt) = &options.prompt {
+                        prompt.set_channel(&payload.channel);
+                    }
+                    session.channel = Some(payload.channel.clone());
+                    let text = format!("Joined #{}", channel);
+                    (text, options.theme.system_message_color)
+                }
+                "wrong_password" => (format!("Wrong channel password for #{}", channel), error),
+                _ => {
+                    let reason = options.clean(&payload.message);
+                    (format!("Can't join #{}: {}", channel, reason), error)
+                }
+            };
+            writeln!(out, "{}", theme::paint(color, &text))?;
+        }
+        Message::Extension(Extension::SpamReportAck { payload }) => {
+            let id = options.clean(&payload.message_id);
+            let (text, color) = if payload.status == "success" {
+                let text = format!("Reported message #{} to the moderators", id);
+                (text, options.theme.system_message_color)
+            } else {
+                let reason = options.clean(&payload.message);
+                let text = format!("Can't report message #{}: {}", id, reason);
+                (text, options.theme.error_color)
+            };
+            writeln!(out, "{}", theme::paint(color, &text))?;
+        }
+        Message::Extension(Extension::CommandResult { payload }) => {
+            let command = options.clean(&payload.command);
+            let output = options.clean(&payload.output);
+            if payload.success {
+                let text = format!("{}: {}", command, output);
+                writeln!(out, "{}", theme::paint(options.theme.success_color, &text))?;
+            } else {
+                let text = format!("{} failed: {}", command, output);
+                writeln!(out, "{}", theme::paint(options.theme.error_color, &text))?;
+                if let Some(help) = &payload.help {
+                    let text = format!("Usage: {}", options.clean(help));
+                    writeln!(out, "{}", theme::dim(&text))?;
+                }
+            }
+        }
+        Message::Extension(Extension::BroadcastAck { payload }) => {
+            let sent = session.acknowledge(&payload.message_id, payload.delivered_to);
+            match sent {
+                Some(sent) if options.show_delivery => {
+                    let text = format!("Delivered to {}: {}", payload.delivered_to, sent.message);
+                    writeln!(
+                        out,
+                        "{}",
+                        theme::paint(options.theme.system_message_color, &text)
+                    )?;
+                }
+                Some(_) => {}
+                None => debug!("Acknowledgement for unknown message {}", payload.message_id),
+            }
+        }
+        Message::Extension(Extension::ServerHeartbeat { payload }) => {
+            debug!(
+                "Server heartbeat: up {}s, {} clients, {:.1} messages/min",
+                payload.server_uptime_secs, payload.connected_clients, payload.messages_per_minute
+            );
+            let now = Instant::now();
+            if let Some(last) = session.last_heartbeat {
+                session.heartbeat_interval = Some(now - last);
+            }
+            session.last_heartbeat = Some(now);
+            session.stats.heartbeat = Some(payload.clone());
+        }
+        Message::Extension(Extension::Checkpoint { payload }) => {
+            debug!("Reached checkpoint {}", payload.checkpoint_id);
+            session.checkpoint = Some(payload.checkpoint_id.clone());
+        }
+        Message::Extension(Extension::ReadReceipt { payload }) => {
+            debug!("Messages read: {:?}", payload.message_ids);
+            session.mark_read(&payload.message_ids);
+        }
+        Message::Extension(Extension::SessionToken { payload }) => {
+            debug!("Received session token expiring at {}", payload.expires_at);
+            session.token = Some(payload.clone());
+        }
+        Message::Custom {
+            message_type,
+            payload,
+        } => {
+            debug!("Received custom message {}: {}", message_type, payload);
+        }
+        _ => debug!("Received message: {:?}", msg),
+    }
+    Ok(ControlFlow::Continue(()))
+}
+
+/// Reads the next line written to the `--pipe-in` pipe into `buffer`, if there is a pipe.
+async fn read_pipe(pipe: Option<&mut PipeIn>, buffer: &mut Vec<u8>) -> io::Result<usize> {
+    match pipe {
+        Some(pipe) => pipe.reader().read_until(b'\n', buffer).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Handles a line of input that hasn't been decoded yet.
+async fn handle_raw_input<O>(
+    queue: &MessageQueue,
+    out: &mut O,
+    input: &[u8],
+    options: &ReplOptions,
+    session: &mut Session,
+) -> Result<ControlFlow<()>, MineChatError>
+where
+    O: Write,
+{
+    // Pasted binary data would reach the server garbled, so it is dropped instead.
+    match encoding::decode_input(input, options.input_encoding) {
+        Some(line) => handle_input(queue, out, line.trim(), options, session).await,
+        None => {
+            let encoding = options.input_encoding.unwrap_or(encoding_rs::UTF_8);
+            warn!("Discarding input that isn't valid {}", encoding.name());
+            session.stats.invalid_input_count += 1;
+            Ok(ControlFlow::Continue(()))
+        }
+    }
+}
+
+/// Handles a line typed by the user, either running it as slash commands or sending it as chat.
+async fn handle_input<O>(
+    queue: &MessageQueue,
+    out: &mut O,
+    input: &str,
+    options: &ReplOptions,
+    session: &mut Session,
+) -> Result<ControlFlow<()>, MineChatError>
+where
+    O: Write,
+{
+    match Command::parse_pipeline(input) {
+        Some(Ok(pipeline)) => return run_pipeline(queue, out, pipeline, options, session).await,
+        Some(Err(usage)) => {
+            let text = format!("Usage: {}", usage);
+            writeln!(out, "{}", theme::paint(options.theme.error_color, &text))?;
+        }
+        None => {
+            send_chat(queue, out, input, None, options, session).await?;
+        }
+    }
+    Ok(ControlFlow::Continue(()))
+}
+
+/// Sends `text` as a chat message, replying to the message with ID `reply_to` if given,
+/// unless the user is flooding the chat. Returns how long sending is blocked for if the
+/// message wasn't sent because of that.
+async fn send_chat<O: Write>(
+    queue: &MessageQueue,
+    out: &mut O,
+    text: &str,
+    reply_to: Option<String>,
+    options: &ReplOptions,
+    session: &mut Session,
+) -> Result<Option<Duration>, MineChatError> {
+    let text = if options.normalize_input {
+        let normalized = sanitize::normalize_whitespace(text);
+        if normalized != text {
+            trace!("Normalized {:?} to {:?}", text, normalized);
+        }
+        Cow::Owned(normalized)
+    } else {
+        Cow::Borrowed(text)
+    };
+    if let Err(blocked_for) = session.spam_guard.check(&text, Instant::now()) {
+        let text = format!(
+            "Slow down! Sending is blocked for {}s",
+            blocked_for.as_secs_f64().ceil()
+        );
+        writeln!(
+            out,
+            "{}",
+            theme::paint(options.theme.highlight_color, &text)
+        )?;
+        return Ok(Some(blocked_for));
+    }
+    let message = if options.expand_emoji {
+        emoji::expand_shortcodes(&text)
+    } else {
+        text.to_string()
+    };
+    let message = match &options.chat_prefix {
+        Some(prefix) => format!("{} {}", prefix, message),
+        None => message,
+    };
+    let signature = options.signer.as_ref().map(|s| s.sign(&message));
+    let message_id = options.show_delivery.then(|| session.next_message_id());
+    if let Some(id) = &message_id {
+        session.record_sent(SentMessage {
+            message_id: id.clone(),
+            message: message.clone(),
+            sent_at: Instant::now(),
+            delivered_to: None,
+            flagged: false,
+        });
+    }
+    let msg = Extension::Chat {
+        payload: ChatPayload {
+            message,
+            message_id,
+            signature,
+            reply_to,
+        },
+    };
+    queue.send(msg).await?;
+    session.stats.messages_sent += 1;
+    let words = text.split_whitespace().count();
+    session.stats.words_typed += words as u64;
+    if options.word_count {
+        let chars = text.chars().count();
+        let text = format!(
+            "({} word{}, {} char{})",
+            words,
+            if words == 1 { "" } else { "s" },
+            chars,
+            if chars == 1 { "" } else { "s" }
+        );
+        writeln!(out, "{}", theme::dim(&text))?;
+    }
+    Ok(None)
+}
+
+/// Queues `msg` sent by a slash command, unless `safe_mode` doesn't allow its type, which
+/// the user is warned about instead.
+async fn send_command_message<O: Write>(
+    queue: &MessageQueue,
+    out: &mut O,
+    msg: impl Into<Message>,
+    options: &ReplOptions,
+) -> Result<(), MineChatError> {
+    let msg = msg.into();
+    if options.safe_mode && !msg.is_safe() {
+        let text = format!("Safe mode: not sending a {} message", msg.message_type());
+        writeln!(
+            out,
+            "{}",
+            theme::paint(options.theme.highlight_color, &text)
+        )?;
+        return Ok(());
+    }
+    queue.send(msg).await
+}
+
+/// Runs slash commands in order, piping the lines each one outputs into the next and showing
+/// the output of the last.
+async fn run_pipeline<O>(
+    queue: &MessageQueue,
+    out: &mut O,
+    pipeline: Vec<Command>,
+    options: &ReplOptions,
+    session: &mut Session,
+) -> Result<ControlFlow<()>, MineChatError>
+where
+    O: Write,
+{
+    if pipeline
+        .windows(2)
+        .any(|pair| !pair[0].writes_lines() || !pair[1].reads_input())
+    {
+        let text = "Only /history and /search can be piped, and only into /export";
+        writeln!(out, "{}", theme::paint(options.theme.error_color, text))?;
+        return Ok(ControlFlow::Continue(()));
+    }
+
+    let mut piped = None;
+    for command in pipeline {
+        match run_command(queue, out, command, piped.take(), options, session).await? {
+            ControlFlow::Break(()) => return Ok(ControlFlow::Break(())),
+            ControlFlow::Continue(CommandOutput::Lines(lines)) => piped = Some(lines),
+            ControlFlow::Continue(CommandOutput::Done) => {}
+        }
+    }
+    let lines = piped.unwrap_or_default();
+    let too_long = scoreboard::terminal_height().is_some_and(|height| lines.len() >= height);
+    if options.pager && too_long {
+        match pager::page(&lines).await {
+            Ok(()) => return Ok(ControlFlow::Continue(())),
+            Err(e) => warn!("Can't start the pager: {}", e),
+        }
+    }
+    for line in lines {
+        writeln!(out, "{}", line)?;
+    }
+    Ok(ControlFlow::Continue(()))
+}
+
+/// Runs a slash command, with `input` holding the lines piped into it, if any.
+async fn run_command<O>(
+    queue: &MessageQueue,
+    out: &mut O,
+    command: Command,
+    input: Option<Vec<String>>,
+    options: &ReplOptions,
+    session: &mut Session,
+) -> Result<ControlFlow<(), CommandOutput>, MineChatError>
+where
+    O: Write,
+{
+    match command {
+        Command::Exit => {
+            queue.send(disconnect_message()).await?;
+            return Ok(ControlFlow::Break(()));
+        }
+        Command::GroupPrivateMessage { to, message } => {
+            let msg = Extension::GroupPrivateMessage {
+                payload: GroupPrivateMessagePayload {
+                    from: None,
+                    to,
+                    message,
+                },
+            };
+            send_command_message(queue, out, msg, options).await?;
+        }
+        Command::History { threaded: false } => {
+            let history = match saved_history(session, None).await {
+                Ok(history) => history,
+                Err(e) => return history_error(out, e, options),
+            };
+            let lines = history
+                .iter()
+                .map(|entry| history_line(entry, options))
+                .collect();
+            return Ok(ControlFlow::Continue(CommandOutput::Lines(lines)));
+        }
+        Command::History { threaded: true } => {
+            let history = match saved_history(session, None).await {
+                Ok(history) => history,
+                Err(e) => return history_error(out, e, options),
+            };
+            let mut lines = Vec::new();
+            for (i, entry) in history.iter().enumerate() {
+                let is_root = entry.reply_to.as_ref().is_none_or(|id| {
+                    !history[..i]
+                        .iter()
+                        .any(|e| e.message_id.as_ref() == Some(id))
+                });
+                if is_root {
+                    thread_lines(&history, i, 0, options, &mut lines);
+                }
+            }
+            return Ok(ControlFlow::Continue(CommandOutput::Lines(lines)));
+        }
+        Command::Reply {
+            message_id,
+            message,
+        } => {
+            send_chat(queue, out, &message, Some(message_id), options, session).await?;
+        }
+        Command::Dice { dice } => {
+            let roll = dice.roll(&mut rand::rng());
+            let message = format!("[dice] {}", roll);
+            session.dice_rolls.push(roll);
+            send_chat(queue, out, &message, None, options, session).await?;
+        }
+        Command::DiceHistory => {
+            if session.dice_rolls.is_empty() {
+                let text = "No dice rolled yet";
+                writeln!(
+                    out,
+                    "{}",
+                    theme::paint(options.theme.system_message_color, text)
+                )?;
+                return Ok(ControlFlow::Continue(CommandOutput::Done));
+            }
+            return Ok(ControlFlow::Continue(CommandOutput::Lines(
+                session.dice_rolls.clone(),
+            )));
+        }
+        Command::Report { message_id, reason } => {
+            let msg = Extension::SpamReport {
+                payload: SpamReportPayload { message_id, reason },
+            };
+            send_command_message(queue, out, msg, options).await?;
+        }
+        Command::Search { text } => {
+            let history = match saved_history(session, Some(&text)).await {
+                Ok(history) => history,
+                Err(e) => return history_error(out, e, options),
+            };
+            let lines = history
+                .iter()
+                .map(|entry| history_line(entry, options))
+                .collect();
+            return Ok(ControlFlow::Continue(CommandOutput::Lines(lines)));
+        }
+        Command::Export { path } => {
+            let lines = match input {
+                Some(lines) => lines,
+                None => match saved_history(session, None).await {
+                    Ok(history) => history
+                        .iter()
+                        .map(|entry| history_line(entry, options))
+                        .collect(),
+                    Err(e) => return history_error(out, e, options),
+                },
+            };
+            // A bad path shouldn't end the session like an I/O error on the connection.
+            if let Err(e) = export(&path, &lines) {
+                let text = format!("Can't export to {}: {}", path, e);
+                writeln!(out, "{}", theme::paint(options.theme.error_color, &text))?;
+                return Ok(ControlFlow::Continue(CommandOutput::Done));
+            }
+            let text = format!("Exported {} lines to {}", lines.len(), path);
+            writeln!(
+                out,
+                "{}",
+                theme::paint(options.theme.system_message_color, &text)
+            )?;
+        }
+        Command::Leaderboard { category } => {
+            let msg = Extension::LeaderboardRequest {
+                payload: LeaderboardRequestPayload { category },
+            };
+            send_command_message(queue, out, msg, options).await?;
+        }
+        Command::Mute | Command::Unmute => {
+            session.muted = matches!(command, Command::Mute);
+            let text = if session.muted {
+                "Muted received messages; /unmute to show them again"
+            } else {
+                "Showing received messages"
+            };
+            writeln!(
+                out,
+                "{}",
+                theme::paint(options.theme.system_message_color, text)
+            )?;
+        }
+        Command::MuteChannel { channel } => {
+            let text = format!(
+                "Muted #{}; /unmute-channel #{} to show it again",
+                channel, channel
+            );
+            session.muted_channels.insert(channel);
+            writeln!(
+                out,
+                "{}",
+                theme::paint(options.theme.system_message_color, &text)
+            )?;
+        }
+        Command::UnmuteChannel { channel } => {
+            let text = if session.muted_channels.remove(&channel) {
+                format!("Showing messages from #{}", channel)
+            } else {
+                format!("#{} isn't muted", channel)
+            };
+            writeln!(
+                out,
+                "{}",
+                theme::paint(options.theme.system_message_color, &text)
+            )?;
+        }
+        Command::Muted => {
+            let mut lines = Vec::new();
+            if session.muted {
+                lines.push("All received messages are muted".to_string());
+            }
+            if session.muted_channels.is_empty() {
+                lines.push("No muted channels".to_string());
+            } else {
+                let channels: Vec<String> = session
+                    .muted_channels
+                    .iter()
+                    .map(|channel| format!("#{}", channel))
+                    .collect();
+                lines.push(format!("Muted channels: {}", channels.join(", ")));
+            }
+            for line in lines {
+                writeln!(
+                    out,
+                    "{}",
+                    theme::paint(options.theme.system_message_color, &line)
+                )?;
+            }
+        }
+        Command::NetDiag => match &session.socket {
+            // The socket may be half closed, which shouldn't end the session.
+            Some(socket) => match netdiag::report(socket) {
+                Ok(report) => write!(out, "{}", report)?,
+                Err(e) => {
+                    let text = format!("Can't read the socket information: {}", e);
+                    writeln!(out, "{}", theme::paint(options.theme.error_color, &text))?;
+                }
+            },
+            None => {
+                let text = "No socket information available";
+                writeln!(
+                    out,
+                    "{}",
+                    theme::paint(options.theme.system_message_color, text)
+                )?;
+            }
+        },
+        Command::Stats => {
+            let connected_at = timestamp::date_time(session.connected_at, options.timezone);
+            write!(out, "{}", stats::report(&session.stats, &connected_at))?;
+        }
+        Command::StatsSender { name } => {
+            let count = session.stats.senders.count(&name);
+            let text = format!(
+                "{} sent {} message{} since connecting",
+                name,
+                count,
+                if count == 1 { "" } else { "s" }
+            );
+            writeln!(
+                out,
+                "{}",
+                theme::paint(options.theme.system_message_color, &text)
+            )?;
+        }
+        Command::StatsSenders => {
+            let top = session.stats.senders.top(TOP_SENDERS);
+            if top.is_empty() {
+                let text = "No messages received since connecting";
+                writeln!(
+                    out,
+                    "{}",
+                    theme::paint(options.theme.system_message_color, text)
+                )?;
+                return Ok(ControlFlow::Continue(CommandOutput::Done));
+            }
+            let rows: Vec<Vec<String>> = top
+                .into_iter()
+                .map(|(sender, count)| vec![options.clean(sender).into_owned(), count.to_string()])
+                .collect();
+            let aligns = [Align::Left, Align::Right];
+            let table = table::render_aligned(&["SENDER", "MESSAGES"], &aligns, &rows);
+            write!(out, "{}", table)?;
+        }
+        Command::StatsReset => {
+            session.stats.senders.reset();
+            let text = "Reset the message counts of every sender";
+            writeln!(
+                out,
+                "{}",
+                theme::paint(options.theme.system_message_color, text)
+            )?;
+        }
+        Command::Servers => {
+            send_command_message(queue, out, Extension::ServerListRequest, options).await?;
+        }
+        Command::Rename { alias } => {
+            // The server may only be in the system config or reached with --uuid-override.
+            let text = match config::set_alias(&session.address, &alias) {
+                Ok(()) => {
+                    if let Some(prompt) = &options.prompt {
+                        prompt.set_server(&alias);
+                    }
+                    format!("Renamed {} to {}", session.address, alias)
+                }
+                Err(e) => {
+                    let text = match e {
+                        MineChatError::ServerNotLinked => {
+                            format!(
+                                "Can't rename {}: server isn't in your config",
+                                session.address
+                            )
+                        }
+                        e => format!("Can't rename {}: {}", session.address, e),
+                    };
+                    writeln!(out, "{}", theme::paint(options.theme.error_color, &text))?;
+                    return Ok(ControlFlow::Continue(CommandOutput::Done));
+                }
+            };
+            writeln!(
+                out,
+                "{}",
+                theme::paint(options.theme.system_message_color, &text)
+            )?;
+        }
+        Command::Join { channel, password } => {
+            let msg = Extension::ChannelJoin {
+                payload: JoinPayload { channel, password },
+            };
+            send_command_message(queue, out, msg, options).await?;
+        }
+        Command::Away => {
+            session.away.start(Instant::now());
+            let text = "You are away; private messages get an automatic reply until /back";
+            writeln!(
+                out,
+                "{}",
+                theme::paint(options.theme.system_message_color, text)
+            )?;
+        }
+        Command::Back => {
+            let text = match session.away.stop(Instant::now()) {
+                Some(away_for) => format!(
+                    "Welcome back, you were away for {}",
+                    away::format_duration(away_for)
+                ),
+                None => "You weren't away".to_string(),
+            };
+            writeln!(
+                out,
+                "{}",
+                theme::paint(options.theme.system_message_color, &text)
+            )?;
+        }
+        Command::Schedule { delay, message } => {
+            let id = session.scheduled.schedule(delay, message);
+            let text = format!(
+                "Scheduled message #{} to be sent in {}s",
+                id,
+                delay.as_secs()
+            );
+            writeln!(
+                out,
+                "{}",
+                theme::paint(options.theme.system_message_color, &text)
+            )?;
+        }
+        Command::Pending => {
+            let pending = session.scheduled.pending();
+            if pending.is_empty() {
+                let text = "No scheduled messages";
+                writeln!(
+                    out,
+                    "{}",
+                    theme::paint(options.theme.system_message_color, text)
+                )?;
+                return Ok(ControlFlow::Continue(CommandOutput::Done));
+            }
+            let now = Instant::now();
+            let rows: Vec<Vec<String>> = pending
+                .iter()
+                .map(|(id, scheduled)| {
+                    let remaining = scheduled.due.saturating_duration_since(now);
+                    vec![
+                        id.to_string(),
+                        format!("{}s", remaining.as_secs_f64().ceil()),
+                        options.clean(&scheduled.message).into_owned(),
+                    ]
+                })
+                .collect();
+            write!(out, "{}", table::render(&["ID", "IN", "MESSAGE"], &rows))?;
+        }
+        Command::Cancel { id } => {
+            let text = if session.scheduled.cancel(id) {
+                format!("Cancelled scheduled message #{}", id)
+            } else {
+                format!("No scheduled message #{}", id)
+            };
+            writeln!(
+                out,
+                "{}",
+                theme::paint(options.theme.system_message_color, &text)
+            )?;
+        }
+        Command::Edit { text } => {
+            // The editor needs the terminal, which only the line editor has set up.
+            if options.prompt.is_none() {
+                let text = "/edit needs a terminal";
+                writeln!(out, "{}", theme::paint(options.theme.error_color, text))?;
+                return Ok(ControlFlow::Continue(CommandOutput::Done));
+            }
+            match shell::edit_in_editor(&text).await {
+                Ok(saved) => {
+                    if let Some(message) = options.multiline_strategy.apply(&saved) {
+                        send_chat(queue, out, &message, None, options, session).await?;
+                    }
+                }
+                Err(e) => {
+                    let text = format!("Can't open the editor: {}", e);
+                    writeln!(out, "{}", theme::paint(options.theme.error_color, &text))?;
+                }
+            }
+        }
+        Command::Topic { topic } => {
+            let Some(current) = &session.topic else {
+                let text = "Not in a channel";
+                writeln!(
+                    out,
+                    "{}",
+                    theme::paint(options.theme.system_message_color, text)
+                )?;
+                return Ok(ControlFlow::Continue(CommandOutput::Done));
+            };
+            let channel = current.channel.clone();
+            match topic {
+                Some(topic) => {
+                    let msg = Extension::ChannelTopicSet {
+                        payload: TopicPayload {
+                            channel,
+                            topic,
+                            set_by: String::new(),
+                        },
+                    };
+                    send_command_message(queue, out, msg, options).await?;
+                }
+                None => {
+                    let msg = Extension::ChannelTopicGet {
+                        payload: TopicRequestPayload { channel },
+                    };
+                    send_command_message(queue, out, msg, options).await?;
+                }
+            }
+        }
+    }
+    Ok(ControlFlow::Continue(CommandOutput::Done))
+}
+
+/// Formats the line quoted above a reply to the message with ID `reply_to`, with the
+/// beginning of that message if it is still in the history.
+fn reply_quote(reply_to: &str, options: &ReplOptions, session: &Session) -> String {
+    let quote = match session.find(reply_to) {
+        Some(original) => {
+            let mut excerpt: String = original.message.chars().take(REPLY_EXCERPT_LEN).collect();
+            if excerpt.len() < original.message.len() {
+                excerpt.truncate(excerpt.trim_end().len());
+                excerpt.push('…');
+            }
+            format!("│ {}: {}", original.from, excerpt)
+        }
+        None => "│ [original message not available]".to_string(),
+    };
+    theme::dim(&options.clean(&quote))
+}
+
+/// Displays a broadcast with its sending time and signature check, below a quote of the
+/// message it replies to, if any.
+fn show_broadcast<O: Write>(
+    out: &mut O,
+    payload: &BroadcastPayload,
+    options: &ReplOptions,
+    session: &Session,
+) -> io::Result<()> {
+    let mark = match &options.verifier {
+        Some(verifier) if verifier.verify(&payload.message, payload.signature.as_deref()) => "✓ ",
+        Some(_) => &theme::paint(options.theme.error_color, "? "),
+        None => "",
+    };
+    if let Some(reply_to) = &payload.reply_to {
+        writeln!(out, "{}", reply_quote(reply_to, options, session))?;
+    }
+    let from = format!("[{}]", options.clean(&payload.from));
+    let time = payload
+        .sent_at
+        .map_or(String::new(), |sent_at| time_prefix(sent_at, options));
+    let tags = if options.show_tag_labels && !payload.tags.is_empty() {
+        let labels = format!("[{}]", options.clean(&payload.tags.join(", ")));
+        format!(" {}", theme::dim(&labels))
+    } else {
+        String::new()
+    };
+    writeln!(
+        out,
+        "{}{}{}{} {}",
+        time,
+        mark,
+        theme::paint(
+            options.sender_color(
+                &payload.from,
+                session.sender_order.get(&payload.from).copied()
+            ),
+            &from
+        ),
+        tags,
+        markup::render_code(&options.clean(&payload.message), &options.theme)
+    )
+}
+
+/// Formats the time a message was sent, to show before it.
+fn time_prefix(sent_at: u64, options: &ReplOptions) -> String {
+    let time = format!(
+        "[{}]",
+        timestamp::time_with_format(sent_at, options.timezone, &options.time_format,)
+    );
+    format!("{} ", theme::paint(options.theme.timestamp_color, &time))
+}
+
+/// Returns the last messages received from the server, oldest first, only those whose
+/// sender or text contains `text` if given. Messages from earlier sessions are included
+/// when the history database is open.
+async fn saved_history(
+    session: &Session,
+    text: Option<&str>,
+) -> Result<Vec<HistoryEntry>, MineChatError> {
+    let Some(db) = &session.history_db else {
+        let text = text.map(str::to_lowercase);
+        let entries = session.history.iter().filter(|entry| {
+            text.as_ref().is_none_or(|text| {
+                entry.from.to_lowercase().contains(text)
+                    || entry.message.to_lowercase().contains(text)
+            })
+        });
+        return Ok(entries.cloned().collect());
+    };
+    let stored = db
+        .recent(&session.address, text, session::HISTORY_LIMIT)
+        .await?;
+    let entries = stored.into_iter().map(|stored| {
+        let mut entry = HistoryEntry::from(stored);
+        // Read receipts are only tracked for messages received in this session.
+        entry.read = entry
+            .message_id
+            .as_ref()
+            .and_then(|id| session.find(id))
+            .is_some_and(|seen| seen.read);
+        entry
+    });
+    Ok(entries.collect())
+}
+
+/// Reports that the history database couldn't be read, leaving the session running.
+fn history_error<O: Write>(
+    out: &mut O,
+    error: MineChatError,
+    options: &ReplOptions,
+) -> Result<ControlFlow<(), CommandOutput>, MineChatError> {
+    let text = format!("Can't read the message history: {}", error);
+    writeln!(out, "{}", theme::paint(options.theme.error_color, &text))?;
+    Ok(ControlFlow::Continue(CommandOutput::Done))
+}
+
+/// Writes `lines` to a new file at `path`, without formatting codes, for `/export`.
+fn export(path: &str, lines: &[String]) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    for line in lines {
+        writeln!(file, "{}", sanitize::strip_formatting(line))?;
+    }
+    Ok(())
+}
+
+/// Formats a history entry the way `/history` shows it.
+fn history_line(entry: &HistoryEntry, options: &ReplOptions) -> String {
+    // Messages with an ID were acknowledged when displayed; a second mark means the server
+    // reported them as read.
+    let mark = match (&entry.message_id, entry.read) {
+        (Some(_), true) => " ✓✓",
+        (Some(_), false) => " ✓",
+        (None, _) => "",
+    };
+    let from = format!("[{}]", options.clean(&entry.from));
+    // Shown so the message can be replied to with /reply or reported with /report.
+    let id = entry
+        .message_id
+        .as_ref()
+        .map_or(String::new(), |id| format!("#{} ", options.clean(id)));
+    let time = entry
+        .sent_at
+        .map_or(String::new(), |sent_at| time_prefix(sent_at, options));
+    format!(
+        "{}{}{} {}{}",
+        time,
+        id,
+        theme::paint(options.sender_color(&entry.from, None), &from),
+        options.clean(&entry.message),
+        mark
+    )
+}
+
+/// Adds the history entry at `index` to `lines`, followed by the replies to it, each
+/// indented one level further than the message it replies to.
+fn thread_lines(
+    history: &[HistoryEntry],
+    index: usize,
+    depth: usize,
+    options: &ReplOptions,
+    lines: &mut Vec<String>,
+) {
+    let entry = &history[index];
+    lines.push(format!(
+        "{}{}",
+        "  ".repeat(depth),
+        history_line(entry, options)
+    ));
+    let Some(id) = &entry.message_id else {
+        return;
+    };
+    // Replies always come after the message they reply to, which also rules out cycles.
+    for (i, reply) in history.iter().enumerate().skip(index + 1) {
+        if reply.reply_to.as_ref() == Some(id) {
+            thread_lines(history, i, depth + 1, options, lines);
+        }
+    }
+}
+
+/// Whether `payload` was replayed from before connecting and is older than
+/// `max_message_age`.
+fn is_stale(payload: &BroadcastPayload, options: &ReplOptions, session: &Session) -> bool {
+    let (Some(sent_at), Some(max)) = (payload.sent_at, options.max_message_age) else {
+        return false;
+    };
+    let age = Duration::from_millis(session::unix_now_millis().saturating_sub(sent_at));
+    sent_at < session.connected_at && age > max
+}
+
+/// Tells the user how many stale broadcasts were left out since the last report, if any.
+fn report_suppressed<O: Write>(out: &mut O, session: &mut Session) -> io::Result<()> {
+    let count = std::mem::take(&mut session.suppressed_old);
+    if count > 0 {
+        let text = format!(
+            "({} old message{} suppressed)",
+            count,
+            if count == 1 { "" } else { "s" }
+        );
+        writeln!(out, "{}", theme::dim(&text))?;
+    }
+    Ok(())
+}
+
+/// Records the lag of a broadcast sent at `sent_at` and warns once it exceeds
+/// `maxlag_warn`. Returns whether it exceeds `maxlag_disconnect`.
+fn check_lag<O: Write>(
+    out: &mut O,
+    sent_at: u64,
+    options: &ReplOptions,
+    session: &mut Session,
+) -> io::Result<bool> {
+    // Messages sent before connecting, such as replays, say nothing about the connection.
+    if sent_at < session.connected_at {
+        return Ok(false);
+    }
+    let lag = Duration::from_millis(session::unix_now_millis().saturating_sub(sent_at));
+    let previous = session.stats.lag.replace(lag);
+
+    if let Some(max) = options.maxlag_warn
+        && lag > max
+        && previous.is_none_or(|previous| previous <= max)
+    {
+        let text = format!(
+            "Lag is {} ms, above {} ms",
+            lag.as_millis(),
+            max.as_millis()
+        );
+        writeln!(
+            out,
+            "{}",
+            theme::paint(options.theme.highlight_color, &text)
+        )?;
+    }
+    Ok(options.maxlag_disconnect.is_some_and(|max| lag > max))
+}
+
+/// Warns about sent messages that weren't acknowledged within [`DELIVERY_TIMEOUT`].
+fn flag_undelivered<O: Write>(
+    out: &mut O,
+    options: &ReplOptions,
+    session: &mut Session,
+) -> io::Result<()> {
+    let now = Instant::now();
+    for sent in &mut session.sent {
+        if sent.delivered_to.is_none() && !sent.flagged && sent.sent_at + DELIVERY_TIMEOUT <= now {
+            sent.flagged = true;
+            let text = format!("Possibly undelivered: {}", sent.message);
+            writeln!(out, "{}", theme::paint(options.theme.error_color, &text))?;
+        }
+    }
+    Ok(())
+}
+
+/// The message telling the server the client is leaving.
+pub fn disconnect_message() -> MineChatMessage {
+    MineChatMessage::Disconnect {
+        payload: DisconnectPayload {
+            reason: "Client exit".into(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        history::HistoryDb,
+        protocol::{
+            CommandResultPayload, GreetingPayload, HeartbeatPayload, NameChangePayload,
+            ServerShutdownPayload,
+        },
+        spam::SpamGuard,
+    };
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream, duplex, split},
+        task::JoinHandle,
+    };
+
+    fn options() -> ReplOptions {
+        ReplOptions {
+            expand_emoji: false,
+            normalize_input: true,
+            strip_formatting: false,
+            signer: None,
+            verifier: None,
+            theme: Theme::default(),
+            show_delivery: false,
+            max_batch_size: 1,
+            max_queue_depth: 50,
+            prompt: None,
+            maxlag_warn: None,
+            maxlag_disconnect: None,
+            max_message_age: None,
+            server_timeout: None,
+            show_tags: Vec::new(),
+            hide_tags: Vec::new(),
+            show_tag_labels: false,
+            input_encoding: None,
+            timezone: Tz::UTC,
+            time_format: timestamp::DEFAULT_TIME_FORMAT.into(),
+            scoreboard: ScoreboardMode::Inline,
+            pager: false,
+            chat_prefix: None,
+            word_count: false,
+            away_reply: crate::away::DEFAULT_REPLY.into(),
+            away_reply_cooldown: Duration::from_secs(60),
+            multiline_strategy: MultilineStrategy::Collapse,
+            show_greeting: true,
+            safe_mode: false,
+            sender_colors: None,
+        }
+    }
+
+    fn line(msg: &impl serde::Serialize) -> String {
+        serde_json::to_string(msg).unwrap() + "\n"
+    }
+
+    #[tokio::test]
+    async fn displays_broadcasts_until_server_disconnects() {
+        let (client, mut server) = duplex(4096);
+        let (reader, writer) = split(client);
+        // Keep the other end open so user input never reaches EOF.
+        let (input, _input_tx) = duplex(64);
+
+        let incoming = line(&Extension::Broadcast {
+            payload: BroadcastPayload {
+                from: "alice".into(),
+                message: "hi".into(),
+                message_id: None,
+                signature: None,
+                sent_at: None,
+                reply_to: None,
+                channel: None,
+                tags: Vec::new(),
+            },
+        }) + &line(&MineChatMessage::Disconnect {
+            payload: DisconnectPayload {
+                reason: "Server closing".into(),
+            },
+        });
+        server.write_all(incoming.as_bytes()).await.unwrap();
+
+        let mut out = Vec::new();
+        let exit = repl(
+            BufReader::new(reader),
+            writer,
+            &mut BufReader::new(input),
+            &mut out,
+            &options(),
+            &mut Session::new("localhost:25575".into()),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            exit,
+            ReplExit::Server {
+                reason: "Server closing".into()
+            }
+        );
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "[alice] hi\nDisconnected: Server closing\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_a_silent_server() {
+        let (client, mut server) = duplex(4096);
+        let (reader, writer) = split(client);
+        let (input, _input_tx) = duplex(64);
+        let options = ReplOptions {
+            server_timeout: Some(Duration::from_millis(50)),
+            ..options()
+        };
+
+        let mut out = Vec::new();
+        let exit = repl(
+            BufReader::new(reader),
+            writer,
+            &mut BufReader::new(input),
+            &mut out,
+            &options,
+            &mut Session::new("localhost:25575".into()),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(exit, ReplExit::Unresponsive);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Server unresponsive for 0s, reconnecting...\n"
+        );
+        let mut sent = String::new();
+        BufReader::new(&mut server)
+            .read_line(&mut sent)
+            .await
+            .unwrap();
+        assert!(sent.contains("DISCONNECT"));
+    }
+
+    #[tokio::test]
+    async fn skips_replayed_broadcasts() {
+        let (client, mut server) = duplex(4096);
+        let (reader, writer) = split(client);
+        let (input, _input_tx) = duplex(64);
+
+        let broadcast = line(&Extension::Broadcast {
+            payload: BroadcastPayload {
+                from: "alice".into(),
+                message: "hi".into(),
+                message_id: Some("7".into()),
+                signature: None,
+                sent_at: None,
+                reply_to: None,
+                channel: None,
+                tags: Vec::new(),
+            },
+        });
+        let incoming = broadcast.clone()
+            + &broadcast
+            + &line(&MineChatMessage::Disconnect {
+                payload: DisconnectPayload {
+                    reason: "Server closing".into(),
+                },
+            });
+        server.write_all(incoming.as_bytes()).await.unwrap();
+
+        let mut out = Vec::new();
+        let mut session = Session::new("localhost:25575".into());
+        repl(
+            BufReader::new(reader),
+            writer,
+            &mut BufReader::new(input),
+            &mut out,
+            &options(),
+            &mut session,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "[alice] hi\nDisconnected: Server closing\n"
+        );
+        assert_eq!(session.history.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn suppresses_old_replayed_broadcasts() {
+        let mut session = Session::new("localhost:25575".into());
+        session.connected_at = session::unix_now_millis();
+        let options = ReplOptions {
+            max_message_age: Some(Duration::from_secs(300)),
+            ..options()
+        };
+        let broadcast = |message: &str, age: Duration| {
+            Message::Extension(Extension::Broadcast {
+                payload: BroadcastPayload {
+                    from: "alice".into(),
+                    message: message.into(),
+                    message_id: None,
+                    signature: None,
+                    sent_at: Some(session.connected_at - age.as_millis() as u64),
+                    reply_to: None,
+                    channel: None,
+                    tags: Vec::new(),
+                },
+            })
+        };
+        let messages = [
+            broadcast("ancient", Duration::from_secs(600)),
+            broadcast("old", Duration::from_secs(301)),
+            broadcast("recent", Duration::from_secs(60)),
+        ];
+
+        let mut out = Vec::new();
+        for msg in messages {
+            let flow = handle_message(&mut out, &msg, &options, &mut session).await;
+            assert!(flow.unwrap().is_continue());
+        }
+
+        let out = String::from_utf8(out).unwrap();
+        let (note, shown) = out.split_once('\n').unwrap();
+        assert_eq!(note, theme::dim("(2 old messages suppressed)"));
+        // Only the recent message is shown, after its time.
+        assert!(shown.ends_with("] [alice] recent\n"));
+        assert_eq!(shown.lines().count(), 1);
+        // Suppressed messages are still kept in the history.
+        assert_eq!(session.history.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn shows_name_changes() {
+        let msg = Message::Extension(Extension::NameChange {
+            payload: NameChangePayload {
+                old_username: "Steve".into(),
+                new_username: "Alex".into(),
+            },
+        });
+        let mut out = Vec::new();
+        let mut session = Session::new("localhost:25575".into());
+        let flow = handle_message(&mut out, &msg, &options(), &mut session).await;
+
+        assert!(flow.unwrap().is_continue());
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "* Steve is now known as Alex\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn learns_the_heartbeat_interval() {
+        let heartbeat = |connected_clients| {
+            Message::Extension(Extension::ServerHeartbeat {
+                payload: HeartbeatPayload {
+                    server_uptime_secs: 60,
+                    connected_clients,
+                    messages_per_minute: 2.5,
+                },
+            })
+        };
+        let mut out = Vec::new();
+        let mut session = Session::new("localhost:25575".into());
+
+        let flow = handle_message(&mut out, &heartbeat(3), &options(), &mut session).await;
+        assert!(flow.unwrap().is_continue());
+        assert!(session.heartbeat_deadline().is_none());
+        time::sleep(Duration::from_millis(20)).await;
+        let flow = handle_message(&mut out, &heartbeat(4), &options(), &mut session).await;
+        assert!(flow.unwrap().is_continue());
+
+        let interval = session.heartbeat_interval.unwrap();
+        assert!(interval >= Duration::from_millis(20));
+        assert_eq!(
+            session.heartbeat_deadline(),
+            Some(session.last_heartbeat.unwrap() + 2 * interval)
+        );
+        assert_eq!(session.stats.heartbeat.unwrap().connected_clients, 4);
+        assert!(out.is_empty());
+    }
+
+    #[tokio::test]
+    async fn colors_command_results_by_outcome() {
+        let result = |success: bool, output: &str, help: Option<&str>| {
+            Message::Extension(Extension::CommandResult {
+                payload: CommandResultPayload {
+                    command: "/ban griefer".into(),
+                    success,
+                    output: output.into(),
+                    help: help.map(str::to_string),
+                },
+            })
+        };
+        let options = ReplOptions {
+            theme: Theme {
+                success_color: "green".parse().ok(),
+                error_color: "red".parse().ok(),
+                ..Theme::default()
+            },
+            ..options()
+        };
+        let messages = [
+            result(true, "Banned griefer", None),
+            result(false, "Missing duration", Some("/ban <player> <duration>")),
+        ];
+
+        let mut out = Vec::new();
+        let mut session = Session::new("localhost:25575".into());
+        for msg in messages {
+            let flow = handle_message(&mut out, &msg, &options, &mut session).await;
+            assert!(flow.unwrap().is_continue());
+        }
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "{}\n{}\n{}\n",
+                theme::paint(options.theme.success_color, "/ban griefer: Banned griefer"),
+                theme::paint(
+                    options.theme.error_color,
+                    "/ban griefer failed: Missing duration"
+                ),
+                theme::dim("Usage: /ban <player> <duration>"),
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn shows_replies_and_groups_them_by_thread() {
+        let (client, mut server) = duplex(4096);
+        let (reader, writer) = split(client);
+        let (input, _input_tx) = duplex(64);
+
+        let broadcast = |id: &str, from: &str, message: &str, reply_to: Option<&str>| {
+            line(&Extension::Broadcast {
+                payload: BroadcastPayload {
+                    from: from.into(),
+                    message: message.into(),
+                    message_id: Some(id.into()),
+                    signature: None,
+                    sent_at: None,
+                    reply_to: reply_to.map(Into::into),
+                    channel: None,
+                    tags: Vec::new(),
+                },
+            })
+        };
+        let incoming = broadcast(
+            "1",
+            "alice",
+            "is anyone around to help with the farm?",
+            None,
+        ) + &broadcast("2", "carol", "hi", None)
+            + &broadcast("3", "bob", "sure", Some("1"))
+            + &broadcast("4", "alice", "thanks", Some("3"))
+            + &broadcast("5", "carol", "agreed", Some("0"))
+            + &line(&MineChatMessage::Disconnect {
+                payload: DisconnectPayload {
+                    reason: "Server closing".into(),
+                },
+            });
+        server.write_all(incoming.as_bytes()).await.unwrap();
+
+        let mut out = Vec::new();
+        let mut session = Session::new("localhost:25575".into());
+        repl(
+            BufReader::new(reader),
+            writer,
+            &mut BufReader::new(input),
+            &mut out,
+            &options(),
+            &mut session,
+        )
+        .await
+        .unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines[2..8],
+            [
+                theme::dim("│ alice: is anyone around to help with the farm?"),
+                "[bob] sure".into(),
+                theme::dim("│ bob: sure"),
+                "[alice] thanks".into(),
+                theme::dim("│ [original message not available]"),
+                "[carol] agreed".into(),
+            ]
+        );
+
+        let mut threaded = Vec::new();
+        let history = session.history.make_contiguous();
+        thread_lines(history, 0, 0, &options(), &mut threaded);
+        assert_eq!(
+            threaded,
+            [
+                "#1 [alice] is anyone around to help with the farm? ✓",
+                "  #3 [bob] sure ✓",
+                "    #4 [alice] thanks ✓",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn publishes_session_events() {
+        let (client, mut server) = duplex(4096);
+        let (reader, writer) = split(client);
+        let (input, _input_tx) = duplex(64);
+        let incoming = line(&MineChatMessage::Disconnect {
+            payload: DisconnectPayload {
+                reason: "Server closing".into(),
+            },
+        });
+        server.write_all(incoming.as_bytes()).await.unwrap();
+
+        let mut session = Session::new("localhost:25575".into());
+        let mut events = session.events.subscribe();
+        repl(
+            BufReader::new(reader),
+            writer,
+            &mut BufReader::new(input),
+            &mut Vec::new(),
+            &options(),
+            &mut session,
+        )
+        .await
+        .unwrap();
+
+        assert!(matches!(
+            events.try_recv(),
+            Ok(MineChatEvent::Connected { address }) if address == "localhost:25575"
+        ));
+        assert!(matches!(
+            events.try_recv(),
+            Ok(MineChatEvent::Received(msg))
+                if matches!(*msg, Message::Core(MineChatMessage::Disconnect { .. }))
+        ));
+        assert!(matches!(
+            events.try_recv(),
+            Ok(MineChatEvent::Disconnected(ReplExit::Server { .. }))
+        ));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn hides_broadcasts_while_muted() {
+        let (client, mut server) = duplex(4096);
+        let (reader, writer) = split(client);
+        let (input, _input_tx) = duplex(64);
+
+        let incoming = line(&Extension::Broadcast {
+            payload: BroadcastPayload {
+                from: "alice".into(),
+                message: "hi".into(),
+                message_id: Some("1".into()),
+                signature: None,
+                sent_at: None,
+                reply_to: None,
+                channel: None,
+                tags: Vec::new(),
+            },
+        }) + &line(&MineChatMessage::Disconnect {
+            payload: DisconnectPayload {
+                reason: "Server closing".into(),
+            },
+        });
+        server.write_all(incoming.as_bytes()).await.unwrap();
+
+        let mut out = Vec::new();
+        let mut session = Session::new("localhost:25575".into());
+        session.muted = true;
+        repl(
+            BufReader::new(reader),
+            writer,
+            &mut BufReader::new(input),
+            &mut out,
+            &options(),
+            &mut session,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Disconnected: Server closing\n"
+        );
+        assert_eq!(session.history.len(), 1);
+        assert!(session.pending_receipts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn hides_broadcasts_from_muted_channels() {
+        let (client, mut server) = duplex(4096);
+        let (reader, writer) = split(client);
+        let (input, _input_tx) = duplex(64);
+
+        let broadcast = |channel: &str, message: &str| {
+            line(&Extension::Broadcast {
+                payload: BroadcastPayload {
+                    from: "alice".into(),
+                    message: message.into(),
+                    message_id: None,
+                    signature: None,
+                    sent_at: None,
+                    reply_to: None,
+                    channel: Some(channel.into()),
+                    tags: Vec::new(),
+                },
+            })
+        };
+        let incoming = broadcast("trade", "selling diamonds")
+            + &broadcast("general", "hi")
+            + &line(&MineChatMessage::Disconnect {
+                payload: DisconnectPayload {
+                    reason: "Server closing".into(),
+                },
+            });
+        server.write_all(incoming.as_bytes()).await.unwrap();
+
+        let mut out = Vec::new();
+        let mut session = Session::new("localhost:25575".into());
+        session.muted_channels.insert("trade".into());
+        repl(
+            BufReader::new(reader),
+            writer,
+            &mut BufReader::new(input),
+            &mut out,
+            &options(),
+            &mut session,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "[alice] hi\nDisconnected: Server closing\n"
+        );
+        assert_eq!(session.history.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn filters_and_labels_tagged_broadcasts() {
+        let options = ReplOptions {
+            show_tags: vec!["announcement".into(), "event".into()],
+            hide_tags: vec!["spoiler".into()],
+            show_tag_labels: true,
+            ..options()
+        };
+        let tagged = |message: &str, tags: &[&str]| {
+            Message::Extension(Extension::TaggedBroadcast {
+                payload: BroadcastPayload {
+                    from: "server".into(),
+                    message: message.into(),
+                    message_id: None,
+                    signature: None,
+                    sent_at: None,
+                    reply_to: None,
+                    channel: None,
+                    tags: tags.iter().map(|tag| tag.to_string()).collect(),
+                },
+            })
+        };
+        let messages = [
+            tagged("Restart at noon", &["Announcement", "important"]),
+            tagged("The dragon dies", &["event", "spoiler"]),
+            tagged("Nice build", &[]),
+        ];
+
+        let mut out = Vec::new();
+        let mut session = Session::new("localhost:25575".into());
+        for msg in messages {
+            let flow = handle_message(&mut out, &msg, &options, &mut session).await;
+            assert!(flow.unwrap().is_continue());
+        }
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "[server] {} Restart at noon\n",
+                theme::dim("[Announcement, important]")
+            )
+        );
+        assert_eq!(session.history.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn history_includes_messages_saved_in_earlier_sessions() {
+        let (client, mut server) = duplex(4096);
+        let (reader, writer) = split(client);
+        let (input, _input_tx) = duplex(64);
+
+        let db = HistoryDb::open_in_memory(4096).await.unwrap();
+        let earlier = |from: &str| HistoryEntry {
+            message_id: None,
+            from: from.into(),
+            message: "from yesterday".into(),
+            reply_to: None,
+            sent_at: None,
+            read: false,
+        };
+        db.insert("localhost:25575", None, &earlier("alice"), 1)
+            .await
+            .unwrap();
+        db.insert("elsewhere:25575", None, &earlier("carol"), 2)
+            .await
+            .unwrap();
+
+        let incoming = line(&Extension::Broadcast {
+            payload: BroadcastPayload {
+                from: "bob".into(),
+                message: "hi".into(),
+                message_id: Some("1".into()),
+                signature: None,
+                sent_at: None,
+                reply_to: None,
+                channel: None,
+                tags: Vec::new(),
+            },
+        }) + &line(&MineChatMessage::Disconnect {
+            payload: DisconnectPayload {
+                reason: "Server closing".into(),
+            },
+        });
+        server.write_all(incoming.as_bytes()).await.unwrap();
+
+        let mut session = Session::new("localhost:25575".into());
+        session.history_db = Some(db);
+        repl(
+            BufReader::new(reader),
+            writer,
+            &mut BufReader::new(input),
+            &mut Vec::new(),
+            &options(),
+            &mut session,
+        )
+        .await
+        .unwrap();
+
+        let history = saved_history(&session, None).await.unwrap();
+        let lines: Vec<String> = history
+            .iter()
+            .map(|entry| history_line(entry, &options()))
+            .collect();
+        assert_eq!(lines, ["[alice] from yesterday", "#1 [bob] hi ✓"]);
+        let found = saved_history(&session, Some("HI")).await.unwrap();
+        assert_eq!(found.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn shows_the_greeting_once_per_session() {
+        let greeting = line(&Extension::Greeting {
+            payload: GreetingPayload {
+                welcome_text: "Welcome to Survival!".into(),
+                rules_url: Some("https://example.com/rules".into()),
+            },
+        }) + &line(&MineChatMessage::Disconnect {
+            payload: DisconnectPayload {
+                reason: "Server closing".into(),
+            },
+        });
+        let mut session = Session::new("localhost:25575".into());
+        let mut outputs = Vec::new();
+        // The second connection stands in for reconnecting.
+        for _ in 0..2 {
+            let (client, mut server) = duplex(4096);
+            let (reader, writer) = split(client);
+            let (input, _input_tx) = duplex(64);
+            server.write_all(greeting.as_bytes()).await.unwrap();
+
+            let mut out = Vec::new();
+            repl(
+                BufReader::new(reader),
+                writer,
+                &mut BufReader::new(input),
+                &mut out,
+                &options(),
+                &mut session,
+            )
+            .await
+            .unwrap();
+            outputs.push(String::from_utf8(out).unwrap());
+        }
+
+        assert_eq!(
+            outputs[0],
+            "┌──────────────────────────────────┐\n\
+             │ Welcome to Survival!             │\n\
+             │ Rules: https://example.com/rules │\n\
+             └──────────────────────────────────┘\n\
+             Disconnected: Server closing\n"
+        );
+        assert_eq!(outputs[1], "Disconnected: Server closing\n");
+    }
+
+    #[tokio::test]
+    async fn reports_restart_time_on_server_shutdown() {
+        let (client, mut server) = duplex(4096);
+        let (reader, writer) = split(client);
+        let (input, _input_tx) = duplex(64);
+
+        let incoming = line(&Extension::ServerShutdown {
+            payload: ServerShutdownPayload {
+                message: "Updating plugins".into(),
+                restart_in_seconds: Some(30),
+            },
+        });
+        server.write_all(incoming.as_bytes()).await.unwrap();
+
+        let mut out = Vec::new();
+        let exit = repl(
+            BufReader::new(reader),
+            writer,
+            &mut BufReader::new(input),
+            &mut out,
+            &options(),
+            &mut Session::new("localhost:25575".into()),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            exit,
+            ReplExit::Shutdown {
+                restart_in: Some(Duration::from_secs(30))
+            }
+        );
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "*** Server shutting down: Updating plugins ***\n*** The server will restart in 30s ***\n"
+        );
+    }
+
+    /// Plays a server that reads until the client disconnects, answers with `reply` and
+    /// closes the connection, returning what the client sent.
+    fn serve_until_disconnect(server: DuplexStream, reply: String) -> JoinHandle<String> {
+        tokio::spawn(async move {
+            let (reader, mut writer) = split(server);
+            let mut lines = BufReader::new(reader).lines();
+            let mut sent = String::new();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                sent += &line;
+                sent.push('\n');
+                if line.contains("DISCONNECT") {
+                    break;
+                }
+            }
+            writer.write_all(reply.as_bytes()).await.unwrap();
+            sent
+        })
+    }
+
+    #[tokio::test]
+    async fn sends_chat_and_disconnects_on_exit() {
+        let (client, server) = duplex(4096);
+        let (reader, writer) = split(client);
+        let server = serve_until_disconnect(server, String::new());
+
+        let mut out = Vec::new();
+        let exit = repl(
+            BufReader::new(reader),
+            writer,
+            &mut &b"hello\n/exit\n"[..],
+            &mut out,
+            &options(),
+            &mut Session::new("localhost:25575".into()),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(exit, ReplExit::User);
+        let sent = server.await.unwrap();
+        let expected = line(&Extension::Chat {
+            payload: ChatPayload {
+                message: "hello".into(),
+                message_id: None,
+                signature: None,
+                reply_to: None,
+            },
+        }) + &line(&MineChatMessage::Disconnect {
+            payload: DisconnectPayload {
+                reason: "Client exit".into(),
+            },
+        });
+        assert_eq!(sent, expected);
+        assert!(out.is_empty());
+    }
+
+    #[tokio::test]
+    async fn retries_scheduled_messages_blocked_by_the_spam_guard() {
+        let (client, server) = duplex(4096);
+        let (reader, writer) = split(client);
+        let server = serve_until_disconnect(server, String::new());
+        let (input, mut input_tx) = duplex(256);
+
+        let typing = tokio::spawn(async move {
+            input_tx
+                .write_all(
+                    b"hi
+hi
+hi
+/schedule 0 later
+",
+                )
+                .await
+                .unwrap();
+            // The first attempt is blocked, and the retry goes out once the penalty ends.
+            time::sleep(Duration::from_millis(300)).await;
+            input_tx
+                .write_all(
+                    b"/exit
+",
+                )
+                .await
+                .unwrap();
+            input_tx
+        });
+        let mut session = Session::new("localhost:25575".into());
+        session.spam_guard = SpamGuard::new(Duration::from_millis(100));
+        let mut out = Vec::new();
+        repl(
+            BufReader::new(reader),
+            writer,
+            &mut BufReader::new(input),
+            &mut out,
+            &options(),
+            &mut session,
+        )
+        .await
+        .unwrap();
+        typing.await.unwrap();
+
+        let sent = server.await.unwrap();
+        assert_eq!(sent.matches("\"hi\"").count(), 2);
+        assert!(sent.contains("\"later\""));
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Slow down! Sending is blocked for 1s\n\
+             Scheduled message #1 to be sent in 0s\n\
+             Slow down! Sending is blocked for 1s\n\
+             Scheduled message #2 will be sent in 1s instead\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_running_when_an_export_fails() {
+        let (client, server) = duplex(4096);
+        let (reader, writer) = split(client);
+        let server = serve_until_disconnect(server, String::new());
+
+        let mut out = Vec::new();
+        let exit = repl(
+            BufReader::new(reader),
+            writer,
+            &mut &b"/export /nonexistent-minechat-dir/history.txt
+hello
+/exit
+"[..],
+            &mut out,
+            &options(),
+            &mut Session::new("localhost:25575".into()),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(exit, ReplExit::User);
+        assert!(server.await.unwrap().contains("hello"));
+        assert!(
+            String::from_utf8(out)
+                .unwrap()
+                .starts_with("Can't export to /nonexistent-minechat-dir/history.txt: ")
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_restricted_commands_in_safe_mode() {
+        let (client, server) = duplex(4096);
+        let (reader, writer) = split(client);
+        let server = serve_until_disconnect(server, String::new());
+
+        let options = ReplOptions {
+            safe_mode: true,
+            ..options()
+        };
+        let mut out = Vec::new();
+        repl(
+            BufReader::new(reader),
+            writer,
+            &mut &b"/join #staff\n/report 42 spam\nhello\n/exit\n"[..],
+            &mut out,
+            &options,
+            &mut Session::new("localhost:25575".into()),
+        )
+        .await
+        .unwrap();
+
+        let sent = server.await.unwrap();
+        let expected = line(&Extension::Chat {
+            payload: ChatPayload {
+                message: "hello".into(),
+                message_id: None,
+                signature: None,
+                reply_to: None,
+            },
+        }) + &line(&MineChatMessage::Disconnect {
+            payload: DisconnectPayload {
+                reason: "Client exit".into(),
+            },
+        });
+        assert_eq!(sent, expected);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Safe mode: not sending a CHANNEL_JOIN message\n\
+             Safe mode: not sending a SPAM_REPORT message\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn prefixes_and_counts_sent_chat() {
+        let (client, server) = duplex(4096);
+        let (reader, writer) = split(client);
+        let server = serve_until_disconnect(server, String::new());
+
+        let options = ReplOptions {
+            chat_prefix: Some("[Bot]".into()),
+            word_count: true,
+            ..options()
+        };
+        let mut out = Vec::new();
+        repl(
+            BufReader::new(reader),
+            writer,
+            &mut &b"hello\n/exit\n"[..],
+            &mut out,
+            &options,
+            &mut Session::new("localhost:25575".into()),
+        )
+        .await
+        .unwrap();
+
+        let sent = server.await.unwrap();
+        assert!(sent.starts_with(&line(&Extension::Chat {
+            payload: ChatPayload {
+                message: "[Bot] hello".into(),
+                message_id: None,
+                signature: None,
+                reply_to: None,
+            },
+        })));
+        // The count is of what was typed, without the prefix.
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!("{}\n", theme::dim("(1 word, 5 chars)"))
+        );
+    }
+
+    #[tokio::test]
+    async fn discards_input_that_is_not_utf8() {
+        let (client, server) = duplex(4096);
+        let (reader, writer) = split(client);
+        let server = serve_until_disconnect(server, String::new());
+
+        let mut session = Session::new("localhost:25575".into());
+        repl(
+            BufReader::new(reader),
+            writer,
+            &mut &b"\xff\xfe garbage\nhello\n/exit\n"[..],
+            &mut Vec::new(),
+            &options(),
+            &mut session,
+        )
+        .await
+        .unwrap();
+
+        let sent = server.await.unwrap();
+        assert_eq!(sent.lines().count(), 2);
+        assert!(sent.starts_with(&line(&Extension::Chat {
+            payload: ChatPayload {
+                message: "hello".into(),
+                message_id: None,
+                signature: None,
+                reply_to: None,
+            },
+        })));
+        assert_eq!(session.stats.invalid_input_count, 1);
+    }
+
+    #[tokio::test]
+    async fn displays_messages_arriving_after_exit() {
+        let (client, server) = duplex(4096);
+        let (reader, writer) = split(client);
+        let reply = line(&Extension::Broadcast {
+            payload: BroadcastPayload {
+                from: "alice".into(),
+                message: "bye".into(),
+                message_id: None,
+                signature: None,
+                sent_at: None,
+                reply_to: None,
+                channel: None,
+                tags: Vec::new(),
+            },
+        }) + &line(&MineChatMessage::Disconnect {
+            payload: DisconnectPayload {
+                reason: "Goodbye".into(),
+            },
+        });
+        let server = serve_until_disconnect(server, reply);
+
+        let mut out = Vec::new();
+        let exit = repl(
+            BufReader::new(reader),
+            writer,
+            &mut &b"/exit\n"[..],
+            &mut out,
+            &options(),
+            &mut Session::new("localhost:25575".into()),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(exit, ReplExit::User);
+        server.await.unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "[alice] bye\nDisconnected: Goodbye\n"
+        );
+    }
+}
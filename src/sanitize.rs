@@ -0,0 +1,143 @@
+/// Removes ANSI escape sequences and control characters from `text`.
+///
+/// Tabs and newlines are kept. Escape sequences are removed as a whole (e.g. `\x1b[1;31m`), so
+/// none of their parameters are left behind in the output.
+pub fn strip_formatting(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            if chars.next_if_eq(&'[').is_some() {
+                // Skip parameter and intermediate bytes up to and including the final byte.
+                for c in chars.by_ref() {
+                    if ('\x40'..='\x7e').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            continue;
+        }
+        if c < ' ' && c != '\t' && c != '\n' {
+            continue;
+        }
+        out.push(c);
+    }
+
+    out
+}
+
+/// Collapses runs of spaces and tabs to a single space and runs of blank lines to a single
+/// blank line, trimming the whitespace around line breaks. Code between triple backticks is
+/// left as it is, since its whitespace matters.
+pub fn normalize_whitespace(text: &str) -> String {
+    text.split("```")
+        .enumerate()
+        .map(|(i, part)| {
+            if i % 2 == 1 {
+                part.to_string()
+            } else {
+                normalize_lines(part)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("```")
+}
+
+/// Normalizes text outside code blocks. The start and end of `text` aren't trimmed, since
+/// they may be next to a code block.
+fn normalize_lines(text: &str) -> String {
+    let lines: Vec<&str> = text.split('\n').collect();
+    let last = lines.len() - 1;
+    let mut out: Vec<String> = Vec::new();
+    let mut after_blank = false;
+    for (i, line) in lines.into_iter().enumerate() {
+        let mut line = line;
+        if i > 0 {
+            line = line.trim_start_matches([' ', '\t']);
+        }
+        if i < last {
+            line = line.trim_end_matches([' ', '\t']);
+        }
+        // The first and last parts of the text aren't whole lines, so they aren't blank
+        // lines to leave out.
+        let blank = line.is_empty() && i > 0 && i < last;
+        if blank && after_blank {
+            continue;
+        }
+        after_blank = blank;
+        out.push(collapse_spaces(line));
+    }
+    out.join("\n")
+}
+
+/// Replaces each run of spaces and tabs in `line` with a single space.
+fn collapse_spaces(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == ' ' || c == '\t' {
+            while chars.next_if(|&c| c == ' ' || c == '\t').is_some() {}
+            out.push(' ');
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaves_plain_text_untouched() {
+        assert_eq!(strip_formatting("hello, world!"), "hello, world!");
+        assert_eq!(strip_formatting(""), "");
+    }
+
+    #[test]
+    fn keeps_tabs_newlines_and_unicode() {
+        assert_eq!(strip_formatting("a\tb\nc"), "a\tb\nc");
+        assert_eq!(strip_formatting("héllo 👍"), "héllo 👍");
+    }
+
+    #[test]
+    fn removes_color_sequences() {
+        assert_eq!(strip_formatting("\x1b[31mred\x1b[0m"), "red");
+        assert_eq!(
+            strip_formatting("\x1b[1;38;5;208mbold\x1b[m text"),
+            "bold text"
+        );
+    }
+
+    #[test]
+    fn removes_other_csi_sequences() {
+        assert_eq!(strip_formatting("\x1b[2Jcleared"), "cleared");
+        assert_eq!(strip_formatting("up\x1b[1Aover"), "upover");
+    }
+
+    #[test]
+    fn removes_control_characters() {
+        assert_eq!(strip_formatting("a\x00b\x07c\rd\x08e"), "abcde");
+        assert_eq!(strip_formatting("lone \x1b escape"), "lone  escape");
+    }
+
+    #[test]
+    fn handles_unterminated_sequence() {
+        assert_eq!(strip_formatting("text\x1b[31"), "text");
+    }
+
+    #[test]
+    fn collapses_whitespace_outside_code_blocks() {
+        assert_eq!(normalize_whitespace("hello \t  world"), "hello world");
+        assert_eq!(
+            normalize_whitespace("first  \n\n \n\n  second\nthird"),
+            "first\n\nsecond\nthird"
+        );
+        assert_eq!(
+            normalize_whitespace("run  this:  ```\nfn main() {\n    go();\n}\n```  ok"),
+            "run this: ```\nfn main() {\n    go();\n}\n``` ok"
+        );
+    }
+}
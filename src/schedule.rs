@@ -0,0 +1,120 @@
+//! Chat messages scheduled with `/schedule` to be sent after a delay.
+
+use std::{collections::HashMap, time::Duration};
+use tokio::{
+    sync::mpsc,
+    task::JoinHandle,
+    time::{self, Instant},
+};
+
+/// A message waiting to be sent.
+pub struct ScheduledMessage {
+    pub message: String,
+    /// When the message is due to be sent.
+    pub due: Instant,
+    /// The task that reports the message as due once its delay has passed.
+    timer: JoinHandle<()>,
+}
+
+/// The scheduled messages of a session, each timed by a task of its own.
+pub struct Scheduler {
+    pending: HashMap<u64, ScheduledMessage>,
+    next_id: u64,
+    due_tx: mpsc::UnboundedSender<u64>,
+    due_rx: mpsc::UnboundedReceiver<u64>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        let (due_tx, due_rx) = mpsc::unbounded_channel();
+        Self {
+            pending: HashMap::new(),
+            next_id: 1,
+            due_tx,
+            due_rx,
+        }
+    }
+}
+
+impl Scheduler {
+    /// Schedules `message` to be sent after `delay`, returning its ID.
+    pub fn schedule(&mut self, delay: Duration, message: String) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let due_tx = self.due_tx.clone();
+        let timer = tokio::spawn(async move {
+            time::sleep(delay).await;
+            let _ = due_tx.send(id);
+        });
+        let scheduled = ScheduledMessage {
+            message,
+            due: Instant::now() + delay,
+            timer,
+        };
+        self.pending.insert(id, scheduled);
+        id
+    }
+
+    /// Cancels the message with `id`, returning whether it was still pending.
+    pub fn cancel(&mut self, id: u64) -> bool {
+        match self.pending.remove(&id) {
+            Some(scheduled) => {
+                scheduled.timer.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Waits for the next message whose delay has passed and returns it.
+    pub async fn next_due(&mut self) -> String {
+        loop {
+            let id = self
+                .due_rx
+                .recv()
+                .await
+                .expect("the scheduler keeps a sender of its own");
+            // A message cancelled just as it came due was already removed.
+            if let Some(scheduled) = self.pending.remove(&id) {
+                return scheduled.message;
+            }
+        }
+    }
+
+    /// Returns the pending messages with their IDs, soonest first.
+    pub fn pending(&self) -> Vec<(u64, &ScheduledMessage)> {
+        let mut pending: Vec<_> = self.pending.iter().map(|(&id, s)| (id, s)).collect();
+        pending.sort_by_key(|(id, scheduled)| (scheduled.due, *id));
+        pending
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        for scheduled in self.pending.values() {
+            scheduled.timer.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn sends_messages_when_due_unless_cancelled() {
+        let mut scheduler = Scheduler::default();
+        let later = scheduler.schedule(Duration::from_millis(60), "later".into());
+        let cancelled = scheduler.schedule(Duration::from_millis(5), "cancelled".into());
+        scheduler.schedule(Duration::from_millis(30), "sooner".into());
+
+        let ids: Vec<u64> = scheduler.pending().iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, [cancelled, 3, later]);
+        assert!(scheduler.cancel(cancelled));
+        assert!(!scheduler.cancel(cancelled));
+
+        assert_eq!(scheduler.next_due().await, "sooner");
+        assert_eq!(scheduler.next_due().await, "later");
+        assert!(scheduler.pending().is_empty());
+    }
+}
@@ -0,0 +1,102 @@
+//! The scoreboard sidebar, drawn over the top right corner of the terminal.
+
+use std::fmt::Write;
+
+/// How scoreboard updates are shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScoreboardMode {
+    /// Ignored, with `--no-scoreboard`.
+    Off,
+    /// Drawn as a panel on the right edge of the terminal, updated in place.
+    Overlay,
+    /// Printed as lines, when the output isn't a terminal.
+    Inline,
+}
+
+/// The most entries shown, like Minecraft's own sidebar.
+pub const MAX_ENTRIES: usize = 15;
+
+/// The width of the panel in columns, including its one-column margins.
+const PANEL_WIDTH: usize = 30;
+
+/// Returns the width of the terminal on stdout, if known.
+pub fn terminal_width() -> Option<usize> {
+    #[cfg(unix)]
+    if let Some((width, _)) = unix::stdout_size() {
+        return Some(width);
+    }
+    std::env::var("COLUMNS").ok()?.parse().ok()
+}
+
+/// Returns the height of the terminal on stdout, if known.
+pub fn terminal_height() -> Option<usize> {
+    #[cfg(unix)]
+    if let Some((_, height)) = unix::stdout_size() {
+        return Some(height);
+    }
+    std::env::var("LINES").ok()?.parse().ok()
+}
+
+/// Returns the escape sequences that draw `entries` as a panel on the right edge of a
+/// terminal `width` columns wide, leaving the cursor where it was.
+///
+/// Rows below the entries are blanked down to `previous_rows`, the height of the panel drawn
+/// before, so entries that are gone don't linger.
+pub fn overlay(entries: &[(String, String)], width: usize, previous_rows: usize) -> String {
+    let column = width.saturating_sub(PANEL_WIDTH) + 1;
+    let inner = PANEL_WIDTH - 2;
+    let mut out = String::from("\x1b7");
+    let rows = entries.len().min(MAX_ENTRIES);
+    for row in 0..rows.max(previous_rows) {
+        let text = match entries.get(row).filter(|_| row < rows) {
+            Some((label, value)) => {
+                let value: String = value.chars().take(inner / 2).collect();
+                let label_width = inner - value.chars().count() - 1;
+                let label: String = label.chars().take(label_width).collect();
+                format!("{:<label_width$} {}", label, value)
+            }
+            None => String::new(),
+        };
+        let _ = write!(out, "\x1b[{};{}H {:<inner$} ", row + 1, column, text);
+    }
+    out.push_str("\x1b8");
+    out
+}
+
+#[cfg(unix)]
+mod unix {
+    use nix::libc;
+    use std::{io, os::fd::AsRawFd};
+
+    nix::ioctl_read_bad!(window_size, libc::TIOCGWINSZ, libc::winsize);
+
+    /// Returns the width and height of the terminal on stdout.
+    pub fn stdout_size() -> Option<(usize, usize)> {
+        // SAFETY: all-zero bytes are a valid `winsize`.
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        // SAFETY: stdout stays open for the whole process, and the ioctl writes a single
+        // `winsize` through the pointer.
+        unsafe { window_size(io::stdout().as_raw_fd(), &mut size) }.ok()?;
+        let size = (size.ws_col as usize, size.ws_row as usize);
+        Some(size).filter(|&(width, height)| width > 0 && height > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn draws_entries_at_the_right_edge_and_blanks_old_rows() {
+        let entries = vec![("Kills".to_string(), "12".to_string())];
+        let drawn = overlay(&entries, 80, 2);
+        assert_eq!(
+            drawn,
+            format!(
+                "\x1b7\x1b[1;51H {:<28} \x1b[2;51H {:28} \x1b8",
+                format!("{:<25} 12", "Kills"),
+                ""
+            )
+        );
+    }
+}
@@ -0,0 +1,274 @@
+use crate::{
+    away::Away,
+    eventlog::{ConnectionEvent, EventLog},
+    events::{BroadcastBus, EventBus},
+    history::HistoryDb,
+    pipe_in::PipeIn,
+    protocol::{GroupPrivateMessagePayload, SessionTokenPayload, TopicPayload},
+    schedule::Scheduler,
+    spam::SpamGuard,
+    stats::Stats,
+    tee::TeeSocket,
+};
+use socket2::Socket;
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{net::TcpListener, time::Instant};
+
+/// How many received messages are kept in the session history.
+pub const HISTORY_LIMIT: usize = 100;
+
+/// How many sent messages are kept while waiting for delivery acknowledgements.
+const SENT_LIMIT: usize = 100;
+
+/// How long sending is blocked after flooding, unless set with `--anti-spam-penalty`.
+const DEFAULT_SPAM_PENALTY: Duration = Duration::from_secs(10);
+
+/// A broadcast message kept in the session history.
+#[derive(Clone)]
+pub struct HistoryEntry {
+    pub message_id: Option<String>,
+    pub from: String,
+    pub message: String,
+    /// The ID of the message this one replies to.
+    pub reply_to: Option<String>,
+    /// When the server sent the message, in milliseconds since the Unix epoch.
+    pub sent_at: Option<u64>,
+    /// Whether the server acknowledged the message as read.
+    pub read: bool,
+}
+
+/// A chat message sent with an ID, awaiting a delivery acknowledgement.
+pub struct SentMessage {
+    pub message_id: String,
+    pub message: String,
+    pub sent_at: Instant,
+    /// How many users the server delivered the message to, once acknowledged.
+    pub delivered_to: Option<u32>,
+    /// Whether the user was warned that the message may not have been delivered.
+    pub flagged: bool,
+}
+
+/// State kept in memory across reconnects to the same server.
+pub struct Session {
+    /// The address of the server.
+    pub address: String,
+    /// A handle to the socket of the current connection, for diagnostics.
+    pub socket: Option<Socket>,
+    /// The latest session token issued by the server, if any.
+    pub token: Option<SessionTokenPayload>,
+    /// The most recently received broadcasts, oldest first.
+    pub history: VecDeque<HistoryEntry>,
+    /// Where received broadcasts are saved across sessions, unless it couldn't be opened.
+    pub history_db: Option<HistoryDb>,
+    /// IDs of displayed messages that haven't been acknowledged to the server yet.
+    pub pending_receipts: Vec<String>,
+    /// When the current connection was established, in milliseconds since the Unix epoch.
+    pub connected_at: u64,
+    /// When the latest server heartbeat arrived, unless the next one was already reported
+    /// missing.
+    pub last_heartbeat: Option<Instant>,
+    /// The time between the two latest heartbeats, taken to be how often the server sends
+    /// them.
+    pub heartbeat_interval: Option<Duration>,
+    /// The order senders were first seen in, for `--color-from cycle`.
+    pub sender_order: HashMap<String, usize>,
+    /// Counters shown by `/stats`.
+    pub stats: Stats,
+    /// The ID of the last checkpoint received from the server.
+    pub checkpoint: Option<String>,
+    /// The channel joined last, if any.
+    pub channel: Option<String>,
+    /// The topic of the current channel, as last reported by the server.
+    pub topic: Option<TopicPayload>,
+    /// How many rows of the scoreboard overlay are on screen.
+    pub scoreboard_rows: usize,
+    /// Whether received broadcasts are hidden, set with `--mute` and `/mute`.
+    pub muted: bool,
+    /// Channels whose broadcasts are hidden, set with `--mute-channel` and `/mute-channel`.
+    pub muted_channels: BTreeSet<String>,
+    /// Whether the user is away, set with `/away` and `/back`.
+    pub away: Away,
+    /// Automatic replies to private messages received while away, waiting to be sent.
+    pub auto_replies: Vec<GroupPrivateMessagePayload>,
+    /// The results of `/dice` this session, oldest first.
+    pub dice_rolls: Vec<String>,
+    /// Replayed broadcasts left out for being older than `--max-message-age`, not yet
+    /// reported to the user.
+    pub suppressed_old: usize,
+    /// Whether the server's greeting was shown, so reconnecting doesn't show it again.
+    pub greeted: bool,
+    /// The shared config merged under the user's when reloading it, given with
+    /// `--system-config`.
+    pub system_config: Option<PathBuf>,
+    /// The UUID to authenticate with instead of the linked one, given with
+    /// `--uuid-override`.
+    pub uuid_override: Option<String>,
+    /// A named pipe read alongside stdin, given with `--pipe-in`.
+    pub pipe_in: Option<PipeIn>,
+    /// The socket other processes get copies of received messages on, with `--tee-socket`.
+    pub tee_socket: Option<TeeSocket>,
+    /// The listener IRC clients connect to instead of chatting on stdin, with
+    /// `--irc-gateway`.
+    pub irc_gateway: Option<TcpListener>,
+    /// Blocks sending when the user floods the chat.
+    pub spam_guard: SpamGuard,
+    /// Chat messages waiting to be sent later, added with `/schedule`.
+    pub scheduled: Scheduler,
+    /// Where the events of the session are published, for anything following them.
+    pub events: Arc<dyn EventBus>,
+    /// The log connection events are recorded in, unless it couldn't be opened.
+    pub event_log: Option<EventLog>,
+    /// Recently sent chat messages that asked for a delivery acknowledgement, oldest first.
+    pub sent: VecDeque<SentMessage>,
+    /// The number of chat messages assigned an ID so far, used to generate the next one.
+    sent_count: u64,
+}
+
+impl Session {
+    pub fn new(address: String) -> Self {
+        Self {
+            address,
+            socket: None,
+            token: None,
+            history: VecDeque::new(),
+            history_db: None,
+            pending_receipts: Vec::new(),
+            connected_at: 0,
+            stats: Stats::default(),
+            last_heartbeat: None,
+            heartbeat_interval: None,
+            sender_order: HashMap::new(),
+            checkpoint: None,
+            channel: None,
+            topic: None,
+            scoreboard_rows: 0,
+            muted: false,
+            muted_channels: BTreeSet::new(),
+            away: Away::default(),
+            auto_replies: Vec::new(),
+            dice_rolls: Vec::new(),
+            suppressed_old: 0,
+            greeted: false,
+            system_config: None,
+            uuid_override: None,
+            pipe_in: None,
+            tee_socket: None,
+            irc_gateway: None,
+            spam_guard: SpamGuard::new(DEFAULT_SPAM_PENALTY),
+            scheduled: Scheduler::default(),
+            events: Arc::new(BroadcastBus::default()),
+            event_log: None,
+            sent: VecDeque::new(),
+            sent_count: 0,
+        }
+    }
+
+    /// Records `event` on this server in the event log, if it is open.
+    pub fn log_event(&self, event: ConnectionEvent) {
+        if let Some(log) = &self.event_log {
+            log.record(&self.address, event);
+        }
+    }
+
+    /// Returns a new ID for a chat message, unique within this session.
+    pub fn next_message_id(&mut self) -> String {
+        self.sent_count += 1;
+        self.sent_count.to_string()
+    }
+
+    /// Remembers a sent message until it is acknowledged, dropping the oldest one if full.
+    pub fn record_sent(&mut self, message: SentMessage) {
+        if self.sent.len() == SENT_LIMIT {
+            self.sent.pop_front();
+        }
+        self.sent.push_back(message);
+    }
+
+    /// Records the delivery of the sent message with the given ID, returning it if known.
+    pub fn acknowledge(&mut self, message_id: &str, delivered_to: u32) -> Option<&SentMessage> {
+        let sent = self.sent.iter_mut().find(|m| m.message_id == message_id)?;
+        sent.delivered_to = Some(delivered_to);
+        Some(sent)
+    }
+
+    /// Returns when the oldest unacknowledged message that hasn't been flagged yet was sent.
+    pub fn oldest_undelivered(&self) -> Option<Instant> {
+        self.sent
+            .iter()
+            .find(|m| m.delivered_to.is_none() && !m.flagged)
+            .map(|m| m.sent_at)
+    }
+
+    /// When the next heartbeat is overdue: twice the interval after the latest one. Not
+    /// until two heartbeats have shown how often they come.
+    pub fn heartbeat_deadline(&self) -> Option<Instant> {
+        Some(self.last_heartbeat? + 2 * self.heartbeat_interval?)
+    }
+
+    /// Adds a received message to the history, dropping the oldest one if it's full.
+    pub fn record(&mut self, entry: HistoryEntry) {
+        if self.history.len() == HISTORY_LIMIT {
+            self.history.pop_front();
+        }
+        self.history.push_back(entry);
+    }
+
+    /// Returns the message with this ID from the history, if it is still there.
+    pub fn find(&self, message_id: &str) -> Option<&HistoryEntry> {
+        self.history
+            .iter()
+            .find(|entry| entry.message_id.as_deref() == Some(message_id))
+    }
+
+    /// Whether a message with this ID is already in the history, such as one replayed after
+    /// reconnecting.
+    pub fn has_seen(&self, message_id: &str) -> bool {
+        self.find(message_id).is_some()
+    }
+
+    /// Marks the messages with the given IDs as read.
+    pub fn mark_read(&mut self, message_ids: &[String]) {
+        for entry in &mut self.history {
+            if entry
+                .message_id
+                .as_ref()
+                .is_some_and(|id| message_ids.contains(id))
+            {
+                entry.read = true;
+            }
+        }
+    }
+
+    /// Whether broadcasts sent in `channel` are hidden, with all of them muted or that
+    /// channel muted.
+    pub fn hides(&self, channel: Option<&str>) -> bool {
+        self.muted || channel.is_some_and(|channel| self.muted_channels.contains(channel))
+    }
+
+    /// Returns the session token if one was issued and it hasn't expired yet.
+    pub fn valid_token(&self) -> Option<&str> {
+        self.token
+            .as_ref()
+            .filter(|token| unix_now() < token.expires_at)
+            .map(|token| token.token.as_str())
+    }
+}
+
+/// Returns the current time as seconds since the Unix epoch.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Returns the current time as milliseconds since the Unix epoch.
+pub fn unix_now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
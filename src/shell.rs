@@ -0,0 +1,418 @@
+//! Line editing for interactive sessions, with a prompt showing the current server and
+//! channel and input history navigable with the arrow keys.
+
+use crate::commands;
+use clap::ValueEnum;
+use minechat_protocol::protocol::MineChatError;
+use rustyline::{
+    Cmd, ConditionalEventHandler, Config, DefaultEditor, Event, EventContext, EventHandler,
+    ExternalPrinter, KeyCode, KeyEvent, Modifiers, RepeatCount, error::ReadlineError,
+};
+use std::{
+    env, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+};
+use tokio::{
+    io::{AsyncWriteExt, BufReader, DuplexStream, duplex},
+    process::Command,
+    runtime::Handle,
+};
+
+/// The editor used when neither `$VISUAL` nor `$EDITOR` is set.
+const DEFAULT_EDITOR: &str = "vi";
+
+/// How long to wait after Escape for the rest of a key's escape sequence, in milliseconds.
+const ESCAPE_TIMEOUT_MS: u16 = 50;
+
+/// The server and channel shown in the prompt, shared with the thread reading input.
+#[derive(Clone)]
+pub struct PromptContext(Arc<(Mutex<PromptState>, Condvar)>);
+
+struct PromptState {
+    server: String,
+    channel: Option<String>,
+    /// Lines read but not handled by the session yet.
+    unhandled_lines: usize,
+}
+
+impl PromptContext {
+    pub fn new(server: &str) -> Self {
+        let state = PromptState {
+            server: server.to_string(),
+            channel: None,
+            unhandled_lines: 0,
+        };
+        Self(Arc::new((Mutex::new(state), Condvar::new())))
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, PromptState> {
+        self.0.0.lock().expect("prompt context poisoned")
+    }
+
+    /// Shows `server` as the server's name in the prompt from the next line on.
+    pub fn set_server(&self, server: &str) {
+        self.state().server = server.to_string();
+    }
+
+    /// Shows `channel` in the prompt from the next line on.
+    pub fn set_channel(&self, channel: &str) {
+        self.state().channel = Some(channel.to_string());
+    }
+
+    /// Lets the prompt come back after a line was handled.
+    ///
+    /// The prompt waits for this so that a pager or editor started by the line has the
+    /// terminal to itself.
+    pub fn line_handled(&self) {
+        let mut state = self.state();
+        state.unhandled_lines = state.unhandled_lines.saturating_sub(1);
+        self.0.1.notify_all();
+    }
+
+    fn line_read(&self) {
+        self.state().unhandled_lines += 1;
+    }
+
+    fn wait_until_handled(&self) {
+        let state = self.state();
+        let _state = self
+            .0
+            .1
+            .wait_while(state, |state| state.unhandled_lines > 0)
+            .expect("prompt context poisoned");
+    }
+
+    fn render(&self, prompt: &str) -> String {
+        let state = self.state();
+        match &state.channel {
+            Some(channel) => format!("[{} #{}] {}", state.server, channel, prompt),
+            None => format!("[{}] {}", state.server, prompt),
+        }
+    }
+}
+
+/// Output written while the user is typing, printed above the prompt instead of over it.
+pub struct ShellOutput {
+    printer: Box<dyn ExternalPrinter + Send>,
+    buffer: Vec<u8>,
+    /// The terminal settings from before line editing started.
+    #[cfg(unix)]
+    saved_termios: Option<nix::sys::termios::Termios>,
+}
+
+impl Drop for ShellOutput {
+    fn drop(&mut self) {
+        // The input thread may still be waiting for a line with the terminal in raw mode,
+        // which would outlive the process.
+        #[cfg(unix)]
+        if let Some(termios) = &self.saved_termios {
+            use nix::sys::termios::{SetArg, tcsetattr};
+            let _ = tcsetattr(io::stdin(), SetArg::TCSANOW, termios);
+        }
+    }
+}
+
+impl Write for ShellOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if let Some(end) = self.buffer.iter().rposition(|&b| b == b'\n') {
+            let lines: Vec<u8> = self.buffer.drain(..=end).collect();
+            self.printer
+                .print(String::from_utf8_lossy(&lines).into_owned())
+                .map_err(io::Error::other)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            let text = String::from_utf8_lossy(&self.buffer).into_owned();
+            self.buffer.clear();
+            self.printer.print(text).map_err(io::Error::other)?;
+        }
+        Ok(())
+    }
+}
+
+/// Accepts the line typed so far and asks for it to be finished in an external editor.
+struct OpenEditor(Arc<AtomicBool>);
+
+impl ConditionalEventHandler for OpenEditor {
+    fn handle(&self, _: &Event, _: RepeatCount, _: bool, _: &EventContext) -> Option<Cmd> {
+        self.0.store(true, Ordering::Relaxed);
+        Some(Cmd::AcceptLine)
+    }
+}
+
+/// Keeps the line typed so far when Ctrl-C is pressed, then interrupts as usual.
+struct KeepDraft(Arc<Mutex<String>>);
+
+impl ConditionalEventHandler for KeepDraft {
+    fn handle(&self, _: &Event, _: RepeatCount, _: bool, ctx: &EventContext) -> Option<Cmd> {
+        *self.0.lock().expect("draft poisoned") = ctx.line().to_string();
+        Some(Cmd::Interrupt)
+    }
+}
+
+/// Saves `draft` at `path` to offer it again on the next start.
+fn save_draft(path: &Path, draft: &str) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, draft)
+}
+
+/// Removes the draft saved at `path` and returns it without trailing whitespace, which would
+/// end the line, unless there is none or it's blank.
+fn take_draft(path: &Path) -> Option<String> {
+    let draft = fs::read_to_string(path).ok()?;
+    if let Err(e) = fs::remove_file(path) {
+        log::warn!("Can't remove the draft {}: {}", path.display(), e);
+    }
+    Some(draft.trim_end().to_string()).filter(|draft| !draft.is_empty())
+}
+
+/// How the line breaks of a message written in an external editor are sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MultilineStrategy {
+    /// Join the lines with spaces.
+    Collapse,
+    /// Keep the line breaks in the message.
+    Preserve,
+}
+
+impl MultilineStrategy {
+    /// Turns the text saved in the editor into a message, or `None` if it is blank.
+    pub fn apply(self, text: &str) -> Option<String> {
+        let message = match self {
+            MultilineStrategy::Collapse => text
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .collect::<Vec<_>>()
+                .join(" "),
+            MultilineStrategy::Preserve => text.trim().to_string(),
+        };
+        Some(message).filter(|message| !message.is_empty())
+    }
+}
+
+/// Opens `text` in `$VISUAL` or `$EDITOR` (`vi` if neither is set), waits for it to exit and
+/// returns the saved text.
+pub async fn edit_in_editor(text: &str) -> io::Result<String> {
+    let editor = ["VISUAL", "EDITOR"]
+        .into_iter()
+        .find_map(|var| {
+            env::var(var)
+                .ok()
+                .filter(|editor| !editor.trim().is_empty())
+        })
+        .unwrap_or_else(|| DEFAULT_EDITOR.to_string());
+    let mut words = editor.split_whitespace();
+    let program = words.next().expect("the editor command isn't blank");
+
+    let path = create_message_file(text)?;
+    let status = Command::new(program).args(words).arg(&path).status().await;
+    let saved = fs::read_to_string(&path);
+    let _ = fs::remove_file(&path);
+    let status = status?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "{} exited with {}",
+            program, status
+        )));
+    }
+    saved
+}
+
+/// Writes `text` to a new file in the temporary directory that only the user can read,
+/// for the editor to open.
+///
+/// The name is random and the file must not exist yet, so another user of a shared
+/// temporary directory can't plant a file or symlink there to read or redirect the message.
+fn create_message_file(text: &str) -> io::Result<PathBuf> {
+    loop {
+        let name = format!("minechat-message-{}.txt", uuid::Uuid::new_v4().simple());
+        let path = env::temp_dir().join(name);
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = match options.open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        };
+        if let Err(e) = file.write_all(text.as_bytes()) {
+            let _ = fs::remove_file(&path);
+            return Err(e);
+        }
+        return Ok(path);
+    }
+}
+
+/// Starts reading lines from the terminal on a separate thread.
+///
+/// Returns the lines as a reader that reaches EOF when the user presses Ctrl-D or Ctrl-C, and
+/// the output to display the session on. Pressing Escape turns the line into an `/edit`
+/// command, to finish it in an external editor.
+///
+/// A line left unsent by pressing Ctrl-C is saved at `draft_path`, and offered to be typed
+/// in for the user when starting again.
+pub fn spawn(
+    prompt: String,
+    context: PromptContext,
+    draft_path: PathBuf,
+) -> Result<(BufReader<DuplexStream>, ShellOutput), MineChatError> {
+    #[cfg(unix)]
+    let saved_termios = nix::sys::termios::tcgetattr(io::stdin()).ok();
+    // Without a timeout, Escape waits for the rest of an escape sequence that never comes.
+    let config = Config::builder()
+        .keyseq_timeout(Some(ESCAPE_TIMEOUT_MS))
+        .build();
+    let mut editor = DefaultEditor::with_config(config).map_err(io::Error::other)?;
+    let printer = editor.create_external_printer().map_err(io::Error::other)?;
+    let open_editor = Arc::new(AtomicBool::new(false));
+    editor.bind_sequence(
+        KeyEvent(KeyCode::Esc, Modifiers::NONE),
+        EventHandler::Conditional(Box::new(OpenEditor(open_editor.clone()))),
+    );
+    let interrupted_line = Arc::new(Mutex::new(String::new()));
+    editor.bind_sequence(
+        KeyEvent::ctrl('C'),
+        EventHandler::Conditional(Box::new(KeepDraft(interrupted_line.clone()))),
+    );
+    let (mut tx, rx) = duplex(1024);
+    let runtime = Handle::current();
+
+    // Not a blocking task: the runtime would wait for it on shutdown, while it waits for input.
+    thread::spawn(move || {
+        let mut initial = take_draft(&draft_path).filter(|_| {
+            let question = "Load the message you didn't send last time? [Y/n] ";
+            editor
+                .readline(question)
+                .is_ok_and(|answer| !answer.trim().to_lowercase().starts_with('n'))
+        });
+        loop {
+            context.wait_until_handled();
+            let prompt = context.render(&prompt);
+            let read = match initial.take() {
+                Some(draft) => editor.readline_with_initial(&prompt, (&draft, "")),
+                None => editor.readline(&prompt),
+            };
+            let line = match read {
+                Ok(line) => line,
+                Err(ReadlineError::Interrupted) => {
+                    let draft =
+                        std::mem::take(&mut *interrupted_line.lock().expect("draft poisoned"));
+                    if !draft.trim().is_empty() && !commands::Command::has_secret(draft.trim()) {
+                        match save_draft(&draft_path, &draft) {
+                            Ok(()) => println!(
+                                "Saved your unsent message to {}, to load it next time",
+                                draft_path.display()
+                            ),
+                            Err(e) => log::error!("Can't save the unsent message: {}", e),
+                        }
+                    }
+                    break;
+                }
+                Err(ReadlineError::Eof) => break,
+                Err(e) => {
+                    log::error!("Failed to read input: {}", e);
+                    break;
+                }
+            };
+            let line = if open_editor.swap(false, Ordering::Relaxed) {
+                format!("/edit {}", line)
+            } else {
+                if !line.trim().is_empty() && !commands::Command::has_secret(line.trim()) {
+                    let _ = editor.add_history_entry(line.as_str());
+                }
+                line
+            };
+            context.line_read();
+            let sent = runtime.block_on(async {
+                tx.write_all(line.as_bytes()).await?;
+                tx.write_all(b"\n").await
+            });
+            if sent.is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok((
+        BufReader::new(rx),
+        ShellOutput {
+            printer: Box::new(printer),
+            buffer: Vec::new(),
+            #[cfg(unix)]
+            saved_termios,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saves_and_takes_drafts_once() {
+        let path = env::temp_dir()
+            .join(format!("minechat-drafts-{}", std::process::id()))
+            .join("localhost_25575.txt");
+        save_draft(&path, "half a thought\n").unwrap();
+        assert_eq!(take_draft(&path).as_deref(), Some("half a thought"));
+        assert_eq!(take_draft(&path), None);
+        save_draft(&path, "  ").unwrap();
+        assert_eq!(take_draft(&path), None);
+        fs::remove_dir(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn creates_private_message_files() {
+        let path = create_message_file("hello").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "hello");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let other = create_message_file("").unwrap();
+        assert_ne!(other, path);
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&other).unwrap();
+    }
+
+    #[test]
+    fn shows_the_renamed_server_in_the_prompt() {
+        let context = PromptContext::new("localhost:25575");
+        context.set_channel("general");
+        context.set_server("home");
+        assert_eq!(context.render("> "), "[home #general] > ");
+    }
+
+    #[test]
+    fn collapses_or_keeps_line_breaks() {
+        let text = "Selling diamonds\n\n  10 each  \nmsg me\n";
+        assert_eq!(
+            MultilineStrategy::Collapse.apply(text).unwrap(),
+            "Selling diamonds 10 each msg me"
+        );
+        assert_eq!(
+            MultilineStrategy::Preserve.apply(text).unwrap(),
+            "Selling diamonds\n\n  10 each  \nmsg me"
+        );
+        assert_eq!(MultilineStrategy::Collapse.apply(" \n\n"), None);
+    }
+}
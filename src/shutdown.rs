@@ -0,0 +1,19 @@
+//! Waiting for the user or the system to ask the client to stop, so that every long-running
+//! path returns from `main` and cleans up, such as the PID file.
+
+use std::io;
+use tokio::signal;
+
+/// Completes when the user presses Ctrl-C or, on Unix, the process receives SIGTERM.
+pub async fn signal() -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            result = signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    signal::ctrl_c().await
+}
@@ -0,0 +1,49 @@
+use base64::{Engine, engine::general_purpose::STANDARD};
+use ed25519_dalek::{
+    Signature, Signer, SigningKey, Verifier, VerifyingKey,
+    pkcs8::{DecodePrivateKey, DecodePublicKey},
+};
+use minechat_protocol::protocol::MineChatError;
+use std::path::Path;
+
+/// Signs outgoing messages with an Ed25519 private key.
+pub struct MessageSigner(SigningKey);
+
+impl MessageSigner {
+    /// Loads a PKCS#8 PEM-encoded private key.
+    pub fn load(path: &Path) -> Result<Self, MineChatError> {
+        SigningKey::read_pkcs8_pem_file(path)
+            .map(Self)
+            .map_err(|e| key_error(path, e))
+    }
+
+    /// Returns the base64-encoded signature of `message`.
+    pub fn sign(&self, message: &str) -> String {
+        STANDARD.encode(self.0.sign(message.as_bytes()).to_bytes())
+    }
+}
+
+/// Verifies signatures on incoming messages against an Ed25519 public key.
+pub struct MessageVerifier(VerifyingKey);
+
+impl MessageVerifier {
+    /// Loads a PEM-encoded public key.
+    pub fn load(path: &Path) -> Result<Self, MineChatError> {
+        VerifyingKey::read_public_key_pem_file(path)
+            .map(Self)
+            .map_err(|e| key_error(path, e))
+    }
+
+    /// Checks that `signature` is a valid base64-encoded signature of `message`.
+    pub fn verify(&self, message: &str, signature: Option<&str>) -> bool {
+        let Some(bytes) = signature.and_then(|s| STANDARD.decode(s).ok()) else {
+            return false;
+        };
+        Signature::from_slice(&bytes)
+            .is_ok_and(|signature| self.0.verify(message.as_bytes(), &signature).is_ok())
+    }
+}
+
+fn key_error(path: &Path, error: impl std::fmt::Display) -> MineChatError {
+    MineChatError::ConfigError(format!("Can't read key {}: {}", path.display(), error))
+}
@@ -0,0 +1,169 @@
+//! Wrappers that dump the raw bytes sent to and received from the server, for `--sniff`.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, BufReader, ReadBuf};
+
+/// The number of bytes shown on each row of a dump.
+const BYTES_PER_ROW: usize = 16;
+
+/// A buffered reader that prints every line it yields to stderr as `< <hex>  <ascii>`.
+pub struct SniffingReader<R> {
+    inner: BufReader<R>,
+    line: Vec<u8>,
+}
+
+impl<R: AsyncRead> SniffingReader<R> {
+    pub fn with_capacity(capacity: usize, inner: R) -> Self {
+        Self {
+            inner: BufReader::with_capacity(capacity, inner),
+            line: Vec::new(),
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for SniffingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let available = ready!(self.as_mut().poll_fill_buf(cx))?;
+        let n = available.len().min(buf.remaining());
+        buf.put_slice(&available[..n]);
+        self.consume(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncBufRead for SniffingReader<R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        let available = ready!(Pin::new(&mut this.inner).poll_fill_buf(cx))?;
+        if available.is_empty() && !this.line.is_empty() {
+            // EOF in the middle of a line; show what arrived anyway.
+            eprint!("{}", hex_dump('<', &this.line));
+            this.line.clear();
+        }
+        Poll::Ready(Ok(available))
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        let this = self.get_mut();
+        log_lines('<', &mut this.line, &this.inner.buffer()[..amt]);
+        Pin::new(&mut this.inner).consume(amt);
+    }
+}
+
+/// A writer that prints every line written through it to stderr as `> <hex>  <ascii>`.
+pub struct SniffingWriter<W> {
+    inner: W,
+    line: Vec<u8>,
+}
+
+impl<W> SniffingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            line: Vec::new(),
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for SniffingWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        log_lines('>', &mut this.line, &buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Appends `bytes` to the partial `line`, printing each line as soon as it is complete.
+fn log_lines(direction: char, line: &mut Vec<u8>, bytes: &[u8]) {
+    for chunk in bytes.split_inclusive(|&b| b == b'\n') {
+        line.extend_from_slice(chunk);
+        if line.ends_with(b"\n") {
+            eprint!("{}", hex_dump(direction, line));
+            line.clear();
+        }
+    }
+}
+
+/// Formats `bytes` as rows of hex followed by their printable ASCII, with non-printable
+/// bytes shown as `.`.
+fn hex_dump(direction: char, bytes: &[u8]) -> String {
+    let mut dump = String::new();
+    for row in bytes.chunks(BYTES_PER_ROW) {
+        let hex: Vec<String> = row.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = row
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        dump.push_str(&format!(
+            "{} {:width$}  {}\n",
+            direction,
+            hex.join(" "),
+            ascii,
+            width = BYTES_PER_ROW * 3 - 1
+        ));
+    }
+    dump
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    #[test]
+    fn dumps_hex_and_ascii_in_rows() {
+        let dump = hex_dump('>', b"{\"type\":\"CHAT\"}\n");
+        assert_eq!(
+            dump,
+            "> 7b 22 74 79 70 65 22 3a 22 43 48 41 54 22 7d 0a  {\"type\":\"CHAT\"}.\n"
+        );
+
+        let dump = hex_dump('<', b"0123456789abcdefXY");
+        let rows: Vec<&str> = dump.lines().collect();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1], format!("< {:47}  XY", "58 59"));
+    }
+
+    #[tokio::test]
+    async fn passes_bytes_through_unchanged() {
+        let (client, server) = tokio::io::duplex(64);
+        let mut writer = SniffingWriter::new(client);
+        writer.write_all(b"hello\nworld\n").await.unwrap();
+        drop(writer);
+
+        let mut reader = SniffingReader::with_capacity(64, server);
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "hello\n");
+        line.clear();
+        reader.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "world\n");
+    }
+}
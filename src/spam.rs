@@ -0,0 +1,120 @@
+//! Local flood protection for outgoing chat messages.
+
+use std::{collections::VecDeque, time::Duration};
+use tokio::time::Instant;
+
+/// How many sent messages are remembered.
+const HISTORY_LIMIT: usize = 10;
+
+/// How far back repeated messages are looked for.
+const REPEAT_WINDOW: Duration = Duration::from_secs(5);
+
+/// How many times the same message may be sent within [`REPEAT_WINDOW`].
+const MAX_REPEATS: usize = 2;
+
+/// How far back sent messages are counted towards the rate limit.
+const BURST_WINDOW: Duration = Duration::from_secs(2);
+
+/// How many messages may be sent within [`BURST_WINDOW`].
+const MAX_BURST: usize = 5;
+
+/// Blocks sending for a while after the same message is repeated too often or too many
+/// messages are sent in a short time.
+pub struct SpamGuard {
+    penalty: Duration,
+    recent: VecDeque<(Instant, String)>,
+    blocked_until: Option<Instant>,
+}
+
+impl SpamGuard {
+    /// Creates a guard that blocks sending for `penalty` once it triggers. A zero penalty
+    /// turns the guard off.
+    pub fn new(penalty: Duration) -> Self {
+        Self {
+            penalty,
+            recent: VecDeque::new(),
+            blocked_until: None,
+        }
+    }
+
+    /// Records an attempt to send `message` at `now`.
+    ///
+    /// Returns how long sending remains blocked if the message must not be sent, either
+    /// because an earlier penalty hasn't run out yet or because this message triggers one.
+    pub fn check(&mut self, message: &str, now: Instant) -> Result<(), Duration> {
+        if self.penalty.is_zero() {
+            return Ok(());
+        }
+        if let Some(until) = self.blocked_until {
+            if now < until {
+                return Err(until - now);
+            }
+            self.blocked_until = None;
+        }
+
+        let message = message.trim().to_lowercase();
+        let sent_within = |window: Duration| {
+            self.recent
+                .iter()
+                .filter(move |(sent_at, _)| now.duration_since(*sent_at) < window)
+        };
+        let repeats = sent_within(REPEAT_WINDOW)
+            .filter(|(_, sent)| *sent == message)
+            .count();
+        let burst = sent_within(BURST_WINDOW).count();
+        if repeats >= MAX_REPEATS || burst >= MAX_BURST {
+            self.blocked_until = Some(now + self.penalty);
+            return Err(self.penalty);
+        }
+
+        if self.recent.len() == HISTORY_LIMIT {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((now, message));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PENALTY: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn blocks_the_third_repeat_within_the_window() {
+        let mut guard = SpamGuard::new(PENALTY);
+        let start = Instant::now();
+        assert_eq!(guard.check("hello", start), Ok(()));
+        assert_eq!(
+            guard.check(" HELLO ", start + Duration::from_secs(1)),
+            Ok(())
+        );
+        assert_eq!(
+            guard.check("Hello", start + Duration::from_secs(2)),
+            Err(PENALTY)
+        );
+        assert_eq!(
+            guard.check("something else", start + Duration::from_secs(3)),
+            Err(Duration::from_secs(9))
+        );
+        assert_eq!(
+            guard.check("hello", start + Duration::from_secs(12)),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn blocks_bursts_of_different_messages() {
+        let mut guard = SpamGuard::new(PENALTY);
+        let start = Instant::now();
+        for i in 0..MAX_BURST {
+            let at = start + Duration::from_millis(100 * i as u64);
+            assert_eq!(guard.check(&i.to_string(), at), Ok(()));
+        }
+        assert_eq!(
+            guard.check("one more", start + Duration::from_millis(600)),
+            Err(PENALTY)
+        );
+    }
+}
@@ -0,0 +1,125 @@
+use crate::{away, protocol::HeartbeatPayload};
+use std::{collections::HashMap, fmt::Write, time::Duration};
+
+/// Counters kept over a session for the `/stats` command.
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// Broadcasts displayed, not counting replays.
+    pub messages_received: u64,
+    /// Chat messages sent.
+    pub messages_sent: u64,
+    /// Words in the chat messages sent, as typed.
+    pub words_typed: u64,
+    /// Input lines discarded because they weren't valid UTF-8.
+    pub invalid_input_count: u64,
+    /// How long the latest timestamped broadcast took to arrive.
+    pub lag: Option<Duration>,
+    /// The latest statistics sent by the server.
+    pub heartbeat: Option<HeartbeatPayload>,
+    /// Broadcasts received from each sender since connecting.
+    pub senders: MessageCounter,
+}
+
+/// How many broadcasts each sender sent, for `/stats sender` and `/stats senders`.
+#[derive(Debug, Default)]
+pub struct MessageCounter {
+    counts: HashMap<String, u32>,
+}
+
+impl MessageCounter {
+    /// Counts a broadcast from `sender`.
+    pub fn record(&mut self, sender: &str) {
+        let count = self.counts.entry(sender.to_string()).or_default();
+        *count = count.saturating_add(1);
+    }
+
+    /// How many broadcasts `sender` sent.
+    pub fn count(&self, sender: &str) -> u32 {
+        self.counts.get(sender).copied().unwrap_or(0)
+    }
+
+    /// The `n` senders who sent the most broadcasts, most first, with their counts. Ties are
+    /// in alphabetical order.
+    pub fn top(&self, n: usize) -> Vec<(&str, u32)> {
+        let mut senders: Vec<(&str, u32)> = self
+            .counts
+            .iter()
+            .map(|(sender, &count)| (sender.as_str(), count))
+            .collect();
+        senders.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        senders.truncate(n);
+        senders
+    }
+
+    /// Forgets every count.
+    pub fn reset(&mut self) {
+        self.counts.clear();
+    }
+}
+
+/// Describes the session statistics for the `/stats` command, for a connection made at
+/// `connected_at`.
+pub fn report(stats: &Stats, connected_at: &str) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "Connected at:      {}", connected_at);
+    let _ = writeln!(out, "Messages received: {}", stats.messages_received);
+    let _ = writeln!(out, "Messages sent:     {}", stats.messages_sent);
+    let _ = writeln!(out, "Words typed:       {}", stats.words_typed);
+    let _ = writeln!(out, "Invalid input:     {}", stats.invalid_input_count);
+    let lag = stats.lag.map_or_else(
+        || "unknown".to_string(),
+        |lag| format!("{} ms", lag.as_millis()),
+    );
+    let _ = writeln!(out, "Lag:               {}", lag);
+    match &stats.heartbeat {
+        Some(heartbeat) => {
+            let uptime = Duration::from_secs(heartbeat.server_uptime_secs);
+            let _ = writeln!(out, "Server uptime:     {}", away::format_duration(uptime));
+            let _ = writeln!(out, "Server clients:    {}", heartbeat.connected_clients);
+            let _ = writeln!(
+                out,
+                "Server traffic:    {:.1} messages/min",
+                heartbeat.messages_per_minute
+            );
+        }
+        None => {
+            let _ = writeln!(out, "Server heartbeat:  none received");
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_the_latest_heartbeat() {
+        let mut stats = Stats::default();
+        assert!(report(&stats, "12:00").ends_with("Server heartbeat:  none received\n"));
+        stats.heartbeat = Some(HeartbeatPayload {
+            server_uptime_secs: 7500,
+            connected_clients: 12,
+            messages_per_minute: 4.25,
+        });
+        assert!(report(&stats, "12:00").ends_with(
+            "Server uptime:     2h 5m\n\
+             Server clients:    12\n\
+             Server traffic:    4.2 messages/min\n"
+        ));
+    }
+
+    #[test]
+    fn ranks_senders_by_count() {
+        let mut senders = MessageCounter::default();
+        for sender in ["bob", "alice", "carol", "bob", "alice", "bob"] {
+            senders.record(sender);
+        }
+        assert_eq!(senders.count("bob"), 3);
+        assert_eq!(senders.count("dave"), 0);
+        assert_eq!(senders.top(2), [("bob", 3), ("alice", 2)]);
+
+        senders.reset();
+        assert!(senders.top(10).is_empty());
+    }
+}
@@ -0,0 +1,77 @@
+use std::fmt::Write;
+
+/// How the cells of a column are lined up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Align {
+    Left,
+    Right,
+}
+
+/// Formats `rows` as a plain-text table under `headers`, padding each column to its widest cell.
+pub fn render(headers: &[&str], rows: &[Vec<String>]) -> String {
+    render_aligned(headers, &vec![Align::Left; headers.len()], rows)
+}
+
+/// Like [`render`], lining up the cells of each column as given in `aligns`.
+pub fn render_aligned(headers: &[&str], aligns: &[Align], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.chars().count()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut out = String::new();
+    let header: Vec<String> = headers.iter().map(|h| h.to_string()).collect();
+    for row in std::iter::once(&header).chain(rows) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .zip(aligns)
+            .map(|((cell, width), align)| match align {
+                Align::Left => format!("{:<width$}", cell, width = width),
+                Align::Right => format!("{:>width$}", cell, width = width),
+            })
+            .collect();
+        let _ = writeln!(out, "{}", line.join("  ").trim_end());
+    }
+    out
+}
+
+/// Draws a box of box-drawing characters around `lines`, padded to the widest line.
+pub fn boxed(lines: &[String]) -> String {
+    let width = lines.iter().map(|l| l.chars().count()).max().unwrap_or(0);
+    let border = "─".repeat(width + 2);
+    let mut out = format!("┌{}┐\n", border);
+    for line in lines {
+        let _ = writeln!(out, "│ {:<width$} │", line, width = width);
+    }
+    let _ = writeln!(out, "└{}┘", border);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pads_columns_to_their_alignment() {
+        let rows = vec![
+            vec!["alice".to_string(), "1200".to_string()],
+            vec!["bob".to_string(), "-5".to_string()],
+        ];
+        assert_eq!(
+            render_aligned(&["PLAYER", "SCORE"], &[Align::Left, Align::Right], &rows),
+            "PLAYER  SCORE\nalice    1200\nbob        -5\n"
+        );
+    }
+
+    #[test]
+    fn draws_boxes_around_lines() {
+        let lines = vec!["Welcome!".to_string(), "Be nice".to_string()];
+        assert_eq!(
+            boxed(&lines),
+            "┌──────────┐\n│ Welcome! │\n│ Be nice  │\n└──────────┘\n"
+        );
+    }
+}
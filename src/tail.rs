@@ -0,0 +1,167 @@
+//! Following the lines appended to a file, like `tail -f`, for the `watch` subcommand.
+
+use log::debug;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::{
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncBufReadExt, AsyncSeekExt, BufReader},
+    sync::mpsc,
+};
+
+/// A file read as lines are appended to it, woken by file system events instead of polling.
+///
+/// A file that shrinks, such as one truncated by `logrotate`'s `copytruncate`, or that is
+/// replaced by a new one, such as after rotating it by renaming, is read again from the start.
+pub struct LogTail {
+    path: PathBuf,
+    reader: BufReader<File>,
+    /// How far into the file has been read.
+    position: u64,
+    /// The identity of the file being read, to notice it being replaced.
+    id: Option<u64>,
+    /// The start of a line whose newline hasn't been written yet.
+    line: Vec<u8>,
+    changes: mpsc::UnboundedReceiver<()>,
+    // Events stop when the watcher is dropped.
+    _watcher: RecommendedWatcher,
+}
+
+impl LogTail {
+    /// Opens `path` to follow the lines written to it from now on, skipping what it already
+    /// contains.
+    pub async fn open(path: &Path) -> io::Result<Self> {
+        let mut file = File::open(path).await?;
+        let id = file_id(&file.metadata().await?);
+        let position = file.seek(SeekFrom::End(0)).await?;
+
+        let (tx, changes) = mpsc::unbounded_channel();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+                Ok(_) => {
+                    let _ = tx.send(());
+                }
+                Err(e) => debug!("File watcher error: {}", e),
+            })
+            .map_err(io::Error::other)?;
+        // The directory is watched rather than the file, to see it being replaced.
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        watcher
+            .watch(dir, RecursiveMode::NonRecursive)
+            .map_err(io::Error::other)?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            reader: BufReader::new(file),
+            position,
+            id,
+            line: Vec::new(),
+            changes,
+            _watcher: watcher,
+        })
+    }
+
+    /// Waits for the next complete line written to the file and returns it without its line
+    /// ending. Bytes that aren't valid UTF-8 are replaced.
+    pub async fn next_line(&mut self) -> io::Result<String> {
+        loop {
+            let read = self.reader.read_until(b'\n', &mut self.line).await?;
+            self.position += read as u64;
+            if self.line.ends_with(b"\n") {
+                let line = String::from_utf8_lossy(&self.line)
+                    .trim_end_matches(['\r', '\n'])
+                    .to_string();
+                self.line.clear();
+                return Ok(line);
+            }
+
+            // At the end of the file, so wait for something in its directory to change.
+            if self.changes.recv().await.is_none() {
+                return Err(io::Error::other("the file watcher stopped"));
+            }
+            while self.changes.try_recv().is_ok() {}
+            self.reopen_if_replaced().await?;
+        }
+    }
+
+    /// Starts reading from the beginning again if the file at the path was truncated or is
+    /// a different file than the one being read.
+    async fn reopen_if_replaced(&mut self) -> io::Result<()> {
+        let metadata = match fs::metadata(&self.path).await {
+            Ok(metadata) => metadata,
+            // Moved away, and the new file isn't there yet.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let id = file_id(&metadata);
+        if id == self.id && metadata.len() >= self.position {
+            return Ok(());
+        }
+        debug!(
+            "{} was truncated or replaced, reading it again",
+            self.path.display()
+        );
+        self.reader = BufReader::new(File::open(&self.path).await?);
+        self.position = 0;
+        self.id = id;
+        self.line.clear();
+        Ok(())
+    }
+}
+
+/// Identifies the file behind `metadata` by its inode, where there are inodes.
+fn file_id(metadata: &std::fs::Metadata) -> Option<u64> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Some(metadata.ino())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::{io::AsyncWriteExt, time};
+
+    async fn next(tail: &mut LogTail) -> String {
+        time::timeout(Duration::from_secs(5), tail.next_line())
+            .await
+            .expect("a line is written")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn follows_appended_lines_and_truncation() {
+        let path = std::env::temp_dir().join(format!("minechat-tail-{}.log", std::process::id()));
+        fs::write(&path, "old line\n").await.unwrap();
+        let mut tail = LogTail::open(&path).await.unwrap();
+
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .await
+            .unwrap();
+        file.write_all(b"first\nsec").await.unwrap();
+        file.flush().await.unwrap();
+        assert_eq!(next(&mut tail).await, "first");
+        file.write_all(b"ond\r\n").await.unwrap();
+        file.flush().await.unwrap();
+        assert_eq!(next(&mut tail).await, "second");
+
+        fs::write(&path, "after truncating\n").await.unwrap();
+        assert_eq!(next(&mut tail).await, "after truncating");
+        fs::remove_file(&path).await.unwrap();
+    }
+}
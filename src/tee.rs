@@ -0,0 +1,160 @@
+//! Copies of received messages served on a Unix domain socket with `--tee-socket`, so other
+//! programs can follow the chat over the connection of this client.
+
+use crate::events::EventBus;
+#[cfg(unix)]
+use crate::events::MineChatEvent;
+#[cfg(unix)]
+use log::debug;
+use log::warn;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::task::JoinHandle;
+#[cfg(unix)]
+use tokio::{
+    io::AsyncWriteExt,
+    net::{UnixListener, UnixStream},
+    sync::broadcast::{self, error::RecvError},
+};
+
+/// A listening socket that every connected process receives each message on, as one line
+/// of JSON, removed again when dropped.
+pub struct TeeSocket {
+    path: PathBuf,
+    accepting: JoinHandle<()>,
+}
+
+impl TeeSocket {
+    /// Listens on a new socket at `path` for processes to send the messages published on
+    /// `events` to.
+    #[cfg(unix)]
+    pub fn bind(path: &Path, events: Arc<dyn EventBus>) -> io::Result<Self> {
+        let listener = UnixListener::bind(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            accepting: tokio::spawn(accept(listener, events)),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn bind(_path: &Path, _events: Arc<dyn EventBus>) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Unix domain sockets are only supported on Unix",
+        ))
+    }
+}
+
+impl Drop for TeeSocket {
+    fn drop(&mut self) {
+        self.accepting.abort();
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Can't remove socket {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Accepts processes connecting to the socket, each sent messages by a task of its own so a
+/// slow one can't hold up the others.
+#[cfg(unix)]
+async fn accept(listener: UnixListener, events: Arc<dyn EventBus>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                debug!("A process connected to the tee socket");
+                tokio::spawn(forward(stream, events.subscribe()));
+            }
+            Err(e) => {
+                warn!("Can't accept a connection on the tee socket: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Writes each received message to `stream` until the process disconnects or the session
+/// ends.
+#[cfg(unix)]
+async fn forward(mut stream: UnixStream, mut events: broadcast::Receiver<MineChatEvent>) {
+    loop {
+        let msg = match events.recv().await {
+            Ok(MineChatEvent::Received(msg)) => msg,
+            Ok(_) => continue,
+            Err(RecvError::Lagged(missed)) => {
+                warn!(
+                    "A tee socket reader fell behind and missed {} events",
+                    missed
+                );
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let mut line = match serde_json::to_string(&*msg) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Can't serialize a message for the tee socket: {}", e);
+                continue;
+            }
+        };
+        line.push('\n');
+        if let Err(e) = stream.write_all(line.as_bytes()).await {
+            debug!("A process disconnected from the tee socket: {}", e);
+            return;
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::{
+        events::BroadcastBus,
+        protocol::{ChatPayload, Extension, Message},
+    };
+    use std::time::Duration;
+    use tokio::{
+        io::{AsyncBufReadExt, BufReader},
+        time,
+    };
+
+    #[tokio::test]
+    async fn sends_received_messages_to_connected_processes() {
+        let path = std::env::temp_dir().join(format!("minechat-tee-{}", std::process::id()));
+        let bus: Arc<dyn EventBus> = Arc::new(BroadcastBus::default());
+        let tee = TeeSocket::bind(&path, Arc::clone(&bus)).unwrap();
+        let mut reader = BufReader::new(UnixStream::connect(&path).await.unwrap());
+
+        // Messages published before the reader is subscribed don't reach it, so publish
+        // until one does.
+        let publishing = tokio::spawn(async move {
+            loop {
+                let msg = Message::Extension(Extension::Chat {
+                    payload: ChatPayload {
+                        message: "hi".into(),
+                        message_id: None,
+                        signature: None,
+                        reply_to: None,
+                    },
+                });
+                bus.publish(MineChatEvent::Received(Arc::new(msg)));
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+        let mut line = String::new();
+        time::timeout(Duration::from_secs(5), reader.read_line(&mut line))
+            .await
+            .expect("a message is sent")
+            .unwrap();
+        publishing.abort();
+
+        assert_eq!(
+            line,
+            "{\"type\":\"CHAT\",\"payload\":{\"message\":\"hi\"}}\n"
+        );
+        drop(tee);
+        assert!(!path.exists());
+    }
+}
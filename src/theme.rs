@@ -0,0 +1,444 @@
+//! Display colors, configured in the `theme` section of the config file.
+
+use anstyle::{Ansi256Color, AnsiColor, Effects, RgbColor, Style};
+use clap::ValueEnum;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, collections::HashMap, fmt, str::FromStr, time::Duration};
+
+/// How long to wait for the terminal to report its background color.
+const DETECT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// The color names accepted in the theme.
+const NAMES: [(&str, AnsiColor); 16] = [
+    ("black", AnsiColor::Black),
+    ("red", AnsiColor::Red),
+    ("green", AnsiColor::Green),
+    ("yellow", AnsiColor::Yellow),
+    ("blue", AnsiColor::Blue),
+    ("magenta", AnsiColor::Magenta),
+    ("cyan", AnsiColor::Cyan),
+    ("white", AnsiColor::White),
+    ("bright-black", AnsiColor::BrightBlack),
+    ("bright-red", AnsiColor::BrightRed),
+    ("bright-green", AnsiColor::BrightGreen),
+    ("bright-yellow", AnsiColor::BrightYellow),
+    ("bright-blue", AnsiColor::BrightBlue),
+    ("bright-magenta", AnsiColor::BrightMagenta),
+    ("bright-cyan", AnsiColor::BrightCyan),
+    ("bright-white", AnsiColor::BrightWhite),
+];
+
+/// The colors sender names are given with `--color-from hash` and `--color-from cycle`.
+const SENDER_PALETTE: [AnsiColor; 12] = [
+    AnsiColor::Cyan,
+    AnsiColor::Green,
+    AnsiColor::Yellow,
+    AnsiColor::Blue,
+    AnsiColor::Magenta,
+    AnsiColor::Red,
+    AnsiColor::BrightCyan,
+    AnsiColor::BrightGreen,
+    AnsiColor::BrightYellow,
+    AnsiColor::BrightBlue,
+    AnsiColor::BrightMagenta,
+    AnsiColor::BrightRed,
+];
+
+/// Whether the terminal has a dark or a light background, chosen with `--color-scheme`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ColorScheme {
+    /// Use the colors for dark backgrounds.
+    Dark,
+    /// Use the colors for light backgrounds.
+    Light,
+    /// Ask the terminal for its background color.
+    Auto,
+}
+
+impl ColorScheme {
+    /// Resolves `Auto` by asking the terminal for its background color, assuming a dark
+    /// background if it doesn't answer.
+    pub fn detect(self) -> ColorScheme {
+        if self != ColorScheme::Auto {
+            return self;
+        }
+        match termbg::theme(DETECT_TIMEOUT) {
+            Ok(termbg::Theme::Light) => ColorScheme::Light,
+            Ok(termbg::Theme::Dark) => ColorScheme::Dark,
+            Err(e) => {
+                debug!("Can't detect the terminal background: {}", e);
+                ColorScheme::Dark
+            }
+        }
+    }
+}
+
+/// A terminal color, given by name (`red`, `bright-blue`, ...), as a 256-color palette
+/// index (`208`) or as a `#rrggbb` hex code, optionally with a different color for light
+/// backgrounds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "ColorSpec", into = "ColorSpec")]
+pub struct Color {
+    /// The color on dark backgrounds, and on light ones unless `light` is set.
+    color: anstyle::Color,
+    /// The color on light backgrounds.
+    light: Option<anstyle::Color>,
+}
+
+/// How a color is written in the config file: either one color, or one for each
+/// [`ColorScheme`].
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum ColorSpec {
+    One(String),
+    Schemes { dark: String, light: String },
+}
+
+impl Color {
+    /// Returns the variant of this color to use with `scheme`. Without a color given for
+    /// light backgrounds, a darker one is used, since bright and white text is hard to read
+    /// on them.
+    pub fn for_scheme(self, scheme: ColorScheme) -> Color {
+        let color = match scheme {
+            ColorScheme::Light => self.light.unwrap_or_else(|| darker(self.color)),
+            ColorScheme::Dark | ColorScheme::Auto => self.color,
+        };
+        Color { color, light: None }
+    }
+}
+
+/// The darker counterpart of a named color, readable on a light background.
+fn darker(color: anstyle::Color) -> anstyle::Color {
+    match color {
+        anstyle::Color::Ansi(AnsiColor::White | AnsiColor::BrightWhite) => AnsiColor::Black.into(),
+        anstyle::Color::Ansi(color) => color.bright(false).into(),
+        color => color,
+    }
+}
+
+/// Parses a single color as written in the theme.
+fn parse_color(s: &str) -> Result<anstyle::Color, String> {
+    let name = s.to_ascii_lowercase();
+    if let Some((_, color)) = NAMES.iter().find(|(n, _)| *n == name) {
+        return Ok((*color).into());
+    }
+    if let Some(hex) = name.strip_prefix('#') {
+        let rgb = u32::from_str_radix(hex, 16).ok().filter(|_| hex.len() == 6);
+        return match rgb {
+            Some(rgb) => Ok(RgbColor((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8).into()),
+            None => Err(format!("Invalid hex color {}, expected #rrggbb", s)),
+        };
+    }
+    match name.parse::<u8>() {
+        Ok(index) => Ok(Ansi256Color(index).into()),
+        Err(_) => Err(format!(
+            "Unknown color {}, expected a color name, 0-255 or #rrggbb",
+            s
+        )),
+    }
+}
+
+/// Writes a single color the way [`parse_color`] reads it.
+fn color_name(color: anstyle::Color) -> String {
+    match color {
+        anstyle::Color::Ansi(color) => {
+            let (name, _) = NAMES
+                .iter()
+                .find(|(_, c)| *c == color)
+                .expect("every ANSI color is named");
+            name.to_string()
+        }
+        anstyle::Color::Ansi256(Ansi256Color(index)) => index.to_string(),
+        anstyle::Color::Rgb(RgbColor(r, g, b)) => format!("#{:02x}{:02x}{:02x}", r, g, b),
+    }
+}
+
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Color {
+            color: parse_color(s)?,
+            light: None,
+        })
+    }
+}
+
+impl TryFrom<ColorSpec> for Color {
+    type Error = String;
+
+    fn try_from(spec: ColorSpec) -> Result<Self, Self::Error> {
+        match spec {
+            ColorSpec::One(color) => color.parse(),
+            ColorSpec::Schemes { dark, light } => Ok(Color {
+                color: parse_color(&dark)?,
+                light: Some(parse_color(&light)?),
+            }),
+        }
+    }
+}
+
+impl From<Color> for ColorSpec {
+    fn from(color: Color) -> Self {
+        match color.light {
+            Some(light) => ColorSpec::Schemes {
+                dark: color_name(color.color),
+                light: color_name(light),
+            },
+            None => ColorSpec::One(color_name(color.color)),
+        }
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.light {
+            Some(light) => write!(
+                f,
+                "{} (light: {})",
+                color_name(self.color),
+                color_name(light)
+            ),
+            None => write!(f, "{}", color_name(self.color)),
+        }
+    }
+}
+
+/// The colors used for each kind of output. Unset colors use the terminal's default, so
+/// the default theme prints plain text.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Theme {
+    /// Color of sender names.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_from_color: Option<Color>,
+    /// Color of message timestamps.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp_color: Option<Color>,
+    /// Color of messages from the client or server rather than from users.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_message_color: Option<Color>,
+    /// Color of private messages.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub private_message_color: Option<Color>,
+    /// Color of warnings that need the user's attention.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub highlight_color: Option<Color>,
+    /// Color of errors.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_color: Option<Color>,
+    /// Color of commands that succeeded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub success_color: Option<Color>,
+    /// Color of `inline code` in messages, and of the label on code blocks.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code_color: Option<Color>,
+    /// Background color of code blocks in messages.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code_background_color: Option<Color>,
+}
+
+impl Theme {
+    /// Whether every color is unset, so there is nothing worth saving.
+    pub fn is_default(&self) -> bool {
+        *self == Theme::default()
+    }
+
+    /// Returns the theme with each color's variant for `scheme`.
+    pub fn for_scheme(self, scheme: ColorScheme) -> Theme {
+        let pick = |color: Option<Color>| color.map(|color| color.for_scheme(scheme));
+        Theme {
+            message_from_color: pick(self.message_from_color),
+            timestamp_color: pick(self.timestamp_color),
+            system_message_color: pick(self.system_message_color),
+            private_message_color: pick(self.private_message_color),
+            highlight_color: pick(self.highlight_color),
+            error_color: pick(self.error_color),
+            success_color: pick(self.success_color),
+            code_color: pick(self.code_color),
+            code_background_color: pick(self.code_background_color),
+        }
+    }
+}
+
+/// How sender names are colored, chosen with `--color-from`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ColorFrom {
+    /// Pick a color for each name by hashing it, so a user has the same color every session.
+    Hash,
+    /// Give each sender the next color, in the order they first send a message.
+    Cycle,
+    /// Use the color given for each user in the `colors` section of the config.
+    Fixed,
+}
+
+/// Assigns the colors of sender names for `--color-from`. Names without one are given
+/// `message_from_color`.
+pub struct SenderColors {
+    from: ColorFrom,
+    scheme: ColorScheme,
+    /// The colors of the `colors` section of the config, for [`ColorFrom::Fixed`].
+    fixed: HashMap<String, Color>,
+}
+
+impl SenderColors {
+    pub fn new(from: ColorFrom, scheme: ColorScheme, fixed: HashMap<String, Color>) -> Self {
+        Self {
+            from,
+            scheme,
+            fixed,
+        }
+    }
+
+    /// Whether the colors depend on the order senders are seen in.
+    pub fn is_cycle(&self) -> bool {
+        self.from == ColorFrom::Cycle
+    }
+
+    /// Returns the color of `sender`, who was the `order`th sender seen if known.
+    pub fn color(&self, sender: &str, order: Option<usize>) -> Option<Color> {
+        let index = match self.from {
+            ColorFrom::Hash => fnv1a(sender) as usize,
+            ColorFrom::Cycle => order?,
+            ColorFrom::Fixed => return self.fixed.get(sender).map(|c| c.for_scheme(self.scheme)),
+        };
+        let color = SENDER_PALETTE[index % SENDER_PALETTE.len()];
+        Some(Color::from(color).for_scheme(self.scheme))
+    }
+}
+
+impl From<AnsiColor> for Color {
+    fn from(color: AnsiColor) -> Self {
+        Color {
+            color: color.into(),
+            light: None,
+        }
+    }
+}
+
+/// The 32-bit FNV-1a hash of `s`, which unlike the standard library's hasher is the same in
+/// every build.
+fn fnv1a(s: &str) -> u32 {
+    s.bytes().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Wraps `text` in the escape sequences for `color`, or returns it unchanged if no color
+/// is set.
+pub fn paint(color: Option<Color>, text: &str) -> Cow<'_, str> {
+    match color {
+        Some(Color { color, .. }) => {
+            let style = Style::new().fg_color(Some(color));
+            Cow::Owned(format!(
+                "{}{}{}",
+                style.render(),
+                text,
+                style.render_reset()
+            ))
+        }
+        None => Cow::Borrowed(text),
+    }
+}
+
+/// Wraps `text` in the escape sequences for the background `color`, or returns it unchanged
+/// if no color is set.
+pub fn paint_background(color: Option<Color>, text: &str) -> Cow<'_, str> {
+    match color {
+        Some(Color { color, .. }) => {
+            let style = Style::new().bg_color(Some(color));
+            Cow::Owned(format!(
+                "{}{}{}",
+                style.render(),
+                text,
+                style.render_reset()
+            ))
+        }
+        None => Cow::Borrowed(text),
+    }
+}
+
+/// Wraps `text` in the escape sequences for dim text.
+pub fn dim(text: &str) -> String {
+    let style = Style::new().effects(Effects::DIMMED);
+    format!("{}{}{}", style.render(), text, style.render_reset())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_names_indexes_and_hex_codes() {
+        for spec in ["red", "bright-cyan", "208", "#ff8800"] {
+            let color: Color = spec.parse().unwrap();
+            assert_eq!(color.to_string(), spec);
+        }
+        assert_eq!("Red".parse::<Color>().unwrap().to_string(), "red");
+        assert!("#ff88".parse::<Color>().is_err());
+        assert!("mauve".parse::<Color>().is_err());
+    }
+
+    #[test]
+    fn picks_colors_for_the_background() {
+        let theme: Theme = serde_json::from_str(
+            r##"{
+                "message_from_color": {"dark": "bright-cyan", "light": "#005f87"},
+                "error_color": "bright-red",
+                "highlight_color": "white",
+                "timestamp_color": "244"
+            }"##,
+        )
+        .unwrap();
+        let light = theme.clone().for_scheme(ColorScheme::Light);
+        let names = |theme: &Theme| {
+            [
+                theme.message_from_color,
+                theme.error_color,
+                theme.highlight_color,
+                theme.timestamp_color,
+            ]
+            .map(|color| color.unwrap().to_string())
+        };
+        assert_eq!(names(&light), ["#005f87", "red", "black", "244"]);
+        let dark = theme.clone().for_scheme(ColorScheme::Dark);
+        assert_eq!(names(&dark), ["bright-cyan", "bright-red", "white", "244"]);
+
+        let saved = serde_json::to_value(&theme).unwrap();
+        assert_eq!(
+            saved["message_from_color"],
+            serde_json::json!({"dark": "bright-cyan", "light": "#005f87"})
+        );
+        assert_eq!(saved["error_color"], "bright-red");
+    }
+
+    #[test]
+    fn assigns_sender_colors() {
+        let hash = SenderColors::new(ColorFrom::Hash, ColorScheme::Dark, HashMap::new());
+        assert_eq!(hash.color("alice", None), hash.color("alice", Some(3)));
+        assert!(hash.color("alice", None).is_some());
+
+        let cycle = SenderColors::new(ColorFrom::Cycle, ColorScheme::Dark, HashMap::new());
+        assert_eq!(cycle.color("bob", Some(0)), Some(AnsiColor::Cyan.into()));
+        assert_eq!(
+            cycle.color("alice", Some(13)),
+            Some(AnsiColor::Green.into())
+        );
+        assert_eq!(cycle.color("bob", None), None);
+
+        let colors = HashMap::from([("alice".to_string(), "#ff8800".parse().unwrap())]);
+        let fixed = SenderColors::new(ColorFrom::Fixed, ColorScheme::Dark, colors);
+        assert_eq!(fixed.color("alice", Some(0)), "#ff8800".parse().ok());
+        assert_eq!(fixed.color("bob", Some(0)), None);
+    }
+
+    #[test]
+    fn paints_only_when_a_color_is_set() {
+        assert_eq!(paint(None, "hi"), "hi");
+        assert_eq!(
+            paint(Some("red".parse().unwrap()), "hi"),
+            "\x1b[31mhi\x1b[0m"
+        );
+    }
+}
@@ -0,0 +1,116 @@
+//! Formatting of the timestamps sent by the server, in the time zone given with
+//! `--timezone` or saved for the server.
+
+use chrono::DateTime;
+use chrono_tz::{TZ_VARIANTS, Tz};
+use std::fmt::Write;
+
+/// The format message times are shown in by default.
+pub const DEFAULT_TIME_FORMAT: &str = "%H:%M:%S";
+
+/// Parses an IANA time zone name such as `America/New_York`.
+///
+/// The error lists the zones in the same region, or every zone if the region is unknown.
+pub fn parse_timezone(name: &str) -> Result<Tz, String> {
+    if let Ok(tz) = name.parse() {
+        return Ok(tz);
+    }
+    let region = name.split('/').next().unwrap_or_default();
+    let mut zones: Vec<&str> = TZ_VARIANTS
+        .iter()
+        .map(|tz| tz.name())
+        .filter(|zone| {
+            zone.split_once('/')
+                .is_some_and(|(r, _)| r.eq_ignore_ascii_case(region))
+        })
+        .collect();
+    if zones.is_empty() {
+        zones = TZ_VARIANTS.iter().map(|tz| tz.name()).collect();
+    }
+    Err(format!(
+        "Unknown time zone {}, expected one of: {}",
+        name,
+        zones.join(", ")
+    ))
+}
+
+/// Parses a `--time-format` value: `short`, `long`, `iso` or a strftime format string.
+///
+/// The format is checked by formatting a known time with it, so mistakes are reported at
+/// startup instead of when the first message arrives.
+pub fn parse_time_format(format: &str) -> Result<String, String> {
+    let format = match format {
+        "short" => "%H:%M",
+        "long" => "%Y-%m-%d %H:%M:%S",
+        "iso" => "%Y-%m-%dT%H:%M:%S%:z",
+        format => format,
+    };
+    let sample = DateTime::UNIX_EPOCH.with_timezone(&Tz::UTC);
+    let mut out = String::new();
+    write!(out, "{}", sample.format(format))
+        .map_err(|_| format!("Invalid time format {}", format))?;
+    Ok(format.to_string())
+}
+
+/// Formats `millis` since the Unix epoch as the date and time in `tz`, with the zone's
+/// abbreviation.
+pub fn date_time(millis: u64, tz: Tz) -> String {
+    time_with_format(millis, tz, "%Y-%m-%d %H:%M:%S %Z")
+}
+
+/// Formats `millis` since the Unix epoch as the time in `tz`, following the strftime
+/// `format`.
+pub fn time_with_format(millis: u64, tz: Tz, format: &str) -> String {
+    i64::try_from(millis)
+        .ok()
+        .and_then(DateTime::from_timestamp_millis)
+        .map_or_else(
+            || "invalid time".to_string(),
+            |time| time.with_timezone(&tz).format(format).to_string(),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_in_the_given_zone() {
+        // 2025-01-15 12:30:45 UTC
+        let millis = 1_736_944_245_000;
+        assert_eq!(
+            time_with_format(millis, Tz::UTC, DEFAULT_TIME_FORMAT),
+            "12:30:45"
+        );
+        let new_york = parse_timezone("America/New_York").unwrap();
+        assert_eq!(date_time(millis, new_york), "2025-01-15 07:30:45 EST");
+    }
+
+    #[test]
+    fn expands_time_format_shortcuts_and_rejects_bad_formats() {
+        assert_eq!(parse_time_format("short").unwrap(), "%H:%M");
+        assert_eq!(parse_time_format("iso").unwrap(), "%Y-%m-%dT%H:%M:%S%:z");
+        assert_eq!(parse_time_format("%d/%m %H:%M").unwrap(), "%d/%m %H:%M");
+        assert!(parse_time_format("%Q").is_err());
+
+        let millis = 1_736_944_245_000;
+        let new_york = parse_timezone("America/New_York").unwrap();
+        let iso = parse_time_format("iso").unwrap();
+        assert_eq!(
+            time_with_format(millis, new_york, &iso),
+            "2025-01-15T07:30:45-05:00"
+        );
+    }
+
+    #[test]
+    fn lists_zones_in_the_region_of_an_unknown_zone() {
+        let error = parse_timezone("Europe/Atlantis").unwrap_err();
+        assert!(error.contains("Europe/Rome"));
+        assert!(!error.contains("America/New_York"));
+        assert!(
+            parse_timezone("Nowhere")
+                .unwrap_err()
+                .contains("America/New_York")
+        );
+    }
+}
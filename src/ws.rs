@@ -0,0 +1,169 @@
+//! WebSocket transport, for servers behind a proxy that only accepts WebSocket connections.
+//!
+//! Each line of the protocol travels as one text frame, without its terminating newline.
+
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tokio_tungstenite::{
+    MaybeTlsStream, WebSocketStream, client_async_tls,
+    tungstenite::{Error as WsError, Message as Frame},
+};
+
+/// Performs the WebSocket handshake with the server at `address` over `stream`, using TLS
+/// (`wss://`) if `tls` is set.
+pub async fn handshake(
+    stream: TcpStream,
+    address: &str,
+    tls: bool,
+) -> io::Result<LineFrames<WebSocketStream<MaybeTlsStream<TcpStream>>>> {
+    let url = format!("{}://{}/", if tls { "wss" } else { "ws" }, address);
+    let (ws, _response) = client_async_tls(url.as_str(), stream)
+        .await
+        .map_err(io::Error::other)?;
+    Ok(LineFrames::new(ws))
+}
+
+/// Adapts a stream of WebSocket frames to the newline-delimited byte stream that the rest of
+/// the client reads and writes.
+pub struct LineFrames<S> {
+    ws: S,
+    /// The rest of the last received frame, with its newline.
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    /// Bytes written since the last complete line was sent.
+    write_buf: Vec<u8>,
+}
+
+impl<S> LineFrames<S> {
+    pub fn new(ws: S) -> Self {
+        Self {
+            ws,
+            read_buf: Vec::new(),
+            read_pos: 0,
+            write_buf: Vec::new(),
+        }
+    }
+}
+
+impl<S> LineFrames<S>
+where
+    S: Sink<Frame, Error = WsError> + Unpin,
+{
+    /// Sends every complete line written so far as a text frame.
+    fn poll_send_lines(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while let Some(end) = self.write_buf.iter().position(|&b| b == b'\n') {
+            ready!(self.ws.poll_ready_unpin(cx)).map_err(io::Error::other)?;
+            let line: Vec<u8> = self.write_buf.drain(..=end).take(end).collect();
+            let text = String::from_utf8(line)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            self.ws
+                .start_send_unpin(Frame::text(text))
+                .map_err(io::Error::other)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncRead for LineFrames<S>
+where
+    S: Stream<Item = Result<Frame, WsError>> + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.read_pos == this.read_buf.len() {
+            match ready!(this.ws.poll_next_unpin(cx)) {
+                Some(Ok(Frame::Text(text))) => {
+                    this.read_buf.clear();
+                    this.read_buf.extend_from_slice(text.as_bytes());
+                    this.read_buf.push(b'\n');
+                    this.read_pos = 0;
+                }
+                // Pings are answered by tungstenite itself; other frames carry no messages.
+                Some(Ok(Frame::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Poll::Ready(Err(io::Error::other(e))),
+            }
+        }
+        let n = (this.read_buf.len() - this.read_pos).min(buf.remaining());
+        buf.put_slice(&this.read_buf[this.read_pos..this.read_pos + n]);
+        this.read_pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for LineFrames<S>
+where
+    S: Sink<Frame, Error = WsError> + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // Only take more bytes once the lines already written are on their way.
+        ready!(this.poll_send_lines(cx))?;
+        this.write_buf.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send_lines(cx))?;
+        this.ws.poll_flush_unpin(cx).map_err(io::Error::other)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send_lines(cx))?;
+        this.ws.poll_close_unpin(cx).map_err(io::Error::other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, duplex};
+    use tokio_tungstenite::tungstenite::protocol::Role;
+
+    #[tokio::test]
+    async fn sends_and_receives_lines_as_text_frames() {
+        let (client, server) = duplex(4096);
+        let client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+        let mut server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+        let (reader, mut writer) = tokio::io::split(LineFrames::new(client));
+
+        writer
+            .write_all(b"{\"type\":\"CHAT\"}\n{\"ty")
+            .await
+            .unwrap();
+        writer.write_all(b"pe\":\"DISCONNECT\"}\n").await.unwrap();
+        writer.flush().await.unwrap();
+        assert_eq!(
+            server.next().await.unwrap().unwrap(),
+            Frame::text("{\"type\":\"CHAT\"}")
+        );
+        assert_eq!(
+            server.next().await.unwrap().unwrap(),
+            Frame::text("{\"type\":\"DISCONNECT\"}")
+        );
+
+        server.send(Frame::text("hello")).await.unwrap();
+        server.close(None).await.unwrap();
+        let mut lines = BufReader::new(reader).lines();
+        assert_eq!(lines.next_line().await.unwrap().as_deref(), Some("hello"));
+        assert_eq!(lines.next_line().await.unwrap(), None);
+    }
+}